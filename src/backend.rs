@@ -0,0 +1,133 @@
+use neutralts::Template;
+use serde_json::json;
+
+use crate::error::IpcError;
+use crate::{ParseTemplateResult, CONTENT_MSGPACK, CONTENT_PATH, CTRL_STATUS_OK};
+
+// ============================================
+// Render backends
+// ============================================
+//
+// The transport and protocol layers only see RenderBackend, the backend is
+// selected by name with the `render_backend` config key.
+
+/// A render request, contents already read and validated.
+pub struct RenderRequest<'a> {
+    pub schema: &'a [u8],
+    pub schema_format: u8,
+    pub template: &'a str,
+    pub template_format: u8,
+}
+
+pub trait RenderBackend: Send + Sync {
+    fn render(&self, request: &RenderRequest) -> Result<ParseTemplateResult, IpcError>;
+}
+
+/// The neutralts template engine.
+pub struct NeutralTs;
+
+/// Renders nothing: the output is the template source (or path) as
+/// received. For testing clients and the transport without templates.
+pub struct Mock;
+
+/// Version of the neutralts dependency, keep in sync with Cargo.toml.
+pub const NEUTRALTS_VERSION: &str = "1.4.3";
+
+static NEUTRALTS: NeutralTs = NeutralTs;
+static MOCK: Mock = Mock;
+
+/// Backend by config name.
+pub fn get(name: &str) -> Option<&'static dyn RenderBackend> {
+    match name {
+        "neutralts" => Some(&NEUTRALTS),
+        "mock" => Some(&MOCK),
+        _ => None,
+    }
+}
+
+impl RenderBackend for NeutralTs {
+    fn render(&self, request: &RenderRequest) -> Result<ParseTemplateResult, IpcError> {
+        let mut template = Template::new().map_err(|e| IpcError::Render(e.to_string()))?;
+
+        if request.schema_format == CONTENT_MSGPACK {
+            template
+                .merge_schema_msgpack(request.schema)
+                .map_err(|e| IpcError::Schema(e.to_string()))?;
+        } else {
+            let schema_str = String::from_utf8(request.schema.to_vec())
+                .map_err(|source| IpcError::InvalidUtf8 { block: 1, source })?;
+            template
+                .merge_schema_str(&schema_str)
+                .map_err(|e| IpcError::Schema(e.to_string()))?;
+        }
+
+        if request.template_format == CONTENT_PATH {
+            template
+                .set_src_path(request.template)
+                .map_err(|e| IpcError::Render(e.to_string()))?;
+        } else {
+            template.set_src_str(request.template);
+        }
+
+        let contents = template.render();
+        let result = json!({
+            "has_error": template.has_error(),
+            "status_code": template.get_status_code(),
+            "status_text": template.get_status_text(),
+            "status_param": template.get_status_param()
+        });
+
+        Ok(ParseTemplateResult {
+            json: result.to_string(),
+            text: contents,
+            status: CTRL_STATUS_OK,
+            binary: None,
+        })
+    }
+}
+
+impl RenderBackend for Mock {
+    fn render(&self, request: &RenderRequest) -> Result<ParseTemplateResult, IpcError> {
+        let result = json!({
+            "has_error": false,
+            "status_code": "200",
+            "status_text": "OK",
+            "status_param": ""
+        });
+
+        Ok(ParseTemplateResult {
+            json: result.to_string(),
+            text: request.template.to_string(),
+            status: CTRL_STATUS_OK,
+            binary: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CONTENT_JSON, CONTENT_TEXT};
+
+    #[test]
+    fn test_get_backend() {
+        assert!(get("neutralts").is_some());
+        assert!(get("mock").is_some());
+        assert!(get("other").is_none());
+    }
+
+    #[test]
+    fn test_mock_render() {
+        let request = RenderRequest {
+            schema: b"{}",
+            schema_format: CONTENT_JSON,
+            template: "Hello {:;name:}",
+            template_format: CONTENT_TEXT,
+        };
+        let result = get("mock").unwrap().render(&request).unwrap();
+
+        assert_eq!(result.text, "Hello {:;name:}");
+        assert_eq!(result.status, CTRL_STATUS_OK);
+        assert!(result.json.contains("\"has_error\":false"));
+    }
+}
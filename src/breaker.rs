@@ -0,0 +1,181 @@
+use std::fs;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error::IpcError;
+use crate::logger;
+use crate::Config;
+
+// ============================================
+// Filesystem circuit breaker
+// ============================================
+//
+// Before rendering a template by path the file is stat'ed with a timeout.
+// After `fs_breaker_threshold` consecutive failures (I/O errors other than
+// not found/permission denied, or stats slower than `fs_breaker_timeout_ms`)
+// the breaker opens and path requests fail fast with 503 for
+// `fs_breaker_cooldown_secs`. Then one request probes the filesystem, its
+// result closes or reopens the breaker.
+
+static BREAKER: CircuitBreaker = CircuitBreaker::new();
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A probe request is in flight.
+    HalfOpen,
+}
+
+pub struct CircuitBreaker {
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub const fn new() -> Self {
+        CircuitBreaker {
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    /// Whether a request may access the filesystem now.
+    fn allow(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until } if now >= until => {
+                *state = State::HalfOpen;
+                true
+            }
+            State::Open { .. } | State::HalfOpen => false,
+        }
+    }
+
+    /// Record the result of an allowed access, returns true if it opened the breaker.
+    fn record(&self, ok: bool, threshold: u32, cooldown: Duration, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if ok {
+            *state = State::Closed { failures: 0 };
+            return false;
+        }
+
+        let failures = match *state {
+            State::Closed { failures } => failures + 1,
+            State::Open { .. } | State::HalfOpen => threshold,
+        };
+
+        if failures >= threshold {
+            *state = State::Open {
+                until: now + cooldown,
+            };
+            true
+        } else {
+            *state = State::Closed { failures };
+            false
+        }
+    }
+}
+
+/// Check the filesystem holding `path` through the breaker.
+pub async fn check(path: &str, config: &Config) -> Result<(), IpcError> {
+    if config.fs_breaker_threshold == 0 {
+        return Ok(());
+    }
+
+    if !BREAKER.allow(Instant::now()) {
+        return Err(IpcError::Unavailable("circuit breaker open".to_string()));
+    }
+
+    let timeout = Duration::from_millis(config.fs_breaker_timeout_ms);
+    let owned = path.to_string();
+    let stat = tokio::task::spawn_blocking(move || fs::metadata(owned));
+
+    let failure = match tokio::time::timeout(timeout, stat).await {
+        Ok(Ok(Ok(_))) => None,
+        // The filesystem answered, the client asked for a bad path.
+        Ok(Ok(Err(e)))
+            if matches!(
+                e.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied
+            ) =>
+        {
+            None
+        }
+        Ok(Ok(Err(e))) => Some(e.to_string()),
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(_) => Some(format!("stat took longer than {:?}", timeout)),
+    };
+
+    let cooldown = Duration::from_secs(config.fs_breaker_cooldown_secs);
+    if BREAKER.record(
+        failure.is_none(),
+        config.fs_breaker_threshold,
+        cooldown,
+        Instant::now(),
+    ) {
+        logger::error(
+            &format!("Filesystem circuit breaker open for {:?}", cooldown),
+            &[("path", path)],
+        );
+    }
+
+    match failure {
+        Some(reason) => Err(IpcError::Unavailable(reason)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_breaker_opens_and_recovers() {
+        let breaker = CircuitBreaker::new();
+        let cooldown = Duration::from_secs(10);
+        let now = Instant::now();
+
+        assert!(breaker.allow(now));
+        assert!(!breaker.record(false, 2, cooldown, now));
+        assert!(breaker.allow(now));
+        assert!(breaker.record(false, 2, cooldown, now));
+        assert!(!breaker.allow(now + Duration::from_secs(5)));
+
+        // One probe after the cooldown, the rest fail fast until it's done.
+        let later = now + cooldown;
+        assert!(breaker.allow(later));
+        assert!(!breaker.allow(later));
+        assert!(!breaker.record(true, 2, cooldown, later));
+        assert!(breaker.allow(later));
+    }
+
+    #[test]
+    fn test_breaker_failed_probe_reopens() {
+        let breaker = CircuitBreaker::new();
+        let cooldown = Duration::from_secs(10);
+        let now = Instant::now();
+
+        breaker.record(false, 1, cooldown, now);
+        let later = now + cooldown;
+        assert!(breaker.allow(later));
+        assert!(breaker.record(false, 1, cooldown, later));
+        assert!(!breaker.allow(later + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_breaker_success_resets_failures() {
+        let breaker = CircuitBreaker::new();
+        let cooldown = Duration::from_secs(10);
+        let now = Instant::now();
+
+        breaker.record(false, 2, cooldown, now);
+        breaker.record(true, 2, cooldown, now);
+        assert!(!breaker.record(false, 2, cooldown, now));
+        assert!(breaker.allow(now));
+    }
+}
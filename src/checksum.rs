@@ -0,0 +1,115 @@
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::error::IpcError;
+use crate::Header;
+
+// ============================================
+// Block checksums
+// ============================================
+//
+// A record with the CHECKSUMS bit set in the version byte ends with the
+// CRC32 (IEEE) of each content block, after the signature trailer if any:
+//
+// \x00\x00\x00\x00  # CRC32 of content 1 (big endian)
+// \x00\x00\x00\x00  # CRC32 of content 2 (big endian)
+//
+// The CRCs are of the blocks as sent, compressed or not. The server verifies
+// them before handling the request and answers with the bit set and the
+// CRCs of its response blocks, the CRC of a chunked output (content 2) after
+// the end chunk. Records that go through several proxies get corruption
+// reported as such, not as a parse error of the contents.
+
+pub const CHECKSUMS: u8 = 0x40;
+pub const SIZE: usize = 8;
+
+pub fn is_checksummed(version: u8) -> bool {
+    version & CHECKSUMS != 0
+}
+
+/// The CRCs a record ends with.
+pub fn trailer(content_1: &[u8], content_2: &[u8]) -> [u8; SIZE] {
+    let mut trailer = [0; SIZE];
+    trailer[..4].copy_from_slice(&crc32fast::hash(content_1).to_be_bytes());
+    trailer[4..].copy_from_slice(&crc32fast::hash(content_2).to_be_bytes());
+    trailer
+}
+
+/// Read the contents and the CRCs of a request and verify them. Returns the
+/// contents, content 1 followed by content 2.
+pub async fn read<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    header: &Header,
+) -> Result<Vec<u8>, IpcError> {
+    let mut contents = crate::read_content(reader, header.content_length_1 as usize).await?;
+    contents.extend(crate::read_content(reader, header.content_length_2 as usize).await?);
+    verify(reader, &contents, header).await?;
+
+    Ok(contents)
+}
+
+/// Read the CRCs and verify the contents already read.
+pub async fn verify<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    contents: &[u8],
+    header: &Header,
+) -> Result<(), IpcError> {
+    let received = [reader.read_u32().await?, reader.read_u32().await?];
+    let (content_1, content_2) = contents.split_at(header.content_length_1 as usize);
+    let computed = [crc32fast::hash(content_1), crc32fast::hash(content_2)];
+
+    for (block, (expected, computed)) in (1..).zip(received.into_iter().zip(computed)) {
+        if expected != computed {
+            return Err(IpcError::Checksum {
+                block,
+                expected,
+                computed,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CONTENT_JSON, CONTENT_TEXT, CTRL_PARSE_TEMPLATE};
+
+    fn header() -> Header {
+        Header {
+            version: 0,
+            control: CTRL_PARSE_TEMPLATE,
+            content_format_1: CONTENT_JSON,
+            content_length_1: 2,
+            content_format_2: CONTENT_TEXT,
+            content_length_2: 5,
+        }
+    }
+
+    #[test]
+    fn test_trailer() {
+        // CRC32 check value of "123456789".
+        assert_eq!(trailer(b"123456789", b"")[..4], 0xcbf43926u32.to_be_bytes());
+        assert_eq!(trailer(b"", b"")[4..], [0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_read_verified() {
+        let mut record = b"{}hello".to_vec();
+        record.extend(trailer(b"{}", b"hello"));
+
+        let contents = read(&mut &record[..], &header()).await.unwrap();
+        assert_eq!(contents, b"{}hello");
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_block() {
+        let mut record = b"{}jello".to_vec();
+        record.extend(trailer(b"{}", b"hello"));
+
+        assert!(matches!(
+            read(&mut &record[..], &header()).await,
+            Err(IpcError::Checksum { block: 2, .. })
+        ));
+    }
+}
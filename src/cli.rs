@@ -0,0 +1,321 @@
+use std::fs;
+use std::io::{self, Write};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+use crate::backend::RenderRequest;
+use crate::error::IpcError;
+use crate::{
+    Config, Header, ParseTemplateResult, CONTENT_JSON, CONTENT_MSGPACK, CONTENT_PATH,
+    CTRL_PARSE_TEMPLATE, CTRL_STATUS_OK, HEADER_SIZE,
+};
+
+// ============================================
+// render and dev commands
+// ============================================
+//
+// `neutral-ipc render --tpl PATH [--schema PATH] [--out PATH] [--server ADDR]`
+// renders a template file. Without --server the render runs in this
+// process with the configured render backend, no server needed. Schemas
+// ending in .msgpack are sent as MessagePack, others as JSON.
+//
+// `neutral-ipc dev --tpl PATH [--schema PATH] [--port PORT]` renders again
+// each time one of the files changes and prints the result metadata. With
+// --port the latest output is served on http://127.0.0.1:PORT/, the page
+// reloads itself after each render.
+
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Default, PartialEq)]
+struct RenderArgs {
+    tpl: String,
+    schema: Option<String>,
+    out: Option<String>,
+    server: Option<String>,
+    port: Option<u16>,
+}
+
+/// Parse the options, only those in `allowed` are accepted.
+fn parse_args(args: &[String], allowed: &[&str]) -> Result<RenderArgs, IpcError> {
+    let mut parsed = RenderArgs::default();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        if !allowed.contains(&arg.as_str()) {
+            return Err(IpcError::Config(format!("unknown option '{}'", arg)));
+        }
+        let value = args
+            .next()
+            .cloned()
+            .ok_or_else(|| IpcError::Config(format!("missing value for '{}'", arg)))?;
+        match arg.as_str() {
+            "--tpl" => parsed.tpl = value,
+            "--schema" => parsed.schema = Some(value),
+            "--out" => parsed.out = Some(value),
+            "--server" => parsed.server = Some(value),
+            "--port" => {
+                parsed.port = Some(
+                    value
+                        .parse()
+                        .map_err(|_| IpcError::Config(format!("invalid port '{}'", value)))?,
+                )
+            }
+            _ => unreachable!("allowed option without a value"),
+        }
+    }
+
+    if parsed.tpl.is_empty() {
+        return Err(IpcError::Config("--tpl is required".to_string()));
+    }
+
+    Ok(parsed)
+}
+
+/// Entry point for `neutral-ipc render`.
+pub async fn render(args: &[String], config: &Config) -> Result<(), IpcError> {
+    let args = parse_args(args, &["--tpl", "--schema", "--out", "--server"])?;
+    let (schema, schema_format) = read_schema(&args.schema)?;
+
+    let result = match &args.server {
+        Some(address) => render_remote(address, &schema, schema_format, &args.tpl).await?,
+        None => render_local(config, &schema, schema_format, &args.tpl)?,
+    };
+
+    match &args.out {
+        Some(path) => fs::write(path, &result.text)?,
+        None => io::stdout().write_all(result.text.as_bytes())?,
+    }
+
+    if has_error(&result) {
+        return Err(IpcError::Render(result.json));
+    }
+
+    Ok(())
+}
+
+/// Entry point for `neutral-ipc dev`.
+pub async fn dev(args: &[String], config: &Config) -> Result<(), IpcError> {
+    let args = parse_args(args, &["--tpl", "--schema", "--port"])?;
+    let (output, _) = watch::channel((0u64, String::new()));
+
+    if let Some(port) = args.port {
+        let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+        eprintln!("Serving the output on http://127.0.0.1:{}/", port);
+        tokio::spawn(serve_output(listener, output.subscribe()));
+    }
+
+    let mut last_change = None;
+    loop {
+        let change = (modified(&args.tpl), args.schema.as_deref().map(modified));
+        if last_change.as_ref() != Some(&change) {
+            last_change = Some(change);
+
+            let version = output.borrow().0 + 1;
+            let rendered = read_schema(&args.schema).and_then(|(schema, schema_format)| {
+                render_local(config, &schema, schema_format, &args.tpl)
+            });
+            match rendered {
+                Ok(result) => {
+                    let status = if has_error(&result) { "ERROR" } else { "OK" };
+                    eprintln!(
+                        "[{}] {} {} bytes {}",
+                        version,
+                        status,
+                        result.text.len(),
+                        result.json
+                    );
+                    output.send_replace((version, result.text));
+                }
+                Err(e) => {
+                    eprintln!("[{}] ERROR {}", version, e);
+                    output.send_replace((version, format!("<pre>{}</pre>", e)));
+                }
+            }
+        }
+
+        tokio::time::sleep(WATCH_INTERVAL).await;
+    }
+}
+
+fn read_schema(path: &Option<String>) -> Result<(Vec<u8>, u8), IpcError> {
+    match path {
+        Some(path) => {
+            let format = if path.ends_with(".msgpack") {
+                CONTENT_MSGPACK
+            } else {
+                CONTENT_JSON
+            };
+            Ok((fs::read(path)?, format))
+        }
+        None => Ok((b"{}".to_vec(), CONTENT_JSON)),
+    }
+}
+
+fn render_local(
+    config: &Config,
+    schema: &[u8],
+    schema_format: u8,
+    tpl: &str,
+) -> Result<ParseTemplateResult, IpcError> {
+    crate::render_backend(config)?.render(&RenderRequest {
+        schema,
+        schema_format,
+        template: tpl,
+        template_format: CONTENT_PATH,
+    })
+}
+
+fn has_error(result: &ParseTemplateResult) -> bool {
+    result.status != CTRL_STATUS_OK
+        || serde_json::from_str::<serde_json::Value>(&result.json)
+            .is_ok_and(|metadata| metadata["has_error"] == true)
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Minimal HTTP server for the dev output: `/__version` returns the render
+/// number, polled by the page to reload itself, any other path the output.
+async fn serve_output(listener: TcpListener, output: watch::Receiver<(u64, String)>) {
+    loop {
+        let Ok((mut stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let output = output.clone();
+
+        tokio::spawn(async move {
+            let mut request = [0; 1024];
+            let length = stream.read(&mut request).await.unwrap_or(0);
+            let (version, html) = output.borrow().clone();
+
+            let (content_type, body) = if request[..length].starts_with(b"GET /__version ") {
+                ("text/plain", version.to_string())
+            } else {
+                ("text/html; charset=utf-8", with_reload(&html, version))
+            };
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+                content_type,
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+    }
+}
+
+/// Add the auto reload script, before </body> if there is one.
+fn with_reload(html: &str, version: u64) -> String {
+    let script = format!(
+        "<script>setInterval(function(){{fetch('/__version').then(function(r){{return r.text()}}).then(function(v){{if(v!=='{}')location.reload()}}).catch(function(){{}})}},1000)</script>",
+        version
+    );
+
+    match html.rfind("</body>") {
+        Some(index) => format!("{}{}{}", &html[..index], script, &html[index..]),
+        None => format!("{}{}", html, script),
+    }
+}
+
+/// Render on a running server, `tpl` is a path on the server.
+async fn render_remote(
+    address: &str,
+    schema: &[u8],
+    schema_format: u8,
+    tpl: &str,
+) -> Result<ParseTemplateResult, IpcError> {
+    let request = Header {
+        version: 0,
+        control: CTRL_PARSE_TEMPLATE,
+        content_format_1: schema_format,
+        content_length_1: schema.len() as u64,
+        content_format_2: CONTENT_PATH,
+        content_length_2: tpl.len() as u64,
+    };
+
+    let mut stream = TcpStream::connect(address).await?;
+    stream.write_all(&request.to_bytes()).await?;
+    stream.write_all(&request.extended_lengths()).await?;
+    stream.write_all(schema).await?;
+    stream.write_all(tpl.as_bytes()).await?;
+
+    let mut header_bytes = [0; HEADER_SIZE];
+    stream.read_exact(&mut header_bytes).await?;
+    let mut header = Header::from_bytes(&header_bytes).ok_or(IpcError::InvalidHeader)?;
+    header.read_extended_lengths(&mut stream).await?;
+    let json = crate::read_content(&mut stream, header.content_length_1 as usize).await?;
+    let text = crate::read_content(&mut stream, header.content_length_2 as usize).await?;
+
+    Ok(ParseTemplateResult {
+        json: String::from_utf8_lossy(&json).into_owned(),
+        text: String::from_utf8_lossy(&text).into_owned(),
+        status: header.control,
+        binary: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let allowed = ["--tpl", "--schema", "--out", "--server"];
+        let parsed = parse_args(
+            &args(&["--tpl", "page.ntpl", "--out", "out.html"]),
+            &allowed,
+        )
+        .unwrap();
+        assert_eq!(parsed.tpl, "page.ntpl");
+        assert_eq!(parsed.out.as_deref(), Some("out.html"));
+        assert_eq!(parsed.server, None);
+
+        assert!(parse_args(&args(&["--schema", "s.json"]), &allowed).is_err());
+        assert!(parse_args(&args(&["--tpl"]), &allowed).is_err());
+        assert!(parse_args(&args(&["--tpl", "a", "--bogus"]), &allowed).is_err());
+        assert!(parse_args(&args(&["--tpl", "a", "--port", "8080"]), &allowed).is_err());
+
+        let parsed = parse_args(
+            &args(&["--tpl", "a", "--port", "8080"]),
+            &["--tpl", "--port"],
+        )
+        .unwrap();
+        assert_eq!(parsed.port, Some(8080));
+    }
+
+    #[test]
+    fn test_with_reload() {
+        let html = with_reload("<html><body>Hi</body></html>", 3);
+        assert!(html.starts_with("<html><body>Hi<script>"));
+        assert!(html.ends_with("</script></body></html>"));
+        assert!(html.contains("v!=='3'"));
+
+        assert!(with_reload("plain", 1).starts_with("plain<script>"));
+    }
+
+    #[tokio::test]
+    async fn test_render_offline() {
+        let out =
+            std::env::temp_dir().join(format!("neutral-ipc-render-{}.html", std::process::id()));
+        let mut config = Config::default();
+        config.render_backend = "mock".to_string();
+
+        let out_arg = out.to_string_lossy().into_owned();
+        render(&args(&["--tpl", "page.ntpl", "--out", &out_arg]), &config)
+            .await
+            .unwrap();
+
+        // The mock backend outputs the template path.
+        assert_eq!(fs::read_to_string(&out).unwrap(), "page.ntpl");
+        fs::remove_file(&out).unwrap();
+    }
+}
@@ -0,0 +1,189 @@
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::{Read, Write};
+use tokio::io::AsyncRead;
+
+use crate::error::IpcError;
+use crate::{Config, Header};
+
+// ============================================
+// Compressed contents
+// ============================================
+//
+// The units digit of a content format is the compression of the block:
+// 11 is gzip JSON, 32 zstd plaintext and so on, 0 (the formats so far) is
+// uncompressed. Requests are decompressed before they are handled, at most
+// `decompress_max_bytes` per block. A client that compresses its request
+// gets the response blocks of at least `compress_min_bytes` compressed the
+// same way.
+
+pub const NONE: u8 = 0;
+pub const GZIP: u8 = 1;
+pub const ZSTD: u8 = 2;
+
+/// Format and compression of a content format code.
+pub fn split(format: u8) -> (u8, u8) {
+    (format - format % 10, format % 10)
+}
+
+/// Compression used by a request, the one of its first compressed block.
+pub fn of(header: &Header) -> u8 {
+    let (_, codec_1) = split(header.content_format_1);
+    let (_, codec_2) = split(header.content_format_2);
+
+    if codec_1 != NONE {
+        codec_1
+    } else {
+        codec_2
+    }
+}
+
+/// The request header without compression in the formats.
+pub fn plain(header: &Header) -> Header {
+    Header {
+        version: header.version,
+        control: header.control,
+        content_format_1: split(header.content_format_1).0,
+        content_length_1: header.content_length_1,
+        content_format_2: split(header.content_format_2).0,
+        content_length_2: header.content_length_2,
+    }
+}
+
+/// Reject compressions the server doesn't support, before the contents are read.
+pub fn check(header: &Header) -> Result<(), IpcError> {
+    for (block, format) in [(1, header.content_format_1), (2, header.content_format_2)] {
+        match split(format).1 {
+            NONE | GZIP => {}
+            #[cfg(feature = "zstd")]
+            ZSTD => {}
+            codec => return Err(IpcError::UnsupportedCompression { block, codec }),
+        }
+    }
+
+    Ok(())
+}
+
+/// Decompress a content block, at most `max` bytes.
+pub fn decompress(block: u8, codec: u8, data: &[u8], max: u64) -> Result<Vec<u8>, IpcError> {
+    let failed = move |e: std::io::Error| IpcError::Decompress {
+        block,
+        message: e.to_string(),
+    };
+    let decoder: Box<dyn Read + '_> = match codec {
+        NONE => return Ok(data.to_vec()),
+        GZIP => Box::new(GzDecoder::new(data)),
+        #[cfg(feature = "zstd")]
+        ZSTD => Box::new(zstd::stream::read::Decoder::new(data).map_err(failed)?),
+        codec => return Err(IpcError::UnsupportedCompression { block, codec }),
+    };
+
+    // One byte over the limit tells a block that is too large.
+    let mut buffer = Vec::new();
+    decoder
+        .take(max + 1)
+        .read_to_end(&mut buffer)
+        .map_err(failed)?;
+
+    if buffer.len() as u64 > max {
+        return Err(IpcError::ContentTooLarge {
+            block,
+            length: buffer.len() as u64,
+            max,
+        });
+    }
+
+    Ok(buffer)
+}
+
+/// Read and decompress both blocks of a compressed request. Returns the
+/// contents and the plain header with their decompressed lengths.
+pub async fn read<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    header: &Header,
+    config: &Config,
+) -> Result<(Vec<u8>, Header), IpcError> {
+    let content_1 = crate::read_content(stream, header.content_length_1 as usize).await?;
+    let content_2 = crate::read_content(stream, header.content_length_2 as usize).await?;
+
+    let max = config.decompress_max_bytes;
+    let mut contents = decompress(1, split(header.content_format_1).1, &content_1, max)?;
+    let content_2 = decompress(2, split(header.content_format_2).1, &content_2, max)?;
+
+    let mut plain = plain(header);
+    plain.content_length_1 = contents.len() as u64;
+    plain.content_length_2 = content_2.len() as u64;
+    contents.extend(content_2);
+
+    Ok((contents, plain))
+}
+
+/// Compress a response block, `None` if the codec is not available.
+pub fn compress(codec: u8, data: &[u8]) -> Option<Vec<u8>> {
+    match codec {
+        GZIP => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data).ok()?;
+            encoder.finish().ok()
+        }
+        #[cfg(feature = "zstd")]
+        ZSTD => zstd::stream::encode_all(data, 0).ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CONTENT_JSON, CONTENT_TEXT, CTRL_PARSE_TEMPLATE};
+
+    #[test]
+    fn test_split_and_plain() {
+        assert_eq!(split(CONTENT_JSON), (CONTENT_JSON, NONE));
+        assert_eq!(split(CONTENT_TEXT + ZSTD), (CONTENT_TEXT, ZSTD));
+
+        let header = Header {
+            version: 0,
+            control: CTRL_PARSE_TEMPLATE,
+            content_format_1: CONTENT_JSON + GZIP,
+            content_length_1: 5,
+            content_format_2: CONTENT_TEXT,
+            content_length_2: 3,
+        };
+        assert_eq!(of(&header), GZIP);
+        assert_eq!(plain(&header).content_format_1, CONTENT_JSON);
+        assert_eq!(plain(&header).content_length_1, 5);
+    }
+
+    #[test]
+    fn test_gzip_roundtrip() {
+        let data = b"{\"data\":{\"text\":\"hello\"}}".repeat(100);
+        let compressed = compress(GZIP, &data).unwrap();
+        assert!(compressed.len() < data.len());
+
+        assert_eq!(decompress(1, GZIP, &compressed, 1 << 20).unwrap(), data);
+    }
+
+    #[test]
+    fn test_decompress_limit() {
+        let compressed = compress(GZIP, &[b'x'; 4096]).unwrap();
+
+        assert!(matches!(
+            decompress(1, GZIP, &compressed, 1024),
+            Err(IpcError::ContentTooLarge {
+                block: 1,
+                max: 1024,
+                ..
+            })
+        ));
+        assert!(matches!(
+            decompress(2, GZIP, b"not gzip", 1024),
+            Err(IpcError::Decompress { block: 2, .. })
+        ));
+        assert!(matches!(
+            decompress(1, 7, b"", 1024),
+            Err(IpcError::UnsupportedCompression { block: 1, codec: 7 })
+        ));
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::error::IpcError;
+use crate::Config;
+
+// ============================================
+// Per template concurrency
+// ============================================
+//
+// With `template_max_concurrent` (0 unlimited) at most that many renders of
+// the same template path run at once, so one heavy template being hammered
+// can't take every render worker. Over the cap requests wait for a turn, or
+// with `"template_limit_mode": "busy"` fail at once with 429. Inline
+// templates are not limited.
+
+static SLOTS: OnceLock<Mutex<HashMap<String, Arc<Semaphore>>>> = OnceLock::new();
+
+fn slots() -> &'static Mutex<HashMap<String, Arc<Semaphore>>> {
+    SLOTS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// A render slot of a template, released on drop.
+pub struct Permit {
+    path: String,
+    semaphore: Arc<Semaphore>,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut slots = slots().lock().unwrap_or_else(|e| e.into_inner());
+        self.permit.take();
+
+        // Only the map and this permit are left: nobody renders or waits for
+        // the template, forget it.
+        if Arc::strong_count(&self.semaphore) == 2 {
+            slots.remove(&self.path);
+        }
+    }
+}
+
+/// Take a render slot of the template, `None` when there is no limit.
+pub async fn acquire(path: &str, config: &Config) -> Result<Option<Permit>, IpcError> {
+    if config.template_max_concurrent == 0 {
+        return Ok(None);
+    }
+
+    let semaphore = {
+        let mut slots = slots().lock().unwrap_or_else(|e| e.into_inner());
+        let semaphore = slots
+            .entry(path.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(config.template_max_concurrent)));
+        Arc::clone(semaphore)
+    };
+
+    let permit = if config.template_limit_mode == "busy" {
+        Arc::clone(&semaphore)
+            .try_acquire_owned()
+            .map_err(|_| IpcError::Busy(path.to_string()))?
+    } else {
+        Arc::clone(&semaphore)
+            .acquire_owned()
+            .await
+            .map_err(|e| IpcError::Render(e.to_string()))?
+    };
+
+    Ok(Some(Permit {
+        path: path.to_string(),
+        semaphore,
+        permit: Some(permit),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracked(path: &str) -> bool {
+        slots().lock().unwrap().contains_key(path)
+    }
+
+    #[tokio::test]
+    async fn test_busy_mode() {
+        let mut config = Config::default();
+        config.template_max_concurrent = 1;
+        config.template_limit_mode = "busy".to_string();
+
+        let first = acquire("/busy/a.ntpl", &config).await.unwrap();
+        assert!(first.is_some());
+        assert!(matches!(
+            acquire("/busy/a.ntpl", &config).await,
+            Err(IpcError::Busy(_))
+        ));
+        assert!(acquire("/busy/b.ntpl", &config).await.unwrap().is_some());
+
+        drop(first);
+        assert!(!tracked("/busy/a.ntpl"));
+        assert!(!tracked("/busy/b.ntpl"));
+    }
+
+    #[tokio::test]
+    async fn test_wait_mode() {
+        let mut config = Config::default();
+        config.template_max_concurrent = 1;
+
+        let first = acquire("/wait/a.ntpl", &config).await.unwrap();
+        let waiting = tokio::time::timeout(
+            std::time::Duration::from_millis(50),
+            acquire("/wait/a.ntpl", &config),
+        )
+        .await;
+        assert!(waiting.is_err());
+
+        drop(first);
+        assert!(acquire("/wait/a.ntpl", &config).await.unwrap().is_some());
+        assert!(acquire("/wait/a.ntpl", &Config::default())
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,398 @@
+use serde_json::{Map, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::error::IpcError;
+use crate::logger;
+use crate::sampler::Sampler;
+use crate::{
+    Config, Header, CONTENT_JSON, CONTENT_PATH, CTRL_PARSE_TEMPLATE, CTRL_STATUS_OK, HEADER_SIZE,
+};
+
+// ============================================
+// Request corpus and bench command
+// ============================================
+//
+// With `corpus_dir` a fraction (`corpus_sample_rate`) of the render requests
+// is saved there as replayable records, one per file (`<unix ms>-<n>.nipc`),
+// at most `corpus_max_files` per run, counting the files written. With `corpus_redact` (default true)
+// the strings of JSON schemas, object keys included, are replaced by as many
+// `x` (more if two keys of an object would collide): the numbers and shape
+// of the data are kept, the text is not. Requests with other schema formats,
+// or an inline template instead of a path, are not saved then.
+//
+// `neutral-ipc bench --corpus DIR [--server ADDR] [--iterations N]` replays
+// the records against a running server (the configured host and port by
+// default), one connection per record, and prints the latency percentiles.
+// Running it on the same corpus against two releases shows performance
+// regressions with the shapes of real traffic.
+
+const RECORD_EXTENSION: &str = "nipc";
+
+static SAMPLER: Sampler = Sampler::new();
+/// Records saved, or being saved, in this run.
+static SAVED: AtomicU64 = AtomicU64::new(0);
+
+/// Save the render request if capture is enabled and the request is sampled.
+pub fn capture(config: &Config, header: &Header, content_1: &[u8], content_2: &[u8]) {
+    let Some((path, record)) = select(&SAMPLER, &SAVED, config, header, content_1, content_2)
+    else {
+        return;
+    };
+
+    tokio::spawn(async move {
+        if let Err(e) = tokio::fs::write(&path, record).await {
+            // Not written, its place under the cap goes to another one.
+            SAVED.fetch_sub(1, Ordering::Relaxed);
+            logger::debug(
+                &format!("Failed to save corpus record: {}", e),
+                &[("path", &path.display().to_string())],
+            );
+        }
+    });
+}
+
+/// The file and record of a sampled request, counted in `saved` if it is
+/// under `corpus_max_files`. Requests redaction skips are not counted.
+fn select(
+    sampler: &Sampler,
+    saved: &AtomicU64,
+    config: &Config,
+    header: &Header,
+    content_1: &[u8],
+    content_2: &[u8],
+) -> Option<(PathBuf, Vec<u8>)> {
+    let dir = config.corpus_dir.as_ref()?;
+    sampler.sample(config.corpus_sample_rate)?;
+    if saved.load(Ordering::Relaxed) >= config.corpus_max_files {
+        return None;
+    }
+
+    let schema = if config.corpus_redact {
+        redact(header, content_1)?
+    } else {
+        content_1.to_vec()
+    };
+
+    let n = saved
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |saved| {
+            (saved < config.corpus_max_files).then_some(saved + 1)
+        })
+        .ok()?
+        + 1;
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let path = Path::new(dir).join(format!("{}-{}.{}", millis, n, RECORD_EXTENSION));
+
+    Some((path, record(header, &schema, content_2)))
+}
+
+/// A version 0 parse template record with the contents.
+pub fn record(header: &Header, content_1: &[u8], content_2: &[u8]) -> Vec<u8> {
+    let header = Header {
+        version: 0,
+        control: CTRL_PARSE_TEMPLATE,
+        content_format_1: header.content_format_1,
+        content_length_1: content_1.len() as u64,
+        content_format_2: header.content_format_2,
+        content_length_2: content_2.len() as u64,
+    };
+
+    let mut record = header.to_bytes().to_vec();
+    record.extend(header.extended_lengths());
+    record.extend_from_slice(content_1);
+    record.extend_from_slice(content_2);
+    record
+}
+
+/// The JSON schema with its strings masked, `None` for other formats or an
+/// inline template.
+fn redact(header: &Header, schema: &[u8]) -> Option<Vec<u8>> {
+    if header.content_format_1 != CONTENT_JSON || header.content_format_2 != CONTENT_PATH {
+        return None;
+    }
+
+    let mut value: Value = serde_json::from_slice(schema).ok()?;
+    mask(&mut value);
+
+    Some(value.to_string().into_bytes())
+}
+
+fn mask(value: &mut Value) {
+    match value {
+        Value::String(text) => *text = "x".repeat(text.chars().count()),
+        Value::Object(map) => {
+            let mut masked = Map::new();
+            for (key, mut value) in std::mem::take(map) {
+                let mut key = "x".repeat(key.chars().count());
+                while masked.contains_key(&key) {
+                    key.push('x');
+                }
+                mask(&mut value);
+                masked.insert(key, value);
+            }
+            *map = masked;
+        }
+        Value::Array(items) => items.iter_mut().for_each(mask),
+        _ => {}
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct BenchArgs {
+    corpus: String,
+    server: Option<String>,
+    iterations: u64,
+}
+
+fn parse_args(args: &[String]) -> Result<BenchArgs, IpcError> {
+    let mut parsed = BenchArgs {
+        corpus: String::new(),
+        server: None,
+        iterations: 1,
+    };
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .cloned()
+            .ok_or_else(|| IpcError::Config(format!("missing value for '{}'", arg)))?;
+        match arg.as_str() {
+            "--corpus" => parsed.corpus = value,
+            "--server" => parsed.server = Some(value),
+            "--iterations" => {
+                parsed.iterations = value
+                    .parse()
+                    .map_err(|_| IpcError::Config(format!("invalid iterations '{}'", value)))?
+            }
+            _ => return Err(IpcError::Config(format!("unknown option '{}'", arg))),
+        }
+    }
+
+    if parsed.corpus.is_empty() {
+        return Err(IpcError::Config("--corpus is required".to_string()));
+    }
+    if parsed.iterations == 0 {
+        return Err(IpcError::Config(
+            "--iterations must be at least 1".to_string(),
+        ));
+    }
+
+    Ok(parsed)
+}
+
+/// Entry point for `neutral-ipc bench`.
+pub async fn bench(args: &[String], config: &Config) -> Result<(), IpcError> {
+    let args = parse_args(args)?;
+    let server = args
+        .server
+        .unwrap_or_else(|| format!("{}:{}", config.host, config.port));
+    let records = load(Path::new(&args.corpus))?;
+    if records.is_empty() {
+        return Err(IpcError::Config(format!(
+            "no .{} records in '{}'",
+            RECORD_EXTENSION, args.corpus
+        )));
+    }
+
+    let mut latencies = Vec::new();
+    let mut errors = 0;
+    let started = Instant::now();
+    for _ in 0..args.iterations {
+        for record in &records {
+            let sent = Instant::now();
+            if replay(&server, record).await? != CTRL_STATUS_OK {
+                errors += 1;
+            }
+            latencies.push(sent.elapsed());
+        }
+    }
+    let elapsed = started.elapsed();
+    latencies.sort();
+
+    println!(
+        "{} requests ({} records), {} errors in {:.2} s, {:.1} requests/s",
+        latencies.len(),
+        records.len(),
+        errors,
+        elapsed.as_secs_f64(),
+        latencies.len() as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "latency p50 {:.3} ms, p90 {:.3} ms, p99 {:.3} ms, max {:.3} ms",
+        millis(percentile(&latencies, 0.5)),
+        millis(percentile(&latencies, 0.9)),
+        millis(percentile(&latencies, 0.99)),
+        millis(percentile(&latencies, 1.0))
+    );
+
+    Ok(())
+}
+
+/// The records of a corpus in file name order, oldest first.
+fn load(dir: &Path) -> Result<Vec<Vec<u8>>, IpcError> {
+    let mut paths: Vec<_> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == RECORD_EXTENSION))
+        .collect();
+    paths.sort();
+
+    Ok(paths.iter().map(fs::read).collect::<Result<_, _>>()?)
+}
+
+/// Send a record on a new connection, returns the response status.
+async fn replay(address: &str, record: &[u8]) -> Result<u8, IpcError> {
+    let mut stream = TcpStream::connect(address).await?;
+    stream.write_all(crate::MAGIC).await?;
+    stream.write_all(record).await?;
+
+    let mut header_bytes = [0; HEADER_SIZE];
+    stream.read_exact(&mut header_bytes).await?;
+    let mut header = Header::from_bytes(&header_bytes).ok_or(IpcError::InvalidHeader)?;
+    header.read_extended_lengths(&mut stream).await?;
+    crate::discard_content(
+        &mut stream,
+        header.content_length_1 + header.content_length_2,
+    )
+    .await?;
+
+    Ok(header.control)
+}
+
+/// The `p` percentile (0.0 to 1.0) of sorted latencies.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    sorted[((sorted.len() - 1) as f64 * p).round() as usize]
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+    use crate::{CONTENT_MSGPACK, CONTENT_TEXT};
+
+    fn header(content_format_1: u8, content_format_2: u8) -> Header {
+        Header {
+            version: 0,
+            control: CTRL_PARSE_TEMPLATE,
+            content_format_1,
+            content_length_1: 0,
+            content_format_2,
+            content_length_2: 0,
+        }
+    }
+
+    #[test]
+    fn test_redact() {
+        let json_path = header(CONTENT_JSON, CONTENT_PATH);
+        let redacted = redact(
+            &json_path,
+            r#"{"data": {"name": "Ana", "age": 42, "tags": ["ñu"]}}"#.as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(
+            redacted,
+            br#"{"xxxx":{"xxxx":"xxx","xxx":42,"xxxxx":["xx"]}}"#
+        );
+        assert!(redact(&header(CONTENT_MSGPACK, CONTENT_PATH), &[0x80]).is_none());
+        assert!(redact(&json_path, b"not json").is_none());
+        assert!(redact(&header(CONTENT_JSON, CONTENT_TEXT), b"{}").is_none());
+    }
+
+    #[test]
+    fn test_max_files() {
+        let mut config = Config::default();
+        config.corpus_dir = Some("corpus".to_string());
+        config.corpus_sample_rate = 0.5;
+        config.corpus_max_files = 3;
+        let sampler = Sampler::new();
+        let saved = AtomicU64::new(0);
+
+        // Sampled inline templates are skipped by redaction, not counted.
+        let inline = header(CONTENT_JSON, CONTENT_TEXT);
+        for _ in 0..10 {
+            assert!(select(&sampler, &saved, &config, &inline, b"{}", b"x").is_none());
+        }
+        assert_eq!(saved.load(Ordering::Relaxed), 0);
+
+        let path = header(CONTENT_JSON, CONTENT_PATH);
+        let selected = (0..20)
+            .filter_map(|_| select(&sampler, &saved, &config, &path, b"{}", b"t.ntpl"))
+            .count();
+        assert_eq!(selected, 3);
+        assert_eq!(saved.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn test_record() {
+        let header = Header {
+            version: 1,
+            control: CTRL_PARSE_TEMPLATE,
+            content_format_1: CONTENT_JSON,
+            content_length_1: 100,
+            content_format_2: CONTENT_PATH,
+            content_length_2: 100,
+        };
+        let record = record(&header, b"{}", b"index.ntpl");
+
+        let parsed = Header::from_bytes(&record).unwrap();
+        assert_eq!(parsed.version, 0);
+        assert_eq!(parsed.content_length_1, 2);
+        assert_eq!(parsed.content_length_2, 10);
+        assert_eq!(&record[HEADER_SIZE..], b"{}index.ntpl");
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = temp_dir("corpus");
+        fs::write(dir.join("2-1.nipc"), b"second").unwrap();
+        fs::write(dir.join("1-1.nipc"), b"first").unwrap();
+        fs::write(dir.join("notes.txt"), b"skipped").unwrap();
+
+        let records = load(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(records, vec![b"first".to_vec(), b"second".to_vec()]);
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let args: Vec<String> = ["--corpus", "corpus", "--iterations", "3"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        assert_eq!(
+            parse_args(&args).unwrap(),
+            BenchArgs {
+                corpus: "corpus".to_string(),
+                server: None,
+                iterations: 3,
+            }
+        );
+
+        assert!(parse_args(&[]).is_err());
+        let zero: Vec<String> = ["--corpus", "corpus", "--iterations", "0"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        assert!(parse_args(&zero).is_err());
+        assert!(parse_args(&["--corpus".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_percentile() {
+        let latencies: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&latencies, 0.5), Duration::from_millis(51));
+        assert_eq!(percentile(&latencies, 1.0), Duration::from_millis(100));
+        assert_eq!(percentile(&latencies[..1], 0.99), Duration::from_millis(1));
+    }
+}
@@ -0,0 +1,269 @@
+use serde_json::json;
+use std::io;
+use std::string::FromUtf8Error;
+use thiserror::Error;
+
+use crate::CTRL_STATUS_KO;
+
+/// Error class, used to map errors to response status and as a log field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// Reading or writing the stream failed, no response can be sent.
+    Connection,
+    /// The record does not follow the protocol.
+    Protocol,
+    /// The contents of the record could not be decoded.
+    Content,
+    /// The template engine failed.
+    Render,
+    /// The server configuration is not valid.
+    Config,
+    /// A dependency of the server (the template filesystem) is failing.
+    Unavailable,
+    /// The request is valid but not allowed on this listener.
+    Forbidden,
+    /// The request signature is missing or not valid.
+    Unauthorized,
+    /// Too many requests for the same resource right now, retry later.
+    Busy,
+    /// The client cancelled the request.
+    Cancelled,
+}
+
+impl ErrorClass {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorClass::Connection => "connection",
+            ErrorClass::Protocol => "protocol",
+            ErrorClass::Content => "content",
+            ErrorClass::Render => "render",
+            ErrorClass::Config => "config",
+            ErrorClass::Unavailable => "unavailable",
+            ErrorClass::Forbidden => "forbidden",
+            ErrorClass::Unauthorized => "unauthorized",
+            ErrorClass::Busy => "busy",
+            ErrorClass::Cancelled => "cancelled",
+        }
+    }
+
+    /// HTTP-like status code and text reported in the response metadata.
+    pub fn status(&self) -> (&'static str, &'static str) {
+        match self {
+            ErrorClass::Protocol | ErrorClass::Content => ("400", "Bad Request"),
+            ErrorClass::Connection | ErrorClass::Render | ErrorClass::Config => {
+                ("500", "Internal Server Error")
+            }
+            ErrorClass::Unavailable => ("503", "Service Unavailable"),
+            ErrorClass::Forbidden => ("403", "Forbidden"),
+            ErrorClass::Unauthorized => ("401", "Unauthorized"),
+            ErrorClass::Busy => ("429", "Too Many Requests"),
+            ErrorClass::Cancelled => ("499", "Client Closed Request"),
+        }
+    }
+}
+
+/// Crate-level error.
+#[derive(Debug, Error)]
+pub enum IpcError {
+    #[error("connection error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("invalid header format")]
+    InvalidHeader,
+
+    #[error("strict header: {0}")]
+    StrictHeader(String),
+
+    #[error("connection does not start with the Neutral IPC magic")]
+    MissingMagic,
+
+    #[error("unsupported protocol version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("unsupported control code {0}")]
+    UnsupportedControl(u8),
+
+    #[error("invalid content_format_{block} {format}, expected {expected}")]
+    InvalidFormat {
+        block: u8,
+        format: u8,
+        expected: &'static str,
+    },
+
+    #[error("content-{block} is {length} bytes, at most {max} allowed")]
+    ContentTooLarge { block: u8, length: u64, max: u64 },
+
+    #[error("content_format_{block} compression {codec} is not supported")]
+    UnsupportedCompression { block: u8, codec: u8 },
+
+    #[error("content-{block} could not be decompressed: {message}")]
+    Decompress { block: u8, message: String },
+
+    #[error("extension: {0}")]
+    Extension(String),
+
+    #[error("multipart: {0}")]
+    Multipart(String),
+
+    #[error("content-{block} checksum mismatch, CRC32 {computed:08x} instead of {expected:08x}")]
+    Checksum { block: u8, expected: u32, computed: u32 },
+
+    #[error("content_format_{block} {format} is not allowed on this listener")]
+    FormatNotAllowed { block: u8, format: u8 },
+
+    #[error("content-{block} is not valid UTF-8")]
+    InvalidUtf8 {
+        block: u8,
+        #[source]
+        source: FromUtf8Error,
+    },
+
+    #[error("unknown template alias '{0}'")]
+    UnknownAlias(String),
+
+    #[error("template source: {0}")]
+    TemplateSource(String),
+
+    #[error("invalid schema: {0}")]
+    Schema(String),
+
+    #[error("template engine error: {0}")]
+    Render(String),
+
+    #[error("configuration error: {0}")]
+    Config(String),
+
+    #[error("template filesystem unavailable: {0}")]
+    Unavailable(String),
+
+    #[error("too many concurrent renders of '{0}'")]
+    Busy(String),
+
+    #[error("request cancelled by the client")]
+    Cancelled,
+
+    #[error("unauthorized: {0}")]
+    Unauthorized(String),
+
+    #[error("transaction failed, {} of {total} templates did not render", .failures.len())]
+    Transaction {
+        total: usize,
+        failures: Vec<serde_json::Value>,
+    },
+}
+
+impl IpcError {
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            IpcError::Io(_) => ErrorClass::Connection,
+            IpcError::InvalidHeader
+            | IpcError::StrictHeader(_)
+            | IpcError::MissingMagic
+            | IpcError::UnsupportedVersion(_)
+            | IpcError::UnsupportedControl(_)
+            | IpcError::InvalidFormat { .. }
+            | IpcError::ContentTooLarge { .. }
+            | IpcError::UnsupportedCompression { .. }
+            | IpcError::Extension(_)
+            | IpcError::Multipart(_)
+            | IpcError::Checksum { .. } => ErrorClass::Protocol,
+            IpcError::InvalidUtf8 { .. }
+            | IpcError::Decompress { .. }
+            | IpcError::UnknownAlias(_)
+            | IpcError::TemplateSource(_)
+            | IpcError::Schema(_) => ErrorClass::Content,
+            IpcError::Render(_) | IpcError::Transaction { .. } => ErrorClass::Render,
+            IpcError::Config(_) => ErrorClass::Config,
+            IpcError::Unavailable(_) => ErrorClass::Unavailable,
+            IpcError::FormatNotAllowed { .. } => ErrorClass::Forbidden,
+            IpcError::Busy(_) => ErrorClass::Busy,
+            IpcError::Cancelled => ErrorClass::Cancelled,
+            IpcError::Unauthorized(_) => ErrorClass::Unauthorized,
+        }
+    }
+
+    /// Response control (status) for this error.
+    pub fn control(&self) -> u8 {
+        CTRL_STATUS_KO
+    }
+
+    /// Metadata sent as content-1 of the error response, same keys as a render result.
+    pub fn to_json(&self) -> String {
+        let (status_code, status_text) = self.class().status();
+        let mut metadata = json!({
+            "has_error": true,
+            "status_code": status_code,
+            "status_text": status_text,
+            "status_param": self.to_string()
+        });
+        match self {
+            IpcError::UnsupportedVersion(_) => {
+                metadata["supported_versions"] = json!(crate::PROTOCOL_VERSIONS);
+            }
+            IpcError::Transaction { failures, .. } => metadata["failures"] = json!(failures),
+            _ => {}
+        }
+        metadata.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_class() {
+        let io_error = IpcError::from(io::Error::new(io::ErrorKind::UnexpectedEof, "eof"));
+        assert_eq!(io_error.class(), ErrorClass::Connection);
+        assert_eq!(
+            IpcError::UnsupportedControl(99).class(),
+            ErrorClass::Protocol
+        );
+        assert_eq!(
+            IpcError::Schema("x".to_string()).class(),
+            ErrorClass::Content
+        );
+        assert_eq!(
+            IpcError::Render("x".to_string()).class(),
+            ErrorClass::Render
+        );
+        assert_eq!(
+            IpcError::Unavailable("x".to_string()).class().status(),
+            ("503", "Service Unavailable")
+        );
+    }
+
+    #[test]
+    fn test_error_to_json() {
+        let error = IpcError::InvalidFormat {
+            block: 2,
+            format: 99,
+            expected: "TEXT or PATH",
+        };
+        let value: serde_json::Value = serde_json::from_str(&error.to_json()).unwrap();
+
+        assert_eq!(value["has_error"], true);
+        assert_eq!(value["status_code"], "400");
+        assert_eq!(
+            value["status_param"],
+            "invalid content_format_2 99, expected TEXT or PATH"
+        );
+        assert_eq!(error.control(), CTRL_STATUS_KO);
+    }
+
+    #[test]
+    fn test_transaction_to_json() {
+        let error = IpcError::Transaction {
+            total: 3,
+            failures: vec![json!({ "template": "b.ntpl", "status_code": "404" })],
+        };
+        let value: serde_json::Value = serde_json::from_str(&error.to_json()).unwrap();
+
+        assert_eq!(value["status_code"], "500");
+        assert_eq!(
+            value["status_param"],
+            "transaction failed, 1 of 3 templates did not render"
+        );
+        assert_eq!(value["failures"][0]["template"], "b.ntpl");
+    }
+}
@@ -0,0 +1,54 @@
+use std::io::{self, Cursor};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+// ============================================
+// In memory exchange
+// ============================================
+//
+// For transports that receive whole records (WebSocket messages, pipelined
+// records) and hand them to the stream based client handler.
+
+/// A request record in memory, read as a stream, and the response written to it.
+pub struct Exchange {
+    request: Cursor<Vec<u8>>,
+    pub response: Vec<u8>,
+}
+
+impl Exchange {
+    pub fn new(request: Vec<u8>) -> Self {
+        Exchange {
+            request: Cursor::new(request),
+            response: Vec::new(),
+        }
+    }
+}
+
+impl AsyncRead for Exchange {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().request).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Exchange {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().response).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().response).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().response).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,181 @@
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::error::IpcError;
+
+// ============================================
+// Header extensions
+// ============================================
+//
+// A record with the EXTENDED bit set in the version byte has an extension
+// area before content 1 (after the request ID on version 1):
+//
+// \x00\x00          # length of the entries (big endian)
+// \x00              # entry type
+// \x00\x00          # entry length (big endian)
+// ...               # entry value
+// ...               # more entries
+//
+// It leaves room for optional request data (auth tokens, trace IDs,
+// deadlines...) without changing the fixed header again. Types below
+// CRITICAL are skipped by a server that doesn't know them, a request with an
+// unknown type from CRITICAL up is rejected. Signed requests sign the area
+// too. Defined types:
+//
+// TIMESTAMP (0x81)  # Unix time in seconds (8 bytes big endian) a signed
+//                   # request was signed at, see signature.rs
+
+pub const EXTENDED: u8 = 0x80;
+/// Types from this one up must be understood by the server.
+const CRITICAL: u8 = 0x80;
+/// The signing time of a signed request.
+pub const TIMESTAMP: u8 = 0x81;
+
+#[derive(Debug, PartialEq)]
+pub struct Extension {
+    pub kind: u8,
+    pub value: Vec<u8>,
+}
+
+pub fn is_extended(version: u8) -> bool {
+    version & EXTENDED != 0
+}
+
+/// Read the extension area as it is, with its length.
+pub async fn read_area<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Vec<u8>> {
+    let length = reader.read_u16().await?;
+    let mut area = vec![0; 2 + length as usize];
+    area[..2].copy_from_slice(&length.to_be_bytes());
+    reader.read_exact(&mut area[2..]).await?;
+
+    Ok(area)
+}
+
+/// The entries of an extension area.
+pub fn decode(area: &[u8]) -> Result<Vec<Extension>, IpcError> {
+    let truncated = || IpcError::Extension("truncated extension area".to_string());
+
+    let mut entries = area.get(2..).ok_or_else(truncated)?;
+    let mut extensions = Vec::new();
+    while let [kind, high, low, rest @ ..] = entries {
+        let length = u16::from_be_bytes([*high, *low]) as usize;
+        let value = rest.get(..length).ok_or_else(truncated)?;
+        extensions.push(Extension {
+            kind: *kind,
+            value: value.to_vec(),
+        });
+        entries = &rest[length..];
+    }
+    if !entries.is_empty() {
+        return Err(truncated());
+    }
+
+    Ok(extensions)
+}
+
+/// Whether the server understands the type.
+pub fn is_known(kind: u8) -> bool {
+    kind == TIMESTAMP
+}
+
+/// Reject the critical extensions the server doesn't know.
+pub fn check(extensions: &[Extension]) -> Result<(), IpcError> {
+    match extensions
+        .iter()
+        .find(|extension| extension.kind >= CRITICAL && !is_known(extension.kind))
+    {
+        Some(extension) => Err(IpcError::Extension(format!(
+            "unsupported critical extension {}",
+            extension.kind
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// The value of the TIMESTAMP extension, `None` without one.
+pub fn timestamp(extensions: &[Extension]) -> Result<Option<u64>, IpcError> {
+    let Some(extension) = extensions
+        .iter()
+        .find(|extension| extension.kind == TIMESTAMP)
+    else {
+        return Ok(None);
+    };
+    let value: [u8; 8] =
+        extension.value.as_slice().try_into().map_err(|_| {
+            IpcError::Extension("the timestamp extension takes 8 bytes".to_string())
+        })?;
+
+    Ok(Some(u64::from_be_bytes(value)))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// The extension area a client sends.
+    pub fn encode(extensions: &[Extension]) -> Vec<u8> {
+        let mut entries = Vec::new();
+        for extension in extensions {
+            entries.push(extension.kind);
+            entries.extend_from_slice(&(extension.value.len() as u16).to_be_bytes());
+            entries.extend_from_slice(&extension.value);
+        }
+
+        let mut area = (entries.len() as u16).to_be_bytes().to_vec();
+        area.extend(entries);
+        area
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip() {
+        let extensions = vec![
+            Extension {
+                kind: 2,
+                value: b"trace-1".to_vec(),
+            },
+            Extension {
+                kind: 3,
+                value: Vec::new(),
+            },
+        ];
+        let mut record = encode(&extensions);
+        record.extend_from_slice(b"content");
+
+        let mut reader = &record[..];
+        let area = read_area(&mut reader).await.unwrap();
+        assert_eq!(reader, b"content");
+        assert_eq!(decode(&area).unwrap(), extensions);
+        assert!(check(&extensions).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_areas() {
+        assert!(matches!(
+            decode(&[0, 4, 1, 0, 5, b'x']),
+            Err(IpcError::Extension(_))
+        ));
+        assert!(matches!(decode(&[0, 1, 1]), Err(IpcError::Extension(_))));
+
+        let critical = [Extension {
+            kind: 0x90,
+            value: Vec::new(),
+        }];
+        assert!(matches!(check(&critical), Err(IpcError::Extension(_))));
+    }
+
+    #[test]
+    fn test_timestamp() {
+        let signed_at = [Extension {
+            kind: TIMESTAMP,
+            value: 1_700_000_000u64.to_be_bytes().to_vec(),
+        }];
+        assert!(check(&signed_at).is_ok());
+        assert_eq!(timestamp(&signed_at).unwrap(), Some(1_700_000_000));
+        assert_eq!(timestamp(&[]).unwrap(), None);
+
+        let short = [Extension {
+            kind: TIMESTAMP,
+            value: vec![0; 4],
+        }];
+        assert!(matches!(timestamp(&short), Err(IpcError::Extension(_))));
+    }
+}
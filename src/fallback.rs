@@ -0,0 +1,256 @@
+use std::path::Path;
+
+use crate::error::IpcError;
+use crate::formats::Formats;
+use crate::logger;
+use crate::source;
+use crate::{Config, CONTENT_PATH, CONTENT_TEXT};
+
+// ============================================
+// Template format fallback
+// ============================================
+//
+// Clients that mixed up content_format_2 30 (plaintext) and 20 (file path)
+// can be migrated with `format_fallback`, "off" by default:
+//
+// - plaintext that is the path of a file in `templates_root` (one line, no
+//   template syntax) looks like a path,
+// - a path that is not a file in `templates_root` but has several lines or
+//   template syntax looks like plaintext.
+//
+// With "warn" the mix-up is logged as a warning and the declared format is
+// kept, with "correct" it is logged and the template is rendered with the
+// format it looks like (a path as the file in `templates_root`, its scopes
+// are checked as any path). It needs `templates_root`, and a format the
+// listener doesn't allow is never used.
+
+const POLICIES: [&str; 3] = ["off", "warn", "correct"];
+
+/// Reject an unknown `format_fallback`, called at startup.
+pub fn check(config: &Config) -> Result<(), IpcError> {
+    if POLICIES.contains(&config.format_fallback.as_str()) {
+        return Ok(());
+    }
+
+    Err(IpcError::Config(format!(
+        "invalid format_fallback '{}', expected {}",
+        config.format_fallback,
+        POLICIES.join(", ")
+    )))
+}
+
+/// The template and the format to render it with.
+pub async fn resolve(
+    template: String,
+    format: u8,
+    config: &Config,
+    formats: Formats,
+) -> (String, u8) {
+    if config.format_fallback == "off" || (format != CONTENT_TEXT && format != CONTENT_PATH) {
+        return (template, format);
+    }
+    let Some(root) = &config.templates_root else {
+        return (template, format);
+    };
+
+    let Some((detected, file)) = detect(&template, root).await else {
+        return (template, format);
+    };
+    if detected == format {
+        return (template, format);
+    }
+
+    let correct = config.format_fallback == "correct" && formats.allows_template(detected);
+    logger::warning(
+        "Template format looks wrong",
+        &[
+            ("declared", name(format)),
+            ("detected", name(detected)),
+            ("used", name(if correct { detected } else { format })),
+        ],
+    );
+
+    match file {
+        Some(file) if correct => (file, detected),
+        _ if correct => (template, detected),
+        _ => (template, format),
+    }
+}
+
+/// The format `template` looks like and the file of a path, `None` if it
+/// could be either.
+async fn detect(template: &str, root: &str) -> Option<(u8, Option<String>)> {
+    if template.contains('\n') || template.contains("{:") {
+        return Some((CONTENT_TEXT, None));
+    }
+
+    let root = tokio::fs::canonicalize(root).await.ok()?;
+    let file = source::contained(&root, Path::new(template.trim())).await?;
+
+    Some((CONTENT_PATH, Some(file.to_string_lossy().into_owned())))
+}
+
+fn name(format: u8) -> &'static str {
+    if format == CONTENT_PATH {
+        "path"
+    } else {
+        "text"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn policy(policy: &str) -> Config {
+        let mut config = Config::default();
+        config.format_fallback = policy.to_string();
+        config
+    }
+
+    /// A config with `policy` and a `templates_root` of its own.
+    fn config(name: &str, policy_name: &str) -> (Config, PathBuf) {
+        let dir = temp_dir(name);
+        fs::write(dir.join("index.ntpl"), "{:;name:}").unwrap();
+
+        let mut config = policy(policy_name);
+        config.templates_root = Some(dir.to_string_lossy().into_owned());
+        (config, dir)
+    }
+
+    #[test]
+    fn test_check_policy() {
+        for name in POLICIES {
+            assert!(check(&policy(name)).is_ok());
+        }
+        assert!(matches!(check(&policy("on")), Err(IpcError::Config(_))));
+        assert!(matches!(
+            check(&policy("Correct")),
+            Err(IpcError::Config(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_corrected_path_keeps_scopes() {
+        let (mut config, dir) = config("fallback-scopes", "correct");
+        config.render_backend = "mock".to_string();
+        let root = config.templates_root.clone().unwrap();
+        fs::create_dir_all(Path::new(&root).join("internal")).unwrap();
+        fs::write(Path::new(&root).join("internal/admin.ntpl"), "admin").unwrap();
+        config.template_scopes = crate::scopes::parse_templates(
+            &serde_json::json!({ "internal/": ["internal"] }),
+            Some(&root),
+        );
+
+        // A scoped template sent as plaintext is refused like its path.
+        let (template, format) = resolve(
+            "internal/admin.ntpl".to_string(),
+            CONTENT_TEXT,
+            &config,
+            Formats::default(),
+        )
+        .await;
+        assert_eq!(format, CONTENT_PATH);
+        let rendered = crate::render_template(
+            &config,
+            std::sync::Arc::new(b"{}".to_vec()),
+            crate::CONTENT_JSON,
+            template,
+            format,
+            &[],
+        )
+        .await;
+        assert!(matches!(rendered, Err(IpcError::Unauthorized(_))));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_correct_mixed_up_formats() {
+        let (config, dir) = config("fallback-correct", "correct");
+        let all = Formats::default();
+
+        assert_eq!(
+            resolve("index.ntpl".to_string(), CONTENT_TEXT, &config, all)
+                .await
+                .1,
+            CONTENT_PATH
+        );
+        assert_eq!(
+            resolve("<p>{:;name:}</p>".to_string(), CONTENT_PATH, &config, all)
+                .await
+                .1,
+            CONTENT_TEXT
+        );
+        assert_eq!(
+            resolve("index.ntpl".to_string(), CONTENT_PATH, &config, all)
+                .await
+                .1,
+            CONTENT_PATH
+        );
+        assert_eq!(
+            resolve("Hello".to_string(), CONTENT_TEXT, &config, all)
+                .await
+                .1,
+            CONTENT_TEXT
+        );
+
+        let text_only =
+            Formats::from_value(&serde_json::json!({ "template_formats": ["text"] })).unwrap();
+        assert_eq!(
+            resolve("index.ntpl".to_string(), CONTENT_TEXT, &config, text_only)
+                .await
+                .1,
+            CONTENT_TEXT
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_warn_keeps_format() {
+        let (config, dir) = config("fallback-warn", "warn");
+        assert_eq!(
+            resolve(
+                "index.ntpl".to_string(),
+                CONTENT_TEXT,
+                &config,
+                Formats::default()
+            )
+            .await
+            .1,
+            CONTENT_TEXT
+        );
+
+        assert_eq!(
+            resolve(
+                "missing.ntpl".to_string(),
+                CONTENT_PATH,
+                &config,
+                Formats::default()
+            )
+            .await
+            .1,
+            CONTENT_PATH
+        );
+
+        let mut config = config;
+        config.format_fallback = "off".to_string();
+        assert_eq!(
+            resolve(
+                "<p>{:;x:}</p>".to_string(),
+                CONTENT_PATH,
+                &config,
+                Formats::default()
+            )
+            .await
+            .1,
+            CONTENT_PATH
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
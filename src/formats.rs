@@ -0,0 +1,196 @@
+use serde_json::Value;
+
+use crate::error::IpcError;
+use crate::{
+    Header, CONTENT_BIN, CONTENT_CBOR, CONTENT_JSON, CONTENT_MSGPACK, CONTENT_PATH, CONTENT_TEXT,
+    CTRL_PARSE_TEMPLATE, CTRL_PARSE_TEMPLATE_CHUNKED, CTRL_PARSE_TRANSACTION, CTRL_TEMPLATE_SOURCE,
+};
+
+// ============================================
+// Content formats per listener
+// ============================================
+//
+// A `listeners` entry can restrict the formats it accepts, e.g. a public
+// listener with inline templates only:
+//
+// { "address": "tcp://0.0.0.0:4273", "template_formats": ["text"] }
+//
+// Checked on the header, before the contents are read. Missing lists allow
+// every format.
+
+/// Content formats accepted on a listener, one bit per format code / 10.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Formats {
+    schema: u8,
+    template: u8,
+}
+
+impl Default for Formats {
+    fn default() -> Self {
+        Formats {
+            schema: bit(CONTENT_JSON) | bit(CONTENT_MSGPACK) | bit(CONTENT_CBOR),
+            template: bit(CONTENT_TEXT) | bit(CONTENT_PATH) | bit(CONTENT_BIN),
+        }
+    }
+}
+
+fn bit(format: u8) -> u8 {
+    1 << (format / 10)
+}
+
+fn parse_list(value: &Value, key: &str, names: &[(&str, u8)]) -> Result<Option<u8>, String> {
+    let Some(list) = value[key].as_array() else {
+        return Ok(None);
+    };
+
+    let mut mask = 0;
+    for name in list {
+        let format = names
+            .iter()
+            .find(|(known, _)| Some(*known) == name.as_str())
+            .ok_or_else(|| format!("{}: unknown format {}", key, name))?;
+        mask |= bit(format.1);
+    }
+
+    Ok(Some(mask))
+}
+
+impl Formats {
+    /// Formats from a listener object, `schema_formats` ("json", "msgpack", "cbor")
+    /// and `template_formats` ("text", "path", "bin").
+    pub fn from_value(value: &Value) -> Result<Self, String> {
+        let all = Formats::default();
+        let schema = parse_list(
+            value,
+            "schema_formats",
+            &[
+                ("json", CONTENT_JSON),
+                ("msgpack", CONTENT_MSGPACK),
+                ("cbor", CONTENT_CBOR),
+            ],
+        )?;
+        let template = parse_list(
+            value,
+            "template_formats",
+            &[
+                ("text", CONTENT_TEXT),
+                ("path", CONTENT_PATH),
+                ("bin", CONTENT_BIN),
+            ],
+        )?;
+
+        Ok(Formats {
+            schema: schema.unwrap_or(all.schema),
+            template: template.unwrap_or(all.template),
+        })
+    }
+
+    /// Reject a request using a format not allowed on this listener.
+    pub fn check(&self, header: &Header) -> Result<(), IpcError> {
+        let schema = matches!(
+            header.control,
+            CTRL_PARSE_TEMPLATE | CTRL_PARSE_TEMPLATE_CHUNKED | CTRL_PARSE_TRANSACTION
+        );
+        // Content 2 of a transaction is its JSON manifest, not a template.
+        let template = matches!(
+            header.control,
+            CTRL_PARSE_TEMPLATE | CTRL_PARSE_TEMPLATE_CHUNKED | CTRL_TEMPLATE_SOURCE
+        );
+
+        if schema && !allows(self.schema, header.content_format_1) {
+            return Err(IpcError::FormatNotAllowed {
+                block: 1,
+                format: header.content_format_1,
+            });
+        }
+        if template && !allows(self.template, header.content_format_2) {
+            return Err(IpcError::FormatNotAllowed {
+                block: 2,
+                format: header.content_format_2,
+            });
+        }
+        // The manifest of a transaction lists template paths.
+        if header.control == CTRL_PARSE_TRANSACTION && !allows(self.template, CONTENT_PATH) {
+            return Err(IpcError::FormatNotAllowed {
+                block: 2,
+                format: CONTENT_PATH,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn allows_template(&self, format: u8) -> bool {
+        allows(self.template, format)
+    }
+}
+
+/// Unknown format codes are left to the control code handler to reject.
+fn allows(mask: u8, format: u8) -> bool {
+    let known = matches!(
+        format,
+        CONTENT_JSON | CONTENT_MSGPACK | CONTENT_CBOR | CONTENT_TEXT | CONTENT_PATH | CONTENT_BIN
+    );
+
+    !known || mask & bit(format) != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn header(control: u8, format_1: u8, format_2: u8) -> Header {
+        Header {
+            version: 0,
+            control,
+            content_format_1: format_1,
+            content_length_1: 0,
+            content_format_2: format_2,
+            content_length_2: 0,
+        }
+    }
+
+    #[test]
+    fn test_default_allows_all() {
+        let formats = Formats::default();
+        assert!(formats
+            .check(&header(CTRL_PARSE_TEMPLATE, CONTENT_MSGPACK, CONTENT_PATH))
+            .is_ok());
+        assert_eq!(Formats::from_value(&json!({})).unwrap(), formats);
+    }
+
+    #[test]
+    fn test_restricted_formats() {
+        let formats = Formats::from_value(&json!({ "template_formats": ["text"] })).unwrap();
+
+        assert!(formats
+            .check(&header(CTRL_PARSE_TEMPLATE, CONTENT_JSON, CONTENT_TEXT))
+            .is_ok());
+        assert!(matches!(
+            formats.check(&header(CTRL_PARSE_TEMPLATE, CONTENT_JSON, CONTENT_PATH)),
+            Err(IpcError::FormatNotAllowed { block: 2, .. })
+        ));
+        assert!(matches!(
+            formats.check(&header(CTRL_TEMPLATE_SOURCE, 0, CONTENT_PATH)),
+            Err(IpcError::FormatNotAllowed { block: 2, .. })
+        ));
+
+        // The manifest of a transaction is JSON and lists paths.
+        let paths = Formats::from_value(&json!({ "template_formats": ["path"] })).unwrap();
+        assert!(paths
+            .check(&header(CTRL_PARSE_TRANSACTION, CONTENT_JSON, CONTENT_JSON))
+            .is_ok());
+        assert!(matches!(
+            formats.check(&header(CTRL_PARSE_TRANSACTION, CONTENT_JSON, CONTENT_JSON)),
+            Err(IpcError::FormatNotAllowed { block: 2, .. })
+        ));
+
+        // Unknown formats are rejected later with the usual error.
+        assert!(formats
+            .check(&header(CTRL_PARSE_TEMPLATE, CONTENT_JSON, 99))
+            .is_ok());
+
+        assert!(Formats::from_value(&json!({ "schema_formats": ["xml"] })).is_err());
+    }
+}
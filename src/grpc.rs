@@ -0,0 +1,318 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::net::TcpListener;
+use tonic::body::BoxBody;
+use tonic::codec::ProstCodec;
+use tonic::codegen::{http, Body, BoxFuture, Service, StdError};
+use tonic::server::{Grpc, NamedService, UnaryService};
+use tonic::transport::server::TcpIncoming;
+use tonic::{Code, Status};
+
+use crate::error::{ErrorClass, IpcError};
+use crate::logger;
+use crate::{Config, CONTENT_JSON, CONTENT_PATH, CONTENT_TEXT};
+
+// ============================================
+// gRPC facade
+// ============================================
+//
+// The `neutral_ipc.Render` service of proto/neutral_ipc.proto on the `grpc`
+// address, for clients generated from the .proto. The RPCs map onto the same
+// render, validation and metrics as the binary protocol. The messages are
+// written by hand below so the build needs no protoc, keep them in sync with
+// the .proto.
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RenderRequest {
+    #[prost(bytes = "vec", tag = "1")]
+    pub schema: Vec<u8>,
+    #[prost(uint32, tag = "2")]
+    pub schema_format: u32,
+    #[prost(string, tag = "3")]
+    pub template: String,
+    #[prost(uint32, tag = "4")]
+    pub template_format: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RenderReply {
+    #[prost(string, tag = "1")]
+    pub metadata: String,
+    #[prost(string, tag = "2")]
+    pub content: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ValidateRequest {
+    #[prost(bytes = "vec", tag = "1")]
+    pub schema: Vec<u8>,
+    #[prost(string, tag = "2")]
+    pub template: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ValidateReply {
+    #[prost(string, repeated, tag = "1")]
+    pub violations: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StatsRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StatsReply {
+    #[prost(string, tag = "1")]
+    pub json: String,
+}
+
+pub async fn serve(listener: TcpListener, config: Arc<Config>) {
+    let incoming = match TcpIncoming::from_listener(listener, true, None) {
+        Ok(incoming) => incoming,
+        Err(e) => {
+            logger::error(&format!("gRPC listener failed: {}", e), &[]);
+            return;
+        }
+    };
+
+    if let Err(e) = tonic::transport::Server::builder()
+        .add_service(RenderService { config })
+        .serve_with_incoming(incoming)
+        .await
+    {
+        logger::error(&format!("gRPC server failed: {}", e), &[]);
+    }
+}
+
+async fn render(config: Arc<Config>, request: RenderRequest) -> Result<RenderReply, Status> {
+    #[cfg(feature = "metrics")]
+    let started = std::time::Instant::now();
+
+    let outcome = render_request(&config, request).await;
+
+    #[cfg(feature = "metrics")]
+    crate::metrics::record(
+        crate::CTRL_PARSE_TEMPLATE,
+        started.elapsed(),
+        outcome.is_ok(),
+    );
+
+    match outcome {
+        Ok(result) => Ok(RenderReply {
+            metadata: result.json,
+            content: result.text,
+        }),
+        Err(e) => {
+            logger::error(
+                &format!("Failed to handle client: {}", e),
+                &[("peer", "grpc"), ("class", e.class().as_str())],
+            );
+            Err(to_status(&e))
+        }
+    }
+}
+
+async fn render_request(
+    config: &Config,
+    request: RenderRequest,
+) -> Result<crate::ParseTemplateResult, IpcError> {
+    let schema_format = match request.schema_format {
+        0 => CONTENT_JSON,
+        format => u8::try_from(format).unwrap_or(u8::MAX),
+    };
+    let template_format = match request.template_format {
+        0 => CONTENT_TEXT,
+        format => u8::try_from(format).unwrap_or(u8::MAX),
+    };
+
+    if !crate::schema_format_supported(schema_format) {
+        return Err(IpcError::InvalidFormat {
+            block: 1,
+            format: schema_format,
+            expected: crate::SCHEMA_FORMATS,
+        });
+    }
+    if template_format != CONTENT_TEXT && template_format != CONTENT_PATH {
+        return Err(IpcError::InvalidFormat {
+            block: 2,
+            format: template_format,
+            expected: "TEXT or PATH",
+        });
+    }
+
+    crate::render_template(
+        config,
+        Arc::new(request.schema),
+        schema_format,
+        request.template,
+        template_format,
+        &[],
+    )
+    .await
+}
+
+async fn validate(config: Arc<Config>, request: ValidateRequest) -> Result<ValidateReply, Status> {
+    let template =
+        crate::resolve_template_path(request.template, &config).map_err(|e| to_status(&e))?;
+    let violations = tokio::task::spawn_blocking(move || {
+        crate::validation::validate(&template, &request.schema)
+    })
+    .await
+    .map_err(|e| Status::internal(e.to_string()))?;
+
+    Ok(ValidateReply { violations })
+}
+
+#[cfg(feature = "metrics")]
+async fn stats(_config: Arc<Config>, _request: StatsRequest) -> Result<StatsReply, Status> {
+    Ok(StatsReply {
+        json: crate::metrics::snapshot().to_string(),
+    })
+}
+
+#[cfg(not(feature = "metrics"))]
+async fn stats(_config: Arc<Config>, _request: StatsRequest) -> Result<StatsReply, Status> {
+    Err(Status::unimplemented(
+        "metrics are not compiled in (feature metrics)",
+    ))
+}
+
+fn to_status(error: &IpcError) -> Status {
+    let code = match error.class() {
+        ErrorClass::Protocol | ErrorClass::Content => Code::InvalidArgument,
+        ErrorClass::Connection | ErrorClass::Render | ErrorClass::Config => Code::Internal,
+        ErrorClass::Unavailable => Code::Unavailable,
+        ErrorClass::Forbidden => Code::PermissionDenied,
+        ErrorClass::Unauthorized => Code::Unauthenticated,
+        ErrorClass::Busy => Code::ResourceExhausted,
+        ErrorClass::Cancelled => Code::Cancelled,
+    };
+
+    Status::new(code, error.to_string())
+}
+
+/// An RPC handled by an async fn.
+struct Rpc<F> {
+    config: Arc<Config>,
+    handler: F,
+}
+
+impl<T, U, F, Fut> UnaryService<T> for Rpc<F>
+where
+    F: Fn(Arc<Config>, T) -> Fut,
+    Fut: Future<Output = Result<U, Status>> + Send + 'static,
+{
+    type Response = U;
+    type Future = BoxFuture<tonic::Response<U>, Status>;
+
+    fn call(&mut self, request: tonic::Request<T>) -> Self::Future {
+        let reply = (self.handler)(Arc::clone(&self.config), request.into_inner());
+        Box::pin(async move { reply.await.map(tonic::Response::new) })
+    }
+}
+
+async fn unary<T, U, F, Fut, B>(rpc: Rpc<F>, request: http::Request<B>) -> http::Response<BoxBody>
+where
+    T: prost::Message + Default + Send + 'static,
+    U: prost::Message + Send + 'static,
+    F: Fn(Arc<Config>, T) -> Fut,
+    Fut: Future<Output = Result<U, Status>> + Send + 'static,
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    Grpc::new(ProstCodec::<U, T>::default())
+        .unary(rpc, request)
+        .await
+}
+
+#[derive(Clone)]
+struct RenderService {
+    config: Arc<Config>,
+}
+
+impl NamedService for RenderService {
+    const NAME: &'static str = "neutral_ipc.Render";
+}
+
+impl<B> Service<http::Request<B>> for RenderService
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let config = Arc::clone(&self.config);
+        match request.uri().path() {
+            "/neutral_ipc.Render/Render" => Box::pin(async move {
+                let rpc = Rpc {
+                    config,
+                    handler: render,
+                };
+                Ok(unary(rpc, request).await)
+            }),
+            "/neutral_ipc.Render/Validate" => Box::pin(async move {
+                let rpc = Rpc {
+                    config,
+                    handler: validate,
+                };
+                Ok(unary(rpc, request).await)
+            }),
+            "/neutral_ipc.Render/Stats" => Box::pin(async move {
+                let rpc = Rpc {
+                    config,
+                    handler: stats,
+                };
+                Ok(unary(rpc, request).await)
+            }),
+            path => {
+                let status = Status::unimplemented(format!("unknown method {}", path));
+                Box::pin(async move { Ok(status.into_http()) })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mock_config() -> Arc<Config> {
+        let mut config = Config::default();
+        config.render_backend = "mock".to_string();
+        Arc::new(config)
+    }
+
+    #[tokio::test]
+    async fn test_render_rpc() {
+        let request = RenderRequest {
+            schema: b"{}".to_vec(),
+            schema_format: 0,
+            template: "Hello".to_string(),
+            template_format: 0,
+        };
+        let reply = render(mock_config(), request).await.unwrap();
+
+        assert_eq!(reply.content, "Hello");
+    }
+
+    #[tokio::test]
+    async fn test_render_rpc_invalid_format() {
+        let request = RenderRequest {
+            schema: b"{}".to_vec(),
+            schema_format: 99,
+            template: "Hello".to_string(),
+            template_format: 0,
+        };
+        let status = render(mock_config(), request).await.unwrap_err();
+
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+}
@@ -0,0 +1,350 @@
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+use crate::error::IpcError;
+use crate::logger;
+use crate::{Config, ParseTemplateResult, CONTENT_JSON, CONTENT_PATH, CONTENT_TEXT};
+
+// ============================================
+// HTTP gateway
+// ============================================
+//
+// POST /render with a JSON body:
+//
+// { "schema": {...}, "template": "source" }   or
+// { "schema": {...}, "path": "/path/or/@alias" }
+//
+// renders like a parse template record and responds with a JSON body:
+//
+// { "metadata": { "has_error": ..., "status_code": ... }, "content": "..." }
+//
+// The HTTP status is the metadata status_code for errors the server
+// reports (400, 500, 503), 200 otherwise.
+//
+// Response headers: the `http_headers` config object is added to every
+// rendered response, and an `http_headers` object at the top of the
+// request schema adds to it or overrides it, e.g.
+//
+// { "http_headers": { "Content-Type": "text/html; charset=utf-8",
+//                     "Cache-Control": "no-cache" } }
+//
+// When they set Content-Type the body is the rendered content as it is,
+// with the metadata status_code as the HTTP status, so pages can be
+// served directly. Error replies keep the JSON body and no extra headers.
+
+/// Headers the gateway sets itself.
+const RESERVED_HEADERS: [&str; 3] = ["connection", "content-length", "transfer-encoding"];
+
+#[derive(Debug, Deserialize)]
+struct RenderBody {
+    schema: Option<Value>,
+    template: Option<String>,
+    path: Option<String>,
+}
+
+pub async fn serve(listener: TcpListener, config: Arc<Config>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let config = Arc::clone(&config);
+                let peer = peer.to_string();
+                crate::tasks::spawn(peer.clone(), async move {
+                    let (config, peer) = (&*config, peer.as_str());
+                    let service = service_fn(move |request| handle(request, config, peer));
+                    if let Err(e) = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await
+                    {
+                        logger::debug(&format!("HTTP connection closed: {}", e), &[("peer", peer)]);
+                    }
+                });
+            }
+            Err(e) => logger::error(&format!("Failed to accept connection: {}", e), &[]),
+        }
+    }
+}
+
+async fn handle(
+    request: Request<Incoming>,
+    config: &Config,
+    peer: &str,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    if request.uri().path() != "/render" {
+        return Ok(reply(
+            StatusCode::NOT_FOUND,
+            json!({ "error": "not found" }),
+        ));
+    }
+    if request.method() != Method::POST {
+        return Ok(reply(
+            StatusCode::METHOD_NOT_ALLOWED,
+            json!({ "error": "method not allowed, use POST" }),
+        ));
+    }
+
+    let body = match Limited::new(request.into_body(), config.http_max_body as usize)
+        .collect()
+        .await
+    {
+        Ok(body) => body.to_bytes(),
+        Err(e) => {
+            return Ok(reply(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                json!({ "error": e.to_string() }),
+            ))
+        }
+    };
+
+    #[cfg(feature = "metrics")]
+    let started = std::time::Instant::now();
+
+    let outcome = render(&body, config).await;
+
+    #[cfg(feature = "metrics")]
+    crate::metrics::record(
+        crate::CTRL_PARSE_TEMPLATE,
+        started.elapsed(),
+        outcome.is_ok(),
+    );
+
+    Ok(match outcome {
+        Ok((result, headers)) => rendered(result, headers),
+        Err(e) => {
+            logger::error(
+                &format!("Failed to handle client: {}", e),
+                &[("peer", peer), ("class", e.class().as_str())],
+            );
+            let status = e
+                .class()
+                .status()
+                .0
+                .parse()
+                .ok()
+                .and_then(|code| StatusCode::from_u16(code).ok())
+                .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+            let result = ParseTemplateResult {
+                json: e.to_json(),
+                text: String::new(),
+                status: e.control(),
+                binary: None,
+            };
+            reply(status, to_json(&result))
+        }
+    })
+}
+
+/// Check the `http_headers` config at startup.
+pub fn check(config: &Config) -> Result<(), IpcError> {
+    add_headers(&mut HeaderMap::new(), &config.http_headers)
+        .map_err(|e| IpcError::Config(format!("http_headers: {}", e)))
+}
+
+async fn render(
+    body: &[u8],
+    config: &Config,
+) -> Result<(ParseTemplateResult, HeaderMap), IpcError> {
+    let body: RenderBody = serde_json::from_slice(body)
+        .map_err(|e| IpcError::Schema(format!("request body: {}", e)))?;
+    let schema = body.schema.unwrap_or_else(|| json!({}));
+
+    let mut headers = HeaderMap::new();
+    add_headers(&mut headers, &config.http_headers).map_err(IpcError::Config)?;
+    if let Some(requested) = schema.get("http_headers") {
+        let requested = requested
+            .as_object()
+            .and_then(|requested| {
+                requested
+                    .iter()
+                    .map(|(name, value)| {
+                        value
+                            .as_str()
+                            .map(|value| (name.clone(), value.to_string()))
+                    })
+                    .collect::<Option<Vec<_>>>()
+            })
+            .ok_or_else(|| {
+                IpcError::Schema("http_headers: expected an object of strings".to_string())
+            })?;
+        add_headers(&mut headers, &requested)
+            .map_err(|e| IpcError::Schema(format!("http_headers: {}", e)))?;
+    }
+    let schema = schema.to_string().into_bytes();
+
+    let (template, template_format) = match (body.template, body.path) {
+        (Some(template), None) => (template, CONTENT_TEXT),
+        (None, Some(path)) => (path, CONTENT_PATH),
+        _ => {
+            return Err(IpcError::Schema(
+                "request body: set either template or path".to_string(),
+            ))
+        }
+    };
+
+    let result = crate::render_template(
+        config,
+        Arc::new(schema),
+        CONTENT_JSON,
+        template,
+        template_format,
+        &[],
+    )
+    .await?;
+    Ok((result, headers))
+}
+
+/// Add the headers, replacing any already set with the same name.
+fn add_headers(headers: &mut HeaderMap, pairs: &[(String, String)]) -> Result<(), String> {
+    for (name, value) in pairs {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("invalid header name '{}'", name))?;
+        if RESERVED_HEADERS.contains(&name.as_str()) {
+            return Err(format!("header '{}' is set by the server", name));
+        }
+        let value = HeaderValue::from_str(value)
+            .map_err(|_| format!("invalid value for header '{}'", name))?;
+        headers.insert(name, value);
+    }
+    Ok(())
+}
+
+fn rendered(result: ParseTemplateResult, headers: HeaderMap) -> Response<Full<Bytes>> {
+    let mut response = if headers.contains_key(CONTENT_TYPE) {
+        let status = serde_json::from_str::<Value>(&result.json)
+            .ok()
+            .and_then(|metadata| metadata["status_code"].as_str()?.parse().ok())
+            .and_then(|code| StatusCode::from_u16(code).ok())
+            .unwrap_or(StatusCode::OK);
+        let mut response = Response::new(Full::new(Bytes::from(result.text)));
+        *response.status_mut() = status;
+        response
+    } else {
+        reply(StatusCode::OK, to_json(&result))
+    };
+    for (name, value) in headers {
+        if let Some(name) = name {
+            response.headers_mut().insert(name, value);
+        }
+    }
+    response
+}
+
+fn to_json(result: &ParseTemplateResult) -> Value {
+    json!({
+        "metadata": serde_json::from_str::<Value>(&result.json).unwrap_or(Value::Null),
+        "content": result.text
+    })
+}
+
+fn reply(status: StatusCode, body: Value) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from(body.to_string())));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        "application/json".parse().expect("valid header value"),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mock_config() -> Config {
+        let mut config = Config::default();
+        config.render_backend = "mock".to_string();
+        config
+    }
+
+    #[tokio::test]
+    async fn test_render_template_body() {
+        let body = br#"{"schema": {"data": {}}, "template": "Hello"}"#;
+        let (result, headers) = render(body, &mock_config()).await.unwrap();
+
+        assert_eq!(to_json(&result)["content"], "Hello");
+        assert_eq!(to_json(&result)["metadata"]["has_error"], false);
+        assert!(headers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_render_response_headers() {
+        let mut config = mock_config();
+        config.http_headers = vec![
+            ("Cache-Control".to_string(), "no-cache".to_string()),
+            ("X-Served-By".to_string(), "neutral-ipc".to_string()),
+        ];
+        let body = br#"{"schema": {"http_headers": {"Content-Type": "text/html", "cache-control": "max-age=60"}}, "template": "<p>Hello</p>"}"#;
+        let (result, headers) = render(body, &config).await.unwrap();
+        let response = rendered(result, headers);
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/html");
+        assert_eq!(response.headers()["cache-control"], "max-age=60");
+        assert_eq!(response.headers()["x-served-by"], "neutral-ipc");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"<p>Hello</p>");
+    }
+
+    #[tokio::test]
+    async fn test_render_json_with_headers() {
+        let mut config = mock_config();
+        config.http_headers = vec![("Cache-Control".to_string(), "no-store".to_string())];
+        let (result, headers) = render(br#"{"template": "Hello"}"#, &config).await.unwrap();
+        let response = rendered(result, headers);
+
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(response.headers()["cache-control"], "no-store");
+    }
+
+    #[tokio::test]
+    async fn test_render_invalid_headers() {
+        let config = mock_config();
+
+        for body in [
+            &br#"{"schema": {"http_headers": {"Content-Length": "1"}}, "template": "a"}"#[..],
+            br#"{"schema": {"http_headers": {"bad name": "a"}}, "template": "a"}"#,
+            br#"{"schema": {"http_headers": {"X-Count": 1}}, "template": "a"}"#,
+            br#"{"schema": {"http_headers": "text/html"}, "template": "a"}"#,
+        ] {
+            assert!(matches!(
+                render(body, &config).await,
+                Err(IpcError::Schema(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_check_headers() {
+        let mut config = mock_config();
+        config.http_headers = vec![("Content-Type".to_string(), "text/html".to_string())];
+        assert!(check(&config).is_ok());
+
+        config.http_headers = vec![("Transfer-Encoding".to_string(), "chunked".to_string())];
+        assert!(matches!(check(&config), Err(IpcError::Config(_))));
+        config.http_headers = vec![("X-Line".to_string(), "a\nb".to_string())];
+        assert!(matches!(check(&config), Err(IpcError::Config(_))));
+    }
+
+    #[tokio::test]
+    async fn test_render_invalid_body() {
+        let config = mock_config();
+
+        assert!(matches!(
+            render(b"not json", &config).await,
+            Err(IpcError::Schema(_))
+        ));
+        assert!(matches!(
+            render(br#"{"template": "a", "path": "b"}"#, &config).await,
+            Err(IpcError::Schema(_))
+        ));
+    }
+}
@@ -0,0 +1,322 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::error::IpcError;
+use crate::Config;
+
+// ============================================
+// Key-value store
+// ============================================
+//
+// With `kv_max_bytes` (0 disabled) the server keeps JSON values by key for
+// fragments shared between requests, e.g. a navigation menu computed once.
+// The kv control code (CTRL_KV) takes in content 1
+//
+// {"op": "set", "key": "nav", "ttl_secs": 300}
+//
+// with the value (JSON) in content 2, or "get" and "delete" without content
+// 2. Set and delete must be signed, the values end up in the schemas of
+// other clients. A JSON (or CBOR) schema uses a value with an object
+// {"$kv": "nav"}, replaced by it before rendering off the runtime threads;
+// MsgPack schemas are not resolved. Keys and values count against
+// `kv_max_bytes`, the expired entries and then the oldest ones are evicted
+// to make room.
+
+struct Entry {
+    value: Value,
+    size: usize,
+    stored: Instant,
+    expires: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Store {
+    entries: HashMap<String, Entry>,
+    bytes: usize,
+}
+
+static STORE: OnceLock<Mutex<Store>> = OnceLock::new();
+
+fn store() -> std::sync::MutexGuard<'static, Store> {
+    STORE
+        .get_or_init(Mutex::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+impl Entry {
+    fn expired(&self, now: Instant) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+impl Store {
+    fn remove(&mut self, key: &str) -> bool {
+        match self.entries.remove(key) {
+            Some(entry) => {
+                self.bytes -= entry.size;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Evict until `size` more bytes fit in `max`.
+    fn make_room(&mut self, size: usize, max: usize) {
+        let now = Instant::now();
+        let expired: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.remove(&key);
+        }
+
+        while self.bytes + size > max {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.stored)
+                .map(|(key, _)| key.clone());
+            let Some(key) = oldest else {
+                return;
+            };
+            self.remove(&key);
+        }
+    }
+}
+
+pub fn enabled(config: &Config) -> bool {
+    config.kv_max_bytes > 0
+}
+
+pub fn set(
+    key: &str,
+    value: Value,
+    ttl: Option<Duration>,
+    config: &Config,
+) -> Result<(), IpcError> {
+    let size = key.len() + value.to_string().len();
+    if size > config.kv_max_bytes {
+        return Err(IpcError::ContentTooLarge {
+            block: 2,
+            length: size as u64,
+            max: config.kv_max_bytes as u64,
+        });
+    }
+
+    let mut store = store();
+    store.remove(key);
+    store.make_room(size, config.kv_max_bytes);
+    let now = Instant::now();
+    store.entries.insert(
+        key.to_string(),
+        Entry {
+            value,
+            size,
+            stored: now,
+            expires: ttl.map(|ttl| now + ttl),
+        },
+    );
+    store.bytes += size;
+
+    Ok(())
+}
+
+pub fn get(key: &str) -> Option<Value> {
+    let mut store = store();
+    let expired = store.entries.get(key)?.expired(Instant::now());
+    if expired {
+        store.remove(key);
+        return None;
+    }
+
+    store.entries.get(key).map(|entry| entry.value.clone())
+}
+
+pub fn delete(key: &str) -> bool {
+    store().remove(key)
+}
+
+/// Run a kv request, `request` is content 1 and `value` content 2.
+pub fn handle(
+    request: &[u8],
+    value: &[u8],
+    signed: bool,
+    config: &Config,
+) -> Result<Value, IpcError> {
+    let request: Value = serde_json::from_slice(request)
+        .map_err(|e| IpcError::Schema(format!("kv request: {}", e)))?;
+    let key = request["key"]
+        .as_str()
+        .ok_or_else(|| IpcError::Schema("kv request: missing key".to_string()))?;
+    if !signed && matches!(request["op"].as_str(), Some("set" | "delete")) {
+        return Err(IpcError::Unauthorized(
+            "kv set and delete must be signed".to_string(),
+        ));
+    }
+
+    match request["op"].as_str() {
+        Some("set") => {
+            let value: Value = serde_json::from_slice(value)
+                .map_err(|e| IpcError::Schema(format!("kv value: {}", e)))?;
+            let ttl = request["ttl_secs"].as_u64().map(Duration::from_secs);
+            set(key, value, ttl, config)?;
+            Ok(json!({ "stored": true }))
+        }
+        Some("get") => match get(key) {
+            Some(value) => Ok(json!({ "found": true, "value": value })),
+            None => Ok(json!({ "found": false })),
+        },
+        Some("delete") => Ok(json!({ "deleted": delete(key) })),
+        _ => Err(IpcError::Schema(
+            "kv request: op must be set, get or delete".to_string(),
+        )),
+    }
+}
+
+/// Whether the JSON schema may have {"$kv": key} references to resolve, a
+/// hint to skip parsing it: the key can be written with escapes
+/// ("\u0024kv"), so any `$` or `\u` may be one.
+pub fn wanted(schema: &[u8], config: &Config) -> bool {
+    enabled(config) && (schema.contains(&b'$') || schema.windows(2).any(|window| window == b"\\u"))
+}
+
+/// The JSON schema with its {"$kv": key} references replaced, `None` if it
+/// has none or is not JSON. Keys keep their order. It parses the whole
+/// schema, so it runs off the runtime threads.
+pub fn resolve(schema: &[u8]) -> Result<Option<Vec<u8>>, IpcError> {
+    // Not JSON: the engine reports it when rendering.
+    let Ok(mut value) = serde_json::from_slice::<Value>(schema) else {
+        return Ok(None);
+    };
+    if !replace(&mut value)? {
+        return Ok(None);
+    }
+
+    Ok(Some(value.to_string().into_bytes()))
+}
+
+/// Replace the references in the value, whether there was any.
+fn replace(value: &mut Value) -> Result<bool, IpcError> {
+    if let Some(key) = reference(value) {
+        *value = get(&key).ok_or_else(|| IpcError::Schema(format!("unknown kv key '{}'", key)))?;
+        return Ok(true);
+    }
+
+    let mut replaced = false;
+    match value {
+        Value::Object(map) => {
+            for item in map.values_mut() {
+                replaced |= replace(item)?;
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                replaced |= replace(item)?;
+            }
+        }
+        _ => {}
+    }
+
+    Ok(replaced)
+}
+
+/// The key of a {"$kv": key} object.
+fn reference(value: &Value) -> Option<String> {
+    let map = value.as_object().filter(|map| map.len() == 1)?;
+    map.get("$kv")?.as_str().map(String::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(max: usize) -> Config {
+        let mut config = Config::default();
+        config.kv_max_bytes = max;
+        config
+    }
+
+    #[test]
+    fn test_set_get_delete() {
+        let config = config(1024);
+        let stored = handle(
+            br#"{"op": "set", "key": "test/nav"}"#,
+            br#"["Home", "About"]"#,
+            true,
+            &config,
+        )
+        .unwrap();
+        assert_eq!(stored["stored"], true);
+
+        let found = handle(br#"{"op": "get", "key": "test/nav"}"#, b"", false, &config).unwrap();
+        assert_eq!(found["value"][1], "About");
+
+        assert!(delete("test/nav"));
+        assert!(get("test/nav").is_none());
+        assert!(handle(br#"{"op": "list", "key": "x"}"#, b"", true, &config).is_err());
+    }
+
+    #[test]
+    fn test_ttl_and_memory_cap() {
+        let config = config(1024);
+        set("test/expired", json!("gone"), Some(Duration::ZERO), &config).unwrap();
+        assert!(get("test/expired").is_none());
+
+        assert!(set("test/big", json!("x".repeat(2000)), None, &config).is_err());
+    }
+
+    #[test]
+    fn test_unsigned_writes() {
+        let config = config(1024);
+        for request in [
+            br#"{"op": "set", "key": "test/unsigned"}"#.as_slice(),
+            br#"{"op": "delete", "key": "test/unsigned"}"#,
+        ] {
+            assert!(matches!(
+                handle(request, b"1", false, &config),
+                Err(IpcError::Unauthorized(_))
+            ));
+        }
+        assert!(get("test/unsigned").is_none());
+    }
+
+    #[test]
+    fn test_resolve_schema() {
+        let config = config(1024);
+        set("test/menu", json!({ "items": [1, 2] }), None, &config).unwrap();
+
+        let schema = br#"{"data": {"title": "T", "menu": {"$kv": "test/menu"}, "after": 1}}"#;
+        assert!(wanted(schema, &config));
+        let resolved = resolve(schema).unwrap().unwrap();
+        assert_eq!(
+            resolved,
+            br#"{"data":{"title":"T","menu":{"items":[1,2]},"after":1}}"#
+        );
+
+        assert!(resolve(br#"{"a": {"$kv": "test/none"}}"#).is_err());
+        assert!(!wanted(br#"{"a": 1}"#, &config));
+        assert!(!wanted(schema, &Config::default()));
+        assert!(resolve(br#"{"price": "$5"}"#).unwrap().is_none());
+        assert!(resolve(b"not json").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_resolve_escaped_reference() {
+        let config = config(1024);
+        set("test/escaped", json!("value"), None, &config).unwrap();
+
+        let schema =
+            br#"{"a": {"\u0024kv": "test/escaped"}, "b": {"\u0024\u006bv": "test/escaped"}}"#;
+        assert!(wanted(schema, &config));
+        assert_eq!(
+            resolve(schema).unwrap().unwrap(),
+            br#"{"a":"value","b":"value"}"#
+        );
+    }
+}
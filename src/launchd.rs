@@ -0,0 +1,197 @@
+use std::env;
+use std::fs;
+use std::io;
+
+use crate::Config;
+
+// ============================================
+// macOS launchd integration
+// ============================================
+//
+// `neutral-ipc install-launchd [--output PATH] [--program PATH] [--no-socket]`
+// writes a LaunchDaemon plist. By default launchd owns the listening socket
+// (socket activation) and starts the daemon on the first connection, the
+// daemon takes the socket with launch_activate_socket().
+
+pub const LABEL: &str = "io.github.franbarinstance.neutral-ipc";
+pub const PLIST_PATH: &str = "/Library/LaunchDaemons/io.github.franbarinstance.neutral-ipc.plist";
+pub const LOG_PATH: &str = "/var/log/neutral-ipc.log";
+pub const SOCKET_NAME: &str = "Listeners";
+
+/// Generate the LaunchDaemon plist, `socket` enables socket activation.
+pub fn plist(program: &str, config: &Config, socket: bool) -> String {
+    let activation = if socket {
+        format!(
+            r#"    <key>Sockets</key>
+    <dict>
+        <key>{}</key>
+        <dict>
+            <key>SockNodeName</key>
+            <string>{}</string>
+            <key>SockServiceName</key>
+            <string>{}</string>
+            <key>SockType</key>
+            <string>stream</string>
+        </dict>
+    </dict>
+"#,
+            SOCKET_NAME,
+            escape_xml(&config.host),
+            escape_xml(&config.port)
+        )
+    } else {
+        "    <key>RunAtLoad</key>\n    <true/>\n    <key>KeepAlive</key>\n    <true/>\n".to_string()
+    };
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{}</string>
+    <key>ProgramArguments</key>
+    <array>
+        <string>{}</string>
+    </array>
+{}    <key>StandardOutPath</key>
+    <string>{}</string>
+    <key>StandardErrorPath</key>
+    <string>{}</string>
+</dict>
+</plist>
+"#,
+        LABEL,
+        escape_xml(program),
+        activation,
+        LOG_PATH,
+        LOG_PATH
+    )
+}
+
+/// Entry point for `neutral-ipc install-launchd`.
+pub fn install(args: &[String], config: &Config) -> io::Result<()> {
+    let mut output = PLIST_PATH.to_string();
+    let mut program = env::current_exe()?.to_string_lossy().into_owned();
+    let mut socket = true;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--output" => output = next_value(&mut args, arg)?,
+            "--program" => program = next_value(&mut args, arg)?,
+            "--no-socket" => socket = false,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unknown option '{}'", arg),
+                ))
+            }
+        }
+    }
+
+    fs::write(&output, plist(&program, config, socket))?;
+    println!("Written {}", output);
+    println!("Load it with: sudo launchctl bootstrap system {}", output);
+
+    Ok(())
+}
+
+fn next_value(args: &mut std::slice::Iter<String>, option: &str) -> io::Result<String> {
+    args.next().cloned().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("missing value for '{}'", option),
+        )
+    })
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(target_os = "macos")]
+mod ffi {
+    use std::os::raw::{c_char, c_int, c_void};
+
+    pub const ENOENT: c_int = 2;
+    pub const ESRCH: c_int = 3;
+
+    extern "C" {
+        pub fn launch_activate_socket(
+            name: *const c_char,
+            fds: *mut *mut c_int,
+            cnt: *mut usize,
+        ) -> c_int;
+        pub fn free(ptr: *mut c_void);
+    }
+}
+
+/// Sockets passed by launchd, empty if not started by launchd with sockets.
+#[cfg(target_os = "macos")]
+pub fn activated_listeners() -> io::Result<Vec<std::net::TcpListener>> {
+    use std::ffi::CString;
+    use std::os::fd::FromRawFd;
+    use std::os::raw::c_int;
+
+    let name = CString::new(SOCKET_NAME).expect("socket name without NUL");
+    let mut fds: *mut c_int = std::ptr::null_mut();
+    let mut count: usize = 0;
+
+    // SAFETY: name is a valid C string, fds and count are valid out pointers.
+    let err = unsafe { ffi::launch_activate_socket(name.as_ptr(), &mut fds, &mut count) };
+    if err == ffi::ESRCH || err == ffi::ENOENT {
+        return Ok(Vec::new());
+    }
+    if err != 0 {
+        return Err(io::Error::from_raw_os_error(err));
+    }
+
+    // SAFETY: on success launchd returns `count` descriptors owned by the
+    // caller in a malloc'ed array that must be freed.
+    let listeners = unsafe {
+        let listeners = std::slice::from_raw_parts(fds, count)
+            .iter()
+            .map(|&fd| std::net::TcpListener::from_raw_fd(fd))
+            .collect();
+        ffi::free(fds.cast());
+        listeners
+    };
+
+    Ok(listeners)
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn activated_listeners() -> io::Result<Vec<std::net::TcpListener>> {
+    Ok(Vec::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plist_socket_activation() {
+        let config = Config::default();
+        let plist = plist("/usr/local/bin/neutral-ipc", &config, true);
+
+        assert!(plist.contains("<string>/usr/local/bin/neutral-ipc</string>"));
+        assert!(plist.contains("<key>Sockets</key>"));
+        assert!(plist.contains("<string>127.0.0.1</string>"));
+        assert!(plist.contains("<string>4273</string>"));
+        assert!(!plist.contains("KeepAlive"));
+    }
+
+    #[test]
+    fn test_plist_without_socket() {
+        let plist = plist("/opt/a&b/neutral-ipc", &Config::default(), false);
+
+        assert!(plist.contains("<string>/opt/a&amp;b/neutral-ipc</string>"));
+        assert!(plist.contains("<key>KeepAlive</key>"));
+        assert!(!plist.contains("Sockets"));
+    }
+}
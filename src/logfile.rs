@@ -0,0 +1,192 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+// ============================================
+// Log file with rotation
+// ============================================
+//
+// Rotated files are named <path>.1, <path>.2, ... (<path>.N.gz if compressed),
+// .1 is the most recent. Compression runs in the rotation itself, it blocks
+// logging for a moment once per rotation.
+
+/// When to rotate and what to keep.
+#[derive(Debug, Clone)]
+pub struct RotationPolicy {
+    /// Rotate when the file would exceed this size, 0 disables it.
+    pub max_size: u64,
+    /// Rotate when the file is older than this.
+    pub max_age: Option<Duration>,
+    /// Number of rotated files kept, 0 keeps none.
+    pub retention: usize,
+    /// Gzip rotated files.
+    pub compress: bool,
+}
+
+pub struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened: SystemTime,
+    policy: RotationPolicy,
+}
+
+impl LogFile {
+    pub fn open(path: impl Into<PathBuf>, policy: RotationPolicy) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(LogFile {
+            path,
+            file,
+            size,
+            opened: SystemTime::now(),
+            policy,
+        })
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let length = line.len() as u64 + 1;
+        if self.needs_rotation(length) {
+            self.rotate()?;
+        }
+
+        writeln!(self.file, "{}", line)?;
+        self.size += length;
+
+        Ok(())
+    }
+
+    /// Reopen the path, e.g. after an external tool (logrotate) moved the file.
+    pub fn reopen(&mut self) -> io::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = file.metadata()?.len();
+        self.file = file;
+        self.opened = SystemTime::now();
+
+        Ok(())
+    }
+
+    fn needs_rotation(&self, incoming: u64) -> bool {
+        let too_big = self.policy.max_size > 0
+            && self.size > 0
+            && self.size + incoming > self.policy.max_size;
+        let too_old = self.policy.max_age.is_some_and(|max_age| {
+            self.opened
+                .elapsed()
+                .is_ok_and(|elapsed| elapsed >= max_age)
+        });
+
+        too_big || too_old
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let retention = self.policy.retention;
+
+        if retention == 0 {
+            fs::remove_file(&self.path)?;
+            return self.reopen();
+        }
+
+        for compressed in [false, true] {
+            remove_if_exists(&rotated_path(&self.path, retention, compressed))?;
+            for n in (1..retention).rev() {
+                let from = rotated_path(&self.path, n, compressed);
+                if from.exists() {
+                    fs::rename(&from, rotated_path(&self.path, n + 1, compressed))?;
+                }
+            }
+        }
+
+        let rotated = rotated_path(&self.path, 1, false);
+        fs::rename(&self.path, &rotated)?;
+        self.reopen()?;
+
+        if self.policy.compress {
+            gzip(&rotated, &rotated_path(&self.path, 1, true))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &Path, n: usize, compressed: bool) -> PathBuf {
+    let suffix = if compressed { ".gz" } else { "" };
+    PathBuf::from(format!("{}.{}{}", path.display(), n, suffix))
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn gzip(source: &Path, target: &Path) -> io::Result<()> {
+    let mut input = File::open(source)?;
+    let mut encoder = GzEncoder::new(File::create(target)?, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+
+    fs::remove_file(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn test_rotate_by_size_with_retention() {
+        let dir = temp_dir("logfile-size");
+        let path = dir.join("test.log");
+        let policy = RotationPolicy {
+            max_size: 10,
+            max_age: None,
+            retention: 2,
+            compress: false,
+        };
+        let mut log = LogFile::open(&path, policy).unwrap();
+
+        for line in ["first", "second", "third", "fourth"] {
+            log.write_line(line).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(fs::read_to_string(rotated_path(&path, 1, false)).unwrap(), "third\n");
+        assert_eq!(fs::read_to_string(rotated_path(&path, 2, false)).unwrap(), "second\n");
+        assert!(!rotated_path(&path, 3, false).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotate_compressed() {
+        let dir = temp_dir("logfile-gzip");
+        let path = dir.join("test.log");
+        let policy = RotationPolicy {
+            max_size: 10,
+            max_age: None,
+            retention: 3,
+            compress: true,
+        };
+        let mut log = LogFile::open(&path, policy).unwrap();
+
+        log.write_line("first line").unwrap();
+        log.write_line("second line").unwrap();
+
+        assert!(rotated_path(&path, 1, true).exists());
+        assert!(!rotated_path(&path, 1, false).exists());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second line\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
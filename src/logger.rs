@@ -0,0 +1,392 @@
+#[cfg(feature = "syslog")]
+use std::fs;
+#[cfg(feature = "syslog")]
+use std::io;
+#[cfg(feature = "syslog")]
+use std::net::{ToSocketAddrs, UdpSocket};
+#[cfg(all(unix, feature = "syslog"))]
+use std::os::unix::net::UnixDatagram;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::logfile::{LogFile, RotationPolicy};
+use crate::Config;
+
+// ============================================
+// Logging backends
+// ============================================
+//
+// stderr:   plain lines, the default, systemd redirects them to a file.
+// file:     timestamped lines to log_file, with size/time rotation, reopened
+//           on SIGUSR1 for logrotate.
+// syslog:   RFC5424 over a unix datagram socket (/dev/log) or udp://host:port,
+//           structured fields are sent as SD-PARAMs.
+// journald: native journal protocol, structured fields as journal fields.
+//
+// syslog and journald are only available with the "syslog" feature.
+
+#[cfg(feature = "syslog")]
+const IDENTIFIER: &str = "neutral-ipc";
+#[cfg(all(unix, feature = "syslog"))]
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+#[cfg(feature = "syslog")]
+const FACILITY_DAEMON: u8 = 3;
+#[cfg(feature = "syslog")]
+const SD_ID: &str = "fields@32473";
+
+/// Syslog severity of a log message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Error = 3,
+    Warning = 4,
+    Info = 6,
+    Debug = 7,
+}
+
+impl Priority {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "error" => Some(Priority::Error),
+            "warning" | "warn" => Some(Priority::Warning),
+            "info" => Some(Priority::Info),
+            "debug" => Some(Priority::Debug),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Error => "error",
+            Priority::Warning => "warning",
+            Priority::Info => "info",
+            Priority::Debug => "debug",
+        }
+    }
+}
+
+enum Backend {
+    Stderr,
+    File(Mutex<LogFile>),
+    #[cfg(feature = "syslog")]
+    SyslogUdp(UdpSocket),
+    #[cfg(all(unix, feature = "syslog"))]
+    SyslogUnix(UnixDatagram),
+    #[cfg(all(unix, feature = "syslog"))]
+    Journald(UnixDatagram),
+}
+
+struct Logger {
+    backend: Backend,
+    level: Priority,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Set up the backend from the config, on failure stderr is used.
+pub fn init(config: &Config) -> Result<(), String> {
+    let level = Priority::from_name(&config.log_level).unwrap_or(Priority::Debug);
+    let (backend, result) = match open_backend(config) {
+        Ok(backend) => (backend, Ok(())),
+        Err(e) => (Backend::Stderr, Err(e)),
+    };
+
+    let _ = LOGGER.set(Logger { backend, level });
+
+    result
+}
+
+fn open_backend(config: &Config) -> Result<Backend, String> {
+    match config.log_backend.as_str() {
+        "stderr" => Ok(Backend::Stderr),
+        "file" => {
+            let policy = RotationPolicy {
+                max_size: config.log_rotate_size,
+                max_age: match config.log_rotate_interval {
+                    0 => None,
+                    secs => Some(Duration::from_secs(secs)),
+                },
+                retention: config.log_retention,
+                compress: config.log_compress,
+            };
+            LogFile::open(&config.log_file, policy)
+                .map(|file| Backend::File(Mutex::new(file)))
+                .map_err(|e| format!("file {}: {}", config.log_file, e))
+        }
+        #[cfg(feature = "syslog")]
+        "syslog" => match config.syslog_address.strip_prefix("udp://") {
+            Some(address) => open_udp(address).map(Backend::SyslogUdp),
+            #[cfg(unix)]
+            None => open_unix(&config.syslog_address).map(Backend::SyslogUnix),
+            #[cfg(not(unix))]
+            None => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix sockets not available",
+            )),
+        }
+        .map_err(|e| format!("syslog {}: {}", config.syslog_address, e)),
+        #[cfg(all(unix, feature = "syslog"))]
+        "journald" => open_unix(JOURNALD_SOCKET)
+            .map(Backend::Journald)
+            .map_err(|e| format!("journald {}: {}", JOURNALD_SOCKET, e)),
+        #[cfg(not(feature = "syslog"))]
+        name @ ("syslog" | "journald") => Err(format!(
+            "log backend '{}' requires the syslog feature",
+            name
+        )),
+        name => Err(format!("unknown log backend '{}'", name)),
+    }
+}
+
+#[cfg(feature = "syslog")]
+fn open_udp(address: &str) -> io::Result<UdpSocket> {
+    let target = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "address not resolved"))?;
+    let socket = UdpSocket::bind(if target.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    })?;
+    socket.connect(target)?;
+    Ok(socket)
+}
+
+#[cfg(all(unix, feature = "syslog"))]
+fn open_unix(path: &str) -> io::Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(path)?;
+    Ok(socket)
+}
+
+/// Log a message with optional structured fields (lowercase names).
+pub fn log(priority: Priority, message: &str, fields: &[(&str, &str)]) {
+    let logger = LOGGER.get_or_init(|| Logger {
+        backend: Backend::Stderr,
+        level: Priority::Debug,
+    });
+
+    if priority > logger.level {
+        return;
+    }
+
+    let sent: Result<(), std::io::Error> = match &logger.backend {
+        Backend::Stderr => Ok(()),
+        Backend::File(file) => {
+            let line = format!(
+                "{} {} {}",
+                timestamp(SystemTime::now()),
+                priority.as_str(),
+                stderr_line(message, fields)
+            );
+            file.lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .write_line(&line)
+        }
+        #[cfg(feature = "syslog")]
+        Backend::SyslogUdp(socket) => socket
+            .send(syslog_payload(priority, hostname(), message, fields).as_bytes())
+            .map(|_| ()),
+        #[cfg(all(unix, feature = "syslog"))]
+        Backend::SyslogUnix(socket) => socket
+            .send(syslog_payload(priority, hostname(), message, fields).as_bytes())
+            .map(|_| ()),
+        #[cfg(all(unix, feature = "syslog"))]
+        Backend::Journald(socket) => socket
+            .send(&journald_payload(priority, message, fields))
+            .map(|_| ()),
+    };
+
+    if matches!(logger.backend, Backend::Stderr) || sent.is_err() {
+        eprintln!("{}", stderr_line(message, fields));
+    }
+}
+
+/// Reopen the log file, e.g. after it was moved by logrotate.
+pub fn reopen() {
+    if let Some(Logger {
+        backend: Backend::File(file),
+        ..
+    }) = LOGGER.get()
+    {
+        if let Err(e) = file.lock().unwrap_or_else(|e| e.into_inner()).reopen() {
+            eprintln!("Impossible to reopen log file: {}", e);
+        }
+    }
+}
+
+pub fn error(message: &str, fields: &[(&str, &str)]) {
+    log(Priority::Error, message, fields);
+}
+
+pub fn warning(message: &str, fields: &[(&str, &str)]) {
+    log(Priority::Warning, message, fields);
+}
+
+pub fn info(message: &str, fields: &[(&str, &str)]) {
+    log(Priority::Info, message, fields);
+}
+
+pub fn debug(message: &str, fields: &[(&str, &str)]) {
+    log(Priority::Debug, message, fields);
+}
+
+fn stderr_line(message: &str, fields: &[(&str, &str)]) -> String {
+    let mut line = message.to_string();
+    for (name, value) in fields {
+        line.push_str(&format!(" {}={}", name, value));
+    }
+    line
+}
+
+#[cfg(feature = "syslog")]
+fn hostname() -> &'static str {
+    static HOSTNAME: OnceLock<String> = OnceLock::new();
+    HOSTNAME.get_or_init(|| {
+        let hostname = fs::read_to_string("/proc/sys/kernel/hostname").unwrap_or_default();
+        match hostname.trim() {
+            "" => "-".to_string(),
+            hostname => hostname.to_string(),
+        }
+    })
+}
+
+#[cfg(feature = "syslog")]
+fn syslog_payload(
+    priority: Priority,
+    hostname: &str,
+    message: &str,
+    fields: &[(&str, &str)],
+) -> String {
+    let pri = FACILITY_DAEMON * 8 + priority as u8;
+    let data = if fields.is_empty() {
+        "-".to_string()
+    } else {
+        let params: Vec<String> = fields
+            .iter()
+            .map(|(name, value)| format!("{}=\"{}\"", name, escape_sd_value(value)))
+            .collect();
+        format!("[{} {}]", SD_ID, params.join(" "))
+    };
+
+    format!(
+        "<{}>1 {} {} {} {} - {} {}",
+        pri,
+        timestamp(SystemTime::now()),
+        hostname,
+        IDENTIFIER,
+        std::process::id(),
+        data,
+        message
+    )
+}
+
+#[cfg(feature = "syslog")]
+fn escape_sd_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(all(unix, feature = "syslog"))]
+fn journald_payload(priority: Priority, message: &str, fields: &[(&str, &str)]) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(message.len() + 128);
+    push_journald_field(&mut buffer, "PRIORITY", &(priority as u8).to_string());
+    push_journald_field(&mut buffer, "SYSLOG_IDENTIFIER", IDENTIFIER);
+    push_journald_field(&mut buffer, "MESSAGE", message);
+    for (name, value) in fields {
+        push_journald_field(&mut buffer, &name.to_ascii_uppercase(), value);
+    }
+    buffer
+}
+
+#[cfg(all(unix, feature = "syslog"))]
+fn push_journald_field(buffer: &mut Vec<u8>, name: &str, value: &str) {
+    buffer.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        // Multi-line values use the binary form: name \n length(u64 LE) value \n
+        buffer.push(b'\n');
+        buffer.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        buffer.push(b'=');
+    }
+    buffer.extend_from_slice(value.as_bytes());
+    buffer.push(b'\n');
+}
+
+/// RFC3339 UTC timestamp with microseconds.
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let rem = secs % 86400;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since_epoch.subsec_micros()
+    )
+}
+
+// Days since 1970-01-01 to (year, month, day), proleptic Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000000Z");
+        let time = UNIX_EPOCH + Duration::new(1_709_210_096, 5_000);
+        assert_eq!(timestamp(time), "2024-02-29T12:34:56.000005Z");
+    }
+
+    #[test]
+    #[cfg(feature = "syslog")]
+    fn test_syslog_payload() {
+        let payload = syslog_payload(Priority::Error, "host", "failed", &[("peer", "a\"b")]);
+        assert!(payload.starts_with("<27>1 "));
+        assert!(payload.contains(" host neutral-ipc "));
+        assert!(payload.ends_with("- [fields@32473 peer=\"a\\\"b\"] failed"));
+    }
+
+    #[test]
+    #[cfg(all(unix, feature = "syslog"))]
+    fn test_journald_payload() {
+        let payload = journald_payload(Priority::Info, "a\nb", &[("peer", "x")]);
+        let mut expected = b"PRIORITY=6\nSYSLOG_IDENTIFIER=neutral-ipc\nMESSAGE\n".to_vec();
+        expected.extend_from_slice(&3u64.to_le_bytes());
+        expected.extend_from_slice(b"a\nb\nPEER=x\n");
+        assert_eq!(payload, expected);
+    }
+
+    #[test]
+    fn test_priority_from_name() {
+        assert_eq!(Priority::from_name("warn"), Some(Priority::Warning));
+        assert_eq!(Priority::from_name("verbose"), None);
+        assert!(Priority::Debug > Priority::Info);
+    }
+}
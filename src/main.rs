@@ -1,11 +1,75 @@
 
-use serde_json::json;
-use std::error::Error;
 use std::result::Result;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::fs;
-use neutralts::Template;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use backend::RenderRequest;
+use error::IpcError;
+use formats::Formats;
+use output::OutputOptions;
+
+mod backend;
+mod breaker;
+mod checksum;
+mod cli;
+mod compression;
+mod concurrency;
+mod corpus;
+mod error;
+mod exchange;
+mod extensions;
+mod fallback;
+mod formats;
+#[cfg(feature = "grpc")]
+mod grpc;
+#[cfg(feature = "http")]
+mod http;
+mod kv;
+#[cfg(feature = "launchd")]
+mod launchd;
+mod logfile;
+mod logger;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod migrate;
+mod mirror;
+mod multipart;
+mod output;
+mod pipeline;
+#[cfg(feature = "quic")]
+mod quic;
+mod sampler;
+mod scopes;
+mod signature;
+mod source;
+mod stdio;
+#[cfg(all(unix, feature = "systemd"))]
+mod systemd;
+mod tasks;
+#[cfg(test)]
+mod testing;
+mod throttle;
+#[cfg(feature = "tls")]
+mod tls;
+mod trace;
+mod transaction;
+mod trim;
+mod validation;
+#[cfg(all(target_os = "linux", feature = "vsock"))]
+mod vsock;
+#[cfg(feature = "websocket")]
+mod websocket;
+mod workers;
 
 // ============================================
 // Neutral IPC record version 0 (draft version)
@@ -13,24 +77,61 @@ use neutralts::Template;
 //
 // HEADER:
 //
-// \x00              # reserved
-// \x00              # control (action/status) (10 = parse template)
-// \x00              # content-format 1 (10 = JSON, 20 = file path, 30 = plaintext, 40 = binary, 50 = MsgPack)
+// \x00              # protocol version (0 = this draft version, 1 = with request ID, + 64 checksums, + 128 extensions)
+// \x00              # control (action/status) (10 = parse template, 20 = stats, 30 = template source, 40 = noop, 50 = hello, 60 = parse template chunked, 70 = cancel, 80 = info, 90 = shutdown, 100 = parse transaction, 110 = kv, + 128 signed)
+// \x00              # content-format 1 (10 = JSON, 20 = file path, 30 = plaintext, 40 = binary, 50 = MsgPack, 60 = CBOR, 70 = multipart, + 1 gzip, + 2 zstd)
 // \x00\x00\x00\x00  # content-length 1 big endian byte order
-// \x00              # content-format 2 (10 = JSON, 20 = file path, 30 = plaintext, 40 = binary, 50 = MsgPack)
+// \x00              # content-format 2 (10 = JSON, 20 = file path, 30 = plaintext, 40 = binary, 50 = MsgPack, 60 = CBOR, 70 = multipart, + 1 gzip, + 2 zstd)
 // \x00\x00\x00\x00  # content-length 2 big endian byte order (can be zero)
 //
 // All text utf8
+//
+// A content length of 0xFFFFFFFF (LENGTH_EXTENDED) means the length is sent
+// as 8 bytes (big endian) right after the header, before the request ID of
+// version 1, content-length 1 first if both are extended. Only blocks of 4 GiB
+// - 1 or more need it, the server sends other lengths in the header but a
+// client may extend any: records are relayed and signed as sent.
+//
+// A connection may start with MAGIC before its first header, version 1
+// connections must. With `magic_required` every connection must.
 
 const HEADER_SIZE: usize = 12;
+/// Content length of the header for a length sent as 8 bytes after it.
+const LENGTH_EXTENDED: u32 = u32::MAX;
+/// Identifies Neutral IPC connections, no header starts with it.
+const MAGIC: &[u8; 4] = b"NIPC";
+/// Protocol versions this server speaks, oldest first.
+const PROTOCOL_VERSIONS: &[u8] = &[0, 1];
+const HELLO_MAX_BYTES: u64 = 4096;
 const CTRL_PARSE_TEMPLATE: u8 = 10;
+#[cfg(feature = "metrics")]
+const CTRL_STATS: u8 = 20;
+const CTRL_TEMPLATE_SOURCE: u8 = 30;
+const CTRL_NOOP: u8 = 40;
+const CTRL_HELLO: u8 = 50;
+const CTRL_PARSE_TEMPLATE_CHUNKED: u8 = 60;
+const CTRL_CANCEL: u8 = 70;
+const CTRL_INFO: u8 = 80;
+const CTRL_SHUTDOWN: u8 = 90;
+const CTRL_PARSE_TRANSACTION: u8 = 100;
+const CTRL_KV: u8 = 110;
 const CTRL_STATUS_OK: u8 = 0;
-const _CTRL_STATUS_KO: u8 = 1;
+const CTRL_STATUS_KO: u8 = 1;
 const CONTENT_JSON: u8 = 10;
 const CONTENT_MSGPACK: u8 = 50;
 const CONTENT_PATH: u8 = 20;
 const CONTENT_TEXT: u8 = 30;
-const _CONTENT_BIN: u8 = 40;
+const CONTENT_BIN: u8 = 40;
+const CONTENT_CBOR: u8 = 60;
+const CONTENT_MULTIPART: u8 = 70;
+
+// Read buffers are allocated as bytes arrive, so a client advertising a
+// huge content length without sending it can't reserve memory.
+const READ_INITIAL_MAX: usize = 1024 * 1024;
+const READ_GROW_STEP: usize = 1024 * 1024;
+
+// Sequence of responses, identifies them in the log
+static RESPONSE_SEQUENCE: AtomicU64 = AtomicU64::new(0);
 
 // IPC config
 const CONFIG_FILE: &str = "/etc/neutral-ipc-cfg.json";
@@ -38,16 +139,234 @@ const CONFIG_FILE: &str = "/etc/neutral-ipc-cfg.json";
 struct Config {
     host: String,
     port: String,
+    dual_stack: bool,
+    reuse_port: bool,
+    acceptors: usize,
+    trace_dump: bool,
+    trace_sample_rate: f64,
+    trace_max_bytes: usize,
+    trace_redact: bool,
+    log_backend: String,
+    #[cfg(feature = "syslog")]
+    syslog_address: String,
+    log_level: String,
+    log_file: String,
+    log_rotate_size: u64,
+    log_rotate_interval: u64,
+    log_retention: usize,
+    log_compress: bool,
+    template_aliases: HashMap<String, String>,
+    mirror_address: Option<String>,
+    mirror_sample_rate: f64,
+    corpus_dir: Option<String>,
+    corpus_sample_rate: f64,
+    corpus_redact: bool,
+    corpus_max_files: u64,
+    strict_header: bool,
+    log_response_hash: bool,
+    allow_insecure_public: bool,
+    render_backend: String,
+    fs_breaker_threshold: u32,
+    fs_breaker_timeout_ms: u64,
+    fs_breaker_cooldown_secs: u64,
+    json_schemas: HashMap<String, String>,
+    json_schema_mode: String,
+    render_workers: usize,
+    render_autotune: bool,
+    render_workers_min: usize,
+    render_workers_max: usize,
+    tcp: bool,
+    socket: Option<String>,
+    output: OutputOptions,
+    socket_output: OutputOptions,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    listeners: Vec<serde_json::Value>,
+    templates_root: Option<String>,
+    template_source_max_bytes: u64,
+    format_fallback: String,
+    pipe: Option<String>,
+    read_rate_limit: u64,
+    write_rate_limit: u64,
+    websocket: Option<String>,
+    http: Option<String>,
+    #[cfg(feature = "http")]
+    http_max_body: u64,
+    #[cfg(feature = "http")]
+    http_headers: Vec<(String, String)>,
+    grpc: Option<String>,
+    quic: Option<String>,
+    max_connections: usize,
+    shutdown_grace_secs: u64,
+    idle_timeout_secs: u64,
+    connection_max_requests: u64,
+    connection_max_age_secs: u64,
+    noop_max_bytes: u64,
+    pipeline_max: usize,
+    decompress_max_bytes: u64,
+    compress_min_bytes: u64,
+    template_max_concurrent: usize,
+    template_limit_mode: String,
+    chunk_size: usize,
+    schema_value_max_bytes: usize,
+    schema_value_limits: HashMap<String, usize>,
+    hmac_keys: HashMap<String, String>,
+    hmac_required: bool,
+    hmac_max_age_secs: u64,
+    magic_required: bool,
+    admin_keys: Vec<String>,
+    template_scopes: Vec<(PathBuf, Vec<String>)>,
+    key_scopes: HashMap<String, Vec<String>>,
+    transaction_max_templates: usize,
+    kv_max_bytes: usize,
 }
 
 impl Config {
+    /// Connection options of the TCP based listeners without a `listeners` entry.
+    fn listener_options(&self) -> ListenerOptions {
+        ListenerOptions { output: self.output, formats: Formats::default() }
+    }
+
+    /// Connection options of the Unix domain socket and the named pipe.
+    fn socket_options(&self) -> ListenerOptions {
+        ListenerOptions { output: self.socket_output, formats: Formats::default() }
+    }
+
     pub fn new() -> Self {
         match fs::read_to_string(CONFIG_FILE) {
             Ok(config_content) => {
                 match serde_json::from_str::<serde_json::Value>(&config_content) {
                     Ok(config) => Config {
                         host: config["host"].as_str().unwrap_or("127.0.0.1").to_string(),
-                        port: config["port"].as_str().unwrap_or("4273").to_string(),
+                        port: match &config["port"] {
+                            serde_json::Value::Number(port) => port.to_string(),
+                            port => port.as_str().unwrap_or("4273").to_string(),
+                        },
+                        dual_stack: config["dual_stack"].as_bool().unwrap_or(true),
+                        reuse_port: config["reuse_port"].as_bool().unwrap_or(false),
+                        acceptors: config["acceptors"].as_u64().unwrap_or(1).max(1) as usize,
+                        trace_dump: config["trace_dump"].as_bool().unwrap_or(false),
+                        trace_sample_rate: config["trace_sample_rate"].as_f64().unwrap_or(1.0),
+                        trace_max_bytes: config["trace_max_bytes"].as_u64().unwrap_or(64) as usize,
+                        trace_redact: config["trace_redact"].as_bool().unwrap_or(true),
+                        log_backend: config["log_backend"].as_str().unwrap_or("stderr").to_string(),
+                        #[cfg(feature = "syslog")]
+                        syslog_address: config["syslog_address"].as_str().unwrap_or("/dev/log").to_string(),
+                        log_level: config["log_level"].as_str().unwrap_or("debug").to_string(),
+                        log_file: config["log_file"].as_str().unwrap_or("/var/log/neutral-ipc/neutral-ipc.log").to_string(),
+                        log_rotate_size: config["log_rotate_size"].as_u64().unwrap_or(0),
+                        log_rotate_interval: config["log_rotate_interval"].as_u64().unwrap_or(0),
+                        log_retention: config["log_retention"].as_u64().unwrap_or(7) as usize,
+                        log_compress: config["log_compress"].as_bool().unwrap_or(false),
+                        template_aliases: config["template_aliases"]
+                            .as_object()
+                            .map(|aliases| {
+                                aliases
+                                    .iter()
+                                    .filter_map(|(name, path)| path.as_str().map(|path| (name.clone(), path.to_string())))
+                                    .collect()
+                            })
+                            .unwrap_or_default(),
+                        mirror_address: config["mirror_address"].as_str().map(String::from),
+                        mirror_sample_rate: config["mirror_sample_rate"].as_f64().unwrap_or(0.01),
+                        corpus_dir: config["corpus_dir"].as_str().map(String::from),
+                        corpus_sample_rate: config["corpus_sample_rate"].as_f64().unwrap_or(0.01),
+                        corpus_redact: config["corpus_redact"].as_bool().unwrap_or(true),
+                        corpus_max_files: config["corpus_max_files"].as_u64().unwrap_or(1000),
+                        strict_header: config["strict_header"].as_bool().unwrap_or(false),
+                        log_response_hash: config["log_response_hash"].as_bool().unwrap_or(false),
+                        allow_insecure_public: config["allow_insecure_public"].as_bool().unwrap_or(false),
+                        render_backend: config["render_backend"].as_str().unwrap_or("neutralts").to_string(),
+                        fs_breaker_threshold: config["fs_breaker_threshold"].as_u64().unwrap_or(5) as u32,
+                        fs_breaker_timeout_ms: config["fs_breaker_timeout_ms"].as_u64().unwrap_or(1000),
+                        fs_breaker_cooldown_secs: config["fs_breaker_cooldown_secs"].as_u64().unwrap_or(10),
+                        json_schemas: config["json_schemas"]
+                            .as_object()
+                            .map(|schemas| {
+                                schemas
+                                    .iter()
+                                    .filter_map(|(name, path)| path.as_str().map(|path| (name.clone(), path.to_string())))
+                                    .collect()
+                            })
+                            .unwrap_or_default(),
+                        json_schema_mode: config["json_schema_mode"].as_str().unwrap_or("warn").to_string(),
+                        render_workers: config["render_workers"].as_u64().unwrap_or(0) as usize,
+                        render_autotune: config["render_autotune"].as_bool().unwrap_or(false),
+                        render_workers_min: config["render_workers_min"].as_u64().unwrap_or(1) as usize,
+                        render_workers_max: config["render_workers_max"].as_u64().unwrap_or(0) as usize,
+                        tcp: config["tcp"].as_bool().unwrap_or(true),
+                        socket: config["socket"].as_str().map(String::from),
+                        output: OutputOptions::from_value(&config["output"]),
+                        socket_output: match &config["socket_output"] {
+                            serde_json::Value::Null => OutputOptions::from_value(&config["output"]),
+                            socket_output => OutputOptions::from_value(socket_output),
+                        },
+                        tls_cert: config["tls_cert"].as_str().map(String::from),
+                        tls_key: config["tls_key"].as_str().map(String::from),
+                        listeners: config["listeners"].as_array().cloned().unwrap_or_default(),
+                        templates_root: config["templates_root"].as_str().map(String::from),
+                        template_source_max_bytes: config["template_source_max_bytes"].as_u64().unwrap_or(1024 * 1024),
+                        format_fallback: config["format_fallback"].as_str().unwrap_or("off").to_string(),
+                        pipe: config["pipe"].as_str().map(String::from),
+                        read_rate_limit: config["read_rate_limit"].as_u64().unwrap_or(0),
+                        write_rate_limit: config["write_rate_limit"].as_u64().unwrap_or(0),
+                        websocket: config["websocket"].as_str().map(String::from),
+                        http: config["http"].as_str().map(String::from),
+                        #[cfg(feature = "http")]
+                        http_max_body: config["http_max_body"].as_u64().unwrap_or(16 * 1024 * 1024),
+                        #[cfg(feature = "http")]
+                        http_headers: config["http_headers"]
+                            .as_object()
+                            .map(|headers| {
+                                headers
+                                    .iter()
+                                    .filter_map(|(name, value)| value.as_str().map(|value| (name.clone(), value.to_string())))
+                                    .collect()
+                            })
+                            .unwrap_or_default(),
+                        grpc: config["grpc"].as_str().map(String::from),
+                        quic: config["quic"].as_str().map(String::from),
+                        max_connections: config["max_connections"].as_u64().unwrap_or(0) as usize,
+                        shutdown_grace_secs: config["shutdown_grace_secs"].as_u64().unwrap_or(10),
+                        idle_timeout_secs: config["idle_timeout_secs"].as_u64().unwrap_or(60),
+                        connection_max_requests: config["connection_max_requests"].as_u64().unwrap_or(0),
+                        connection_max_age_secs: config["connection_max_age_secs"].as_u64().unwrap_or(0),
+                        noop_max_bytes: config["noop_max_bytes"].as_u64().unwrap_or(1024),
+                        pipeline_max: config["pipeline_max"].as_u64().unwrap_or(16) as usize,
+                        decompress_max_bytes: config["decompress_max_bytes"].as_u64().unwrap_or(64 * 1024 * 1024),
+                        compress_min_bytes: config["compress_min_bytes"].as_u64().unwrap_or(1024),
+                        template_max_concurrent: config["template_max_concurrent"].as_u64().unwrap_or(0) as usize,
+                        template_limit_mode: config["template_limit_mode"].as_str().unwrap_or("wait").to_string(),
+                        chunk_size: config["chunk_size"].as_u64().unwrap_or(64 * 1024) as usize,
+                        schema_value_max_bytes: config["schema_value_max_bytes"].as_u64().unwrap_or(0) as usize,
+                        schema_value_limits: config["schema_value_limits"]
+                            .as_object()
+                            .map(|limits| {
+                                limits
+                                    .iter()
+                                    .filter_map(|(path, max)| max.as_u64().map(|max| (path.clone(), max as usize)))
+                                    .collect()
+                            })
+                            .unwrap_or_default(),
+                        hmac_keys: config["hmac_keys"]
+                            .as_object()
+                            .map(|keys| {
+                                keys.iter()
+                                    .filter_map(|(id, key)| key.as_str().map(|key| (id.clone(), key.to_string())))
+                                    .collect()
+                            })
+                            .unwrap_or_default(),
+                        hmac_required: config["hmac_required"].as_bool().unwrap_or(false),
+                        hmac_max_age_secs: config["hmac_max_age_secs"].as_u64().unwrap_or(30),
+                        magic_required: config["magic_required"].as_bool().unwrap_or(false),
+                        admin_keys: config["admin_keys"]
+                            .as_array()
+                            .map(|keys| keys.iter().filter_map(|key| key.as_str().map(String::from)).collect())
+                            .unwrap_or_default(),
+                        template_scopes: scopes::parse_templates(&config["template_scopes"], config["templates_root"].as_str()),
+                        key_scopes: scopes::parse(&config["key_scopes"]),
+                        transaction_max_templates: config["transaction_max_templates"].as_u64().unwrap_or(100) as usize,
+                        kv_max_bytes: config["kv_max_bytes"].as_u64().unwrap_or(0) as usize,
                     },
                     Err(_) => {
                         eprintln!("Config is not a valid JSON, default is used.");
@@ -66,22 +385,114 @@ impl Config {
         Config {
             host: "127.0.0.1".to_string(),
             port: "4273".to_string(),
+            dual_stack: true,
+            reuse_port: false,
+            acceptors: 1,
+            trace_dump: false,
+            trace_sample_rate: 1.0,
+            trace_max_bytes: 64,
+            trace_redact: true,
+            log_backend: "stderr".to_string(),
+            #[cfg(feature = "syslog")]
+            syslog_address: "/dev/log".to_string(),
+            log_level: "debug".to_string(),
+            log_file: "/var/log/neutral-ipc/neutral-ipc.log".to_string(),
+            log_rotate_size: 0,
+            log_rotate_interval: 0,
+            log_retention: 7,
+            log_compress: false,
+            template_aliases: HashMap::new(),
+            mirror_address: None,
+            mirror_sample_rate: 0.01,
+            corpus_dir: None,
+            corpus_sample_rate: 0.01,
+            corpus_redact: true,
+            corpus_max_files: 1000,
+            strict_header: false,
+            log_response_hash: false,
+            allow_insecure_public: false,
+            render_backend: "neutralts".to_string(),
+            fs_breaker_threshold: 5,
+            fs_breaker_timeout_ms: 1000,
+            fs_breaker_cooldown_secs: 10,
+            json_schemas: HashMap::new(),
+            json_schema_mode: "warn".to_string(),
+            render_workers: 0,
+            render_autotune: false,
+            render_workers_min: 1,
+            render_workers_max: 0,
+            tcp: true,
+            socket: None,
+            output: OutputOptions::default(),
+            socket_output: OutputOptions::default(),
+            tls_cert: None,
+            tls_key: None,
+            listeners: Vec::new(),
+            templates_root: None,
+            template_source_max_bytes: 1024 * 1024,
+            format_fallback: "off".to_string(),
+            pipe: None,
+            read_rate_limit: 0,
+            write_rate_limit: 0,
+            websocket: None,
+            http: None,
+            #[cfg(feature = "http")]
+            http_max_body: 16 * 1024 * 1024,
+            #[cfg(feature = "http")]
+            http_headers: Vec::new(),
+            grpc: None,
+            quic: None,
+            max_connections: 0,
+            shutdown_grace_secs: 10,
+            idle_timeout_secs: 60,
+            connection_max_requests: 0,
+            connection_max_age_secs: 0,
+            noop_max_bytes: 1024,
+            pipeline_max: 16,
+            decompress_max_bytes: 64 * 1024 * 1024,
+            compress_min_bytes: 1024,
+            template_max_concurrent: 0,
+            template_limit_mode: "wait".to_string(),
+            chunk_size: 64 * 1024,
+            schema_value_max_bytes: 0,
+            schema_value_limits: HashMap::new(),
+            hmac_keys: HashMap::new(),
+            hmac_required: false,
+            hmac_max_age_secs: 30,
+            magic_required: false,
+            admin_keys: Vec::new(),
+            template_scopes: Vec::new(),
+            key_scopes: HashMap::new(),
+            transaction_max_templates: 100,
+            kv_max_bytes: 0,
         }
     }
 }
 
 /// Header structure representing the protocol header.
 ///
-/// The header contains information about the request or response, including reserved fields,
-/// control/status indicators, content formats, and content lengths.
+/// The header contains information about the request or response, including the protocol
+/// version, control/status indicators, content formats, and content lengths.
 #[derive(Debug)]
 pub struct Header {
-    /// Reserved field that must be set to 0x00. This field is reserved for future use.
-    pub reserved: u8,
+    /// Protocol version, `0` for this draft version. Requests with a version the
+    /// server doesn't speak are rejected, except hello.
+    pub version: u8,
 
     /// Control field indicating the action for requests or status for responses.
     /// - For requests:
     ///   - `10`: Parse template
+    ///   - `20`: Stats, returns the metrics as JSON (contents are ignored)
+    ///   - `30`: Template source, returns the raw source of the template path in content 2
+    ///   - `40`: Noop, echoes the content 2 payload
+    ///   - `50`: Hello, negotiates the protocol version
+    ///   - `60`: Parse template, with the output in chunks after content 1
+    ///   - `70`: Cancel the request with the same ID (pipelined connections)
+    ///   - `80`: Info, returns the server capabilities as JSON (contents are ignored)
+    ///   - `90`: Shutdown, signed with an admin key
+    ///   - `100`: Parse transaction, renders a manifest of templates, all or nothing
+    ///   - `110`: Kv, sets, gets or deletes a value of the key-value store
+    ///   - plus `128` for a signed request, with a trailer after content 2
     ///   - Other values can be defined as needed.
     /// - For responses:
     ///   - `0`: Success
@@ -94,17 +505,21 @@ pub struct Header {
     /// - `20`: File path
     /// - `30`: Plaintext
     /// - `40`: Binary
+    ///
+    /// plus `1` if the block is gzip compressed or `2` if zstd compressed.
     pub content_format_1: u8,
 
     /// Length of the first content block in bytes, represented in big-endian byte order.
-    pub content_length_1: u32,
+    /// A length that doesn't fit in 4 bytes is sent as `0xFFFFFFFF` with the
+    /// length as 8 bytes after the header (see `LENGTH_EXTENDED`).
+    pub content_length_1: u64,
 
     /// Content format for the second content block. Possible values are the same as for `content_format_1`.
     pub content_format_2: u8,
 
     /// Length of the second content block in bytes, represented in big-endian byte order.
     /// This field can be zero if there is no second content block.
-    pub content_length_2: u32,
+    pub content_length_2: u64,
 }
 
 impl Header {
@@ -113,205 +528,2549 @@ impl Header {
             return None;
         }
         Some(Header {
-            reserved: bytes[0],
+            version: bytes[0],
             control: bytes[1],
             content_format_1: bytes[2],
-            content_length_1: u32::from_be_bytes([bytes[3], bytes[4], bytes[5], bytes[6]]),
+            content_length_1: u32::from_be_bytes([bytes[3], bytes[4], bytes[5], bytes[6]]) as u64,
             content_format_2: bytes[7],
-            content_length_2: u32::from_be_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+            content_length_2: u32::from_be_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as u64,
         })
     }
 
+    /// Read the 8 byte lengths that follow the header for the lengths sent as
+    /// `LENGTH_EXTENDED`. Returns which lengths were, whatever their size.
+    async fn read_extended_lengths<R: AsyncRead + Unpin>(&mut self, reader: &mut R) -> std::io::Result<[bool; 2]> {
+        let mut extended = [false; 2];
+        for (length, extended) in [&mut self.content_length_1, &mut self.content_length_2].into_iter().zip(&mut extended) {
+            if *length == LENGTH_EXTENDED as u64 {
+                *length = reader.read_u64().await?;
+                *extended = true;
+            }
+        }
+
+        Ok(extended)
+    }
+
+    /// Reject versions the server doesn't speak, hello is accepted with any
+    /// version so a client can find out which ones it does.
+    fn check_version(&self) -> Result<(), IpcError> {
+        if self.control != CTRL_HELLO && !PROTOCOL_VERSIONS.contains(&self.version) {
+            return Err(IpcError::UnsupportedVersion(self.version));
+        }
+
+        Ok(())
+    }
+
+    /// Strict mode: reject what the protocol leaves undefined, so clients
+    /// don't come to rely on it.
+    fn validate_strict(&self) -> Result<(), IpcError> {
+        #[cfg(feature = "metrics")]
+        if self.control == CTRL_STATS
+            && (self.content_format_1 != 0 || self.content_length_1 != 0 || self.content_format_2 != 0 || self.content_length_2 != 0)
+        {
+            return Err(IpcError::StrictHeader("stats takes no contents, formats and lengths must be 0".to_string()));
+        }
+
+        if (self.control == CTRL_INFO || self.control == CTRL_SHUTDOWN)
+            && (self.content_format_1 != 0 || self.content_length_1 != 0 || self.content_format_2 != 0 || self.content_length_2 != 0)
+        {
+            return Err(IpcError::StrictHeader("info and shutdown take no contents, formats and lengths must be 0".to_string()));
+        }
+
+        if self.control == CTRL_NOOP {
+            if self.content_format_1 != 0 || self.content_length_1 != 0 {
+                return Err(IpcError::StrictHeader("noop takes no content-1, format and length must be 0".to_string()));
+            }
+            if self.content_length_2 != 0 && self.content_format_2 != CONTENT_TEXT && self.content_format_2 != CONTENT_BIN {
+                return Err(IpcError::StrictHeader("noop payload must be content_format_2 30 (plaintext) or 40 (binary)".to_string()));
+            }
+        }
+
+        Ok(())
+    }
+
     fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        self.to_bytes_as([false; 2])
+    }
+
+    /// The header with the lengths `extended` (as returned by
+    /// `read_extended_lengths`) sent after it even if they fit in 4 bytes.
+    fn to_bytes_as(&self, extended: [bool; 2]) -> [u8; HEADER_SIZE] {
         let mut buffer = [0; HEADER_SIZE];
-        buffer[0] = self.reserved;
+        buffer[0] = self.version;
         buffer[1] = self.control;
         buffer[2] = self.content_format_1;
-        buffer[3..7].copy_from_slice(&self.content_length_1.to_be_bytes());
+        buffer[3..7].copy_from_slice(&wire_length(self.content_length_1, extended[0]).to_be_bytes());
         buffer[7] = self.content_format_2;
-        buffer[8..12].copy_from_slice(&self.content_length_2.to_be_bytes());
+        buffer[8..12].copy_from_slice(&wire_length(self.content_length_2, extended[1]).to_be_bytes());
         buffer
     }
+
+    /// The 8 byte lengths sent after the header, empty if both fit in 4 bytes.
+    fn extended_lengths(&self) -> Vec<u8> {
+        self.extended_lengths_as([false; 2])
+    }
+
+    /// The 8 byte lengths sent after the header of `to_bytes_as`.
+    fn extended_lengths_as(&self, extended: [bool; 2]) -> Vec<u8> {
+        [self.content_length_1, self.content_length_2]
+            .into_iter()
+            .zip(extended)
+            .filter(|&(length, extended)| wire_length(length, extended) == LENGTH_EXTENDED)
+            .flat_map(|(length, _)| length.to_be_bytes())
+            .collect()
+    }
+}
+
+/// The 4 byte length of the header, `LENGTH_EXTENDED` if it doesn't fit or
+/// is `extended`.
+fn wire_length(length: u64, extended: bool) -> u32 {
+    u32::try_from(length).ok().filter(|&length| length != LENGTH_EXTENDED && !extended).unwrap_or(LENGTH_EXTENDED)
 }
 
 struct ParseTemplateResult {
     json: String,
     text: String,
     status: u8,
+    /// Binary content 2, sent as CONTENT_BIN instead of the text.
+    binary: Option<Vec<u8>>,
+}
+
+impl ParseTemplateResult {
+    /// Format and bytes of content 2 of the response.
+    fn content_2(&self) -> (u8, &[u8]) {
+        match &self.binary {
+            Some(binary) => (CONTENT_BIN, binary),
+            None => (CONTENT_TEXT, self.text.as_bytes()),
+        }
+    }
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let config = Config::new();
-    let bindto = format!("{}:{}", config.host.as_str(), config.port);
-    let listener = TcpListener::bind(bindto).await?;
-    println!("Neutral IPC on {}:{}",config.host, config.port);
+async fn main() -> Result<(), IpcError> {
+    let config = Arc::new(Config::new());
+    let args: Vec<String> = std::env::args().collect();
 
-    loop {
-        match listener.accept().await {
-            Ok((stream, _)) => {
-                tokio::spawn(async move {
-                    if let Err(e) = handle_client(stream).await {
-                        eprintln!("Failed to handle client: {}", e);
-                    }
-                });
+    match args.get(1).map(String::as_str) {
+        Some("migrate-config") => return migrate::run(&args[2..]).map_err(IpcError::from),
+        Some("render") => return cli::render(&args[2..], &config).await,
+        Some("dev") => return cli::dev(&args[2..], &config).await,
+        Some("bench") => return corpus::bench(&args[2..], &config).await,
+        #[cfg(feature = "launchd")]
+        Some("install-launchd") => return launchd::install(&args[2..], &config).map_err(IpcError::from),
+        _ => {}
+    }
+
+    if let Err(e) = logger::init(&config) {
+        eprintln!("Impossible to open log backend, stderr is used: {}", e);
+    }
+
+    if let Err(e) = render_backend(&config).and_then(|_| validation::init(&config)).and_then(|_| fallback::check(&config)) {
+        logger::error(&e.to_string(), &[]);
+        return Err(e);
+    }
+
+    #[cfg(feature = "http")]
+    if let Err(e) = http::check(&config) {
+        logger::error(&e.to_string(), &[]);
+        return Err(e);
+    }
+
+    #[cfg(feature = "tls")]
+    if let Err(e) = tls::init(&config) {
+        logger::error(&e.to_string(), &[]);
+        return Err(e);
+    }
+    #[cfg(not(feature = "tls"))]
+    if config.tls_cert.is_some() || config.tls_key.is_some() {
+        let message = "tls_cert and tls_key are set but TLS support is not compiled in (feature tls)".to_string();
+        logger::error(&message, &[]);
+        return Err(IpcError::Config(message));
+    }
+
+    if let Err(e) = check_unsigned_gateways(&config) {
+        logger::error(&e.to_string(), &[]);
+        return Err(e);
+    }
+
+    workers::init(&config);
+    tasks::init(&config);
+
+    if args.iter().skip(1).any(|arg| arg == "--stdio") {
+        return stdio::serve(&config).await.map_err(IpcError::from);
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::user_defined1()) {
+            Ok(mut usr1) => {
+                while usr1.recv().await.is_some() {
+                    logger::reopen();
+                }
             }
-            Err(e) => eprintln!("Failed to accept connection: {}", e),
+            Err(e) => logger::warning(&format!("SIGUSR1 handler not installed: {}", e), &[]),
         }
+    });
+
+    let activated = activated_listeners()?;
+    let mut servers = tokio::task::JoinSet::new();
+
+    #[cfg(windows)]
+    if let Some(name) = &config.pipe {
+        let server = tokio::net::windows::named_pipe::ServerOptions::new().first_pipe_instance(true).create(name)?;
+        logger::info(&format!("Neutral IPC on {}", name), &[]);
+        servers.spawn(serve_pipe(server, name.clone(), Arc::clone(&config)));
+    }
+    #[cfg(not(windows))]
+    if config.pipe.is_some() {
+        logger::warning("Named pipes are only supported on Windows, pipe is ignored", &[]);
     }
-}
 
-async fn handle_client(mut stream: TcpStream) -> Result<(), Box<dyn Error>> {
-    let mut header_bytes = [0; HEADER_SIZE];
-    stream.read_exact(&mut header_bytes).await?;
+    #[cfg(feature = "websocket")]
+    if let Some(address) = &config.websocket {
+        let addresses: Vec<SocketAddr> = tokio::net::lookup_host(address).await?.collect();
+        check_public_bind(&addresses, &config, false)?;
+        let listener = bind_tcp(&addresses, config.dual_stack, false)?;
+        logger::info(&format!("Neutral IPC WebSocket on {}", address), &[]);
+        servers.spawn(websocket::serve(listener, Arc::clone(&config)));
+    }
+    #[cfg(not(feature = "websocket"))]
+    if config.websocket.is_some() {
+        logger::warning("WebSocket support is not compiled in (feature websocket), websocket is ignored", &[]);
+    }
+
+    #[cfg(feature = "http")]
+    if let Some(address) = &config.http {
+        let addresses: Vec<SocketAddr> = tokio::net::lookup_host(address).await?.collect();
+        check_public_bind(&addresses, &config, false)?;
+        let listener = bind_tcp(&addresses, config.dual_stack, false)?;
+        logger::info(&format!("Neutral IPC HTTP on {}", address), &[]);
+        servers.spawn(http::serve(listener, Arc::clone(&config)));
+    }
+    #[cfg(not(feature = "http"))]
+    if config.http.is_some() {
+        logger::warning("HTTP support is not compiled in (feature http), http is ignored", &[]);
+    }
+
+    #[cfg(feature = "grpc")]
+    if let Some(address) = &config.grpc {
+        let addresses: Vec<SocketAddr> = tokio::net::lookup_host(address).await?.collect();
+        check_public_bind(&addresses, &config, false)?;
+        let listener = bind_tcp(&addresses, config.dual_stack, false)?;
+        logger::info(&format!("Neutral IPC gRPC on {}", address), &[]);
+        servers.spawn(grpc::serve(listener, Arc::clone(&config)));
+    }
+    #[cfg(not(feature = "grpc"))]
+    if config.grpc.is_some() {
+        logger::warning("gRPC support is not compiled in (feature grpc), grpc is ignored", &[]);
+    }
+
+    #[cfg(feature = "quic")]
+    if let Some(address) = &config.quic {
+        let Some(address) = tokio::net::lookup_host(address).await?.next() else {
+            let message = format!("quic address {} does not resolve", address);
+            logger::error(&message, &[]);
+            return Err(IpcError::Config(message));
+        };
+        let endpoint = match quic::bind(address, &config) {
+            Ok(endpoint) => endpoint,
+            Err(e) => {
+                logger::error(&e.to_string(), &[]);
+                return Err(e);
+            }
+        };
+        logger::info(&format!("Neutral IPC QUIC on {}", address), &[]);
+        servers.spawn(quic::serve(endpoint, Arc::clone(&config)));
+    }
+    #[cfg(not(feature = "quic"))]
+    if config.quic.is_some() {
+        logger::warning("QUIC support is not compiled in (feature quic), quic is ignored", &[]);
+    }
 
-    if let Some(header) = Header::from_bytes(&header_bytes) {
-        match header.control {
-            CTRL_PARSE_TEMPLATE => {
-                if header.content_format_1 != CONTENT_JSON && header.content_format_1 != CONTENT_MSGPACK {
-                    return Err("Invalid content_format_1. Expected JSON or MSGPACK.".into());
+    if !activated.is_empty() {
+        // The service manager owns the sockets, nothing is bound here.
+        #[cfg_attr(not(any(feature = "launchd", all(unix, feature = "systemd"))), allow(clippy::never_loop))]
+        for listener in activated {
+            match listener {
+                #[cfg(any(feature = "launchd", all(unix, feature = "systemd")))]
+                Activated::Tcp(std_listener) => {
+                    std_listener.set_nonblocking(true)?;
+                    let listener = TcpListener::from_std(std_listener)?;
+                    check_public_bind(&[listener.local_addr()?], &config, config.tls_cert.is_some())?;
+                    logger::info(&format!("Neutral IPC on {} (socket activation)", listener.local_addr()?), &[]);
+                    servers.spawn(serve(listener, Arc::clone(&config), config.tls_cert.is_some(), config.listener_options()));
+                }
+                #[cfg(all(unix, feature = "systemd"))]
+                Activated::Unix(std_listener) => {
+                    std_listener.set_nonblocking(true)?;
+                    let listener = UnixListener::from_std(std_listener)?;
+                    logger::info("Neutral IPC on Unix domain socket (socket activation)", &[]);
+                    servers.spawn(serve_unix(listener, Arc::clone(&config), config.socket_options()));
                 }
+            }
+        }
+    } else if !config.listeners.is_empty() {
+        // The listeners replace host, port, tcp and socket.
+        let listeners = match config.listeners.iter().map(|listener| Listener::from_value(listener, &config)).collect::<Result<Vec<_>, _>>() {
+            Ok(listeners) => listeners,
+            Err(e) => {
+                logger::error(&e.to_string(), &[]);
+                return Err(e);
+            }
+        };
 
-                if header.content_format_2 != CONTENT_TEXT && header.content_format_2 != CONTENT_PATH {
-                    return Err("Invalid content_format_2. Expected TEXT or PATH.".into());
+        for (listener, options) in listeners {
+            match listener {
+                Listener::Tcp { host, port, tls } => {
+                    #[cfg(feature = "tls")]
+                    let tls_ready = tls::acceptor().is_some();
+                    #[cfg(not(feature = "tls"))]
+                    let tls_ready = false;
+                    if tls && !tls_ready {
+                        let message = "tls:// listener needs tls_cert and tls_key (and the tls feature)".to_string();
+                        logger::error(&message, &[]);
+                        return Err(IpcError::Config(message));
+                    }
+
+                    let addresses = resolve(&host, &port).await?;
+                    check_public_bind(&addresses, &config, tls)?;
+                    let scheme = if tls { "tls" } else { "tcp" };
+                    for listener in bind_acceptors(&addresses, &config)? {
+                        logger::info(&format!("Neutral IPC on {}://{}", scheme, listener.local_addr()?), &[]);
+                        servers.spawn(serve(listener, Arc::clone(&config), tls, options));
+                    }
+                }
+                #[cfg(unix)]
+                Listener::Unix(path) => {
+                    let listener = bind_unix(&path)?;
+                    logger::info(&format!("Neutral IPC on unix://{}", path), &[]);
+                    servers.spawn(serve_unix(listener, Arc::clone(&config), options));
                 }
+                #[cfg(all(target_os = "linux", feature = "vsock"))]
+                Listener::Vsock { cid, port } => {
+                    let listener = vsock::bind(cid, port)?;
+                    logger::info(&format!("Neutral IPC on vsock://{}:{}", cid, port), &[]);
+                    servers.spawn(vsock::serve(listener, Arc::clone(&config), options));
+                }
+            }
+        }
+    } else {
+        #[cfg(unix)]
+        if let Some(path) = &config.socket {
+            let listener = bind_unix(path)?;
+            logger::info(&format!("Neutral IPC on {}", path), &[]);
+            servers.spawn(serve_unix(listener, Arc::clone(&config), config.socket_options()));
+        }
+        #[cfg(not(unix))]
+        if config.socket.is_some() {
+            logger::warning("Unix domain sockets are not supported on this platform, socket is ignored", &[]);
+        }
+
+        if config.tcp {
+            let addresses = resolve(&config.host, &config.port).await?;
+            check_public_bind(&addresses, &config, config.tls_cert.is_some())?;
+            for listener in bind_acceptors(&addresses, &config)? {
+                logger::info(&format!("Neutral IPC on {}", listener.local_addr()?), &[]);
+                servers.spawn(serve(listener, Arc::clone(&config), config.tls_cert.is_some(), config.listener_options()));
+            }
+        }
+    }
+
+    if servers.is_empty() {
+        let message = "No listener, tcp is disabled and no socket, listeners or pipe is set".to_string();
+        logger::error(&message, &[]);
+        return Err(IpcError::Config(message));
+    }
 
-                let mut content_1_buffer = vec![0; header.content_length_1 as usize];
-                stream.read_exact(&mut content_1_buffer).await?;
+    // Servers run until a termination signal or a shutdown request.
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            joined = servers.join_next() => match joined {
+                Some(Err(e)) if e.is_panic() => logger::error(&format!("Listener task panicked: {}", e), &[]),
+                Some(_) => {}
+                None => return Ok(()),
+            },
+            _ = &mut shutdown => break,
+            _ = tasks::shutdown_requested() => break,
+        }
+    }
 
-                let mut content_2_buffer = vec![0; header.content_length_2 as usize];
-                stream.read_exact(&mut content_2_buffer).await?;
+    // Stop accepting, then let the connections finish.
+    servers.abort_all();
+    logger::info(&format!("Shutting down, {} connections running", tasks::active()), &[]);
+    let remaining = tasks::shutdown(Duration::from_secs(config.shutdown_grace_secs)).await;
+    if remaining > 0 {
+        logger::warning(&format!("{} connections still running after shutdown_grace_secs, closed", remaining), &[]);
+    }
 
-                let text_content = String::from_utf8(content_2_buffer)
-                    .map_err(|e| format!("Failed to parse text content: {}", e))?;
+    Ok(())
+}
 
-                let result = parse_template(&content_1_buffer, &text_content, header.content_format_1, header.content_format_2);
-                let response_header = Header {
-                    reserved: 0,
-                    control: result.status,
-                    content_format_1: CONTENT_JSON,
-                    content_length_1: result.json.len() as u32,
-                    content_format_2: CONTENT_TEXT,
-                    content_length_2: result.text.len() as u32,
-                };
+/// SIGTERM or Ctrl-C.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = terminate.recv() => return,
+                _ = tokio::signal::ctrl_c() => return,
+            }
+        }
+    }
+
+    if tokio::signal::ctrl_c().await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// A listening socket passed by the service manager (socket activation).
+enum Activated {
+    #[cfg(any(feature = "launchd", all(unix, feature = "systemd")))]
+    Tcp(std::net::TcpListener),
+    #[cfg(all(unix, feature = "systemd"))]
+    Unix(std::os::unix::net::UnixListener),
+}
+
+/// An entry of the `listeners` config: `tcp://HOST:PORT`, `tls://HOST:PORT`,
+/// `unix:///PATH` or `vsock://[CID:]PORT`.
+#[derive(Debug, PartialEq)]
+enum Listener {
+    Tcp { host: String, port: String, tls: bool },
+    #[cfg(unix)]
+    Unix(String),
+    #[cfg(all(target_os = "linux", feature = "vsock"))]
+    Vsock { cid: u32, port: u32 },
+}
+
+/// Options of the connections accepted on a listener.
+#[derive(Debug, Clone, Copy, Default)]
+struct ListenerOptions {
+    output: OutputOptions,
+    formats: Formats,
+}
+
+impl Listener {
+    /// A `listeners` entry, the address or an object with the address and
+    /// the accepted formats.
+    fn from_value(value: &serde_json::Value, config: &Config) -> Result<(Self, ListenerOptions), IpcError> {
+        let Some(address) = value.as_str().or_else(|| value["address"].as_str()) else {
+            return Err(IpcError::Config(format!("invalid listener {}, address missing", value)));
+        };
+        let formats = Formats::from_value(value).map_err(|e| IpcError::Config(format!("listener {}: {}", address, e)))?;
+
+        let listener = Listener::parse(address)?;
+        #[cfg(unix)]
+        let output = if matches!(listener, Listener::Unix(_)) { config.socket_output } else { config.output };
+        #[cfg(not(unix))]
+        let output = config.output;
 
-                stream.write_all(&response_header.to_bytes()).await?;
-                stream.write_all(result.json.as_bytes()).await?;
-                stream.write_all(result.text.as_bytes()).await?;
+        Ok((listener, ListenerOptions { output, formats }))
+    }
+
+    fn parse(listener: &str) -> Result<Self, IpcError> {
+        let invalid = || {
+            IpcError::Config(format!(
+                "invalid listener '{}', expected tcp://HOST:PORT, tls://HOST:PORT, unix://PATH or vsock://[CID:]PORT",
+                listener
+            ))
+        };
+        let (scheme, address) = listener.split_once("://").ok_or_else(invalid)?;
+
+        match scheme {
+            "tcp" | "tls" => {
+                let (host, port) = address.rsplit_once(':').ok_or_else(invalid)?;
+                if host.is_empty() || port.is_empty() {
+                    return Err(invalid());
+                }
+                Ok(Listener::Tcp { host: host.to_string(), port: port.to_string(), tls: scheme == "tls" })
             }
-            _ => {
-                return Err("Unsupported control code".into());
+            #[cfg(unix)]
+            "unix" if !address.is_empty() => Ok(Listener::Unix(address.to_string())),
+            #[cfg(not(unix))]
+            "unix" => Err(IpcError::Config(format!("Unix domain sockets are not supported on this platform: '{}'", listener))),
+            #[cfg(all(target_os = "linux", feature = "vsock"))]
+            "vsock" => {
+                let (cid, port) = match address.split_once(':') {
+                    Some((cid, port)) => (cid.parse().map_err(|_| invalid())?, port),
+                    None => (vsock::CID_ANY, address),
+                };
+                Ok(Listener::Vsock { cid, port: port.parse().map_err(|_| invalid())? })
             }
+            #[cfg(not(all(target_os = "linux", feature = "vsock")))]
+            "vsock" => Err(IpcError::Config(format!("vsock is only supported on Linux with the vsock feature: '{}'", listener))),
+            _ => Err(invalid()),
         }
-    } else {
-        return Err("Invalid header format".into());
     }
+}
+
+/// Sockets passed by launchd or systemd, empty if not socket activated.
+fn activated_listeners() -> std::io::Result<Vec<Activated>> {
+    #[allow(unused_mut)]
+    let mut activated = Vec::new();
+
+    #[cfg(feature = "launchd")]
+    activated.extend(launchd::activated_listeners()?.into_iter().map(Activated::Tcp));
+    #[cfg(all(unix, feature = "systemd"))]
+    activated.extend(systemd::activated_listeners()?);
+
+    Ok(activated)
+}
+
+/// Refuse to listen on a non-loopback address without TLS unless
+/// `allow_insecure_public` is set, there is no authentication protecting the
+/// render service.
+fn check_public_bind(addresses: &[SocketAddr], config: &Config, tls: bool) -> Result<(), IpcError> {
+    if tls {
+        return Ok(());
+    }
+
+    let public: Vec<String> = addresses
+        .iter()
+        .filter(|address| !address.ip().is_loopback())
+        .map(|address| address.to_string())
+        .collect();
+
+    if public.is_empty() {
+        return Ok(());
+    }
+
+    if !config.allow_insecure_public {
+        let message = format!(
+            "Refusing to listen on public address {} without authentication or TLS, set allow_insecure_public to true to allow it",
+            public.join(", ")
+        );
+        logger::error(&message, &[]);
+        return Err(IpcError::Config(message));
+    }
+
+    logger::warning(
+        &format!(
+            "WARNING: listening on public address {} without authentication or TLS, anyone who can reach it can render templates",
+            public.join(", ")
+        ),
+        &[],
+    );
 
     Ok(())
 }
 
-fn parse_template(schema: &[u8], tpl: &str, schema_type: u8, tpl_type: u8) -> ParseTemplateResult {
-    let mut template = Template::new().unwrap();
+/// Refuse `hmac_required` with the HTTP or gRPC gateway: their requests are
+/// not records and can't be signed, they would render unsigned.
+fn check_unsigned_gateways(config: &Config) -> Result<(), IpcError> {
+    if !config.hmac_required {
+        return Ok(());
+    }
 
-    if schema_type == CONTENT_MSGPACK {
-        template.merge_schema_msgpack(schema).unwrap();
-    } else {
-        let schema_str = String::from_utf8(schema.to_vec())
-            .map_err(|e| format!("Failed to parse schema: {}", e))
-            .unwrap();
-        template.merge_schema_str(&schema_str).unwrap();
+    let gateways: Vec<&str> = [("http", &config.http), ("grpc", &config.grpc)]
+        .into_iter()
+        .filter(|(_, address)| address.is_some())
+        .map(|(name, _)| name)
+        .collect();
+    if gateways.is_empty() {
+        return Ok(());
     }
 
-    if tpl_type == CONTENT_PATH {
-        template.set_src_path(tpl).unwrap();
-    } else {
-        template.set_src_str(tpl);
+    Err(IpcError::Config(format!(
+        "hmac_required can't be enforced on {}, its requests are not signed",
+        gateways.join(" and ")
+    )))
+}
+
+/// Accept loop of a TCP listener, `tls` connections start with a handshake.
+#[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+async fn serve(listener: TcpListener, config: Arc<Config>, tls: bool, options: ListenerOptions) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                #[cfg(feature = "tls")]
+                if let Some(acceptor) = tls::acceptor().filter(|_| tls) {
+                    tls::spawn_client(acceptor, stream, peer.to_string(), &config, options);
+                    continue;
+                }
+                spawn_client(stream, peer.to_string(), &config, options)
+            }
+            Err(e) => logger::error(&format!("Failed to accept connection: {}", e), &[]),
+        }
     }
+}
 
-    let contents = template.render();
-    let result = json!({
-        "has_error": template.has_error(),
-        "status_code": template.get_status_code(),
-        "status_text": template.get_status_text(),
-        "status_param": template.get_status_param()
-    });
+#[cfg(unix)]
+async fn serve_unix(listener: UnixListener, config: Arc<Config>, options: ListenerOptions) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => spawn_client(stream, "unix".to_string(), &config, options),
+            Err(e) => logger::error(&format!("Failed to accept connection: {}", e), &[]),
+        }
+    }
+}
 
-    ParseTemplateResult {
-        json: result.to_string(),
-        text: contents,
-        status: CTRL_STATUS_OK,
+/// Named pipe servers serve one client each, a new instance is created for
+/// the next client before handing over the connected one.
+#[cfg(windows)]
+async fn serve_pipe(mut server: tokio::net::windows::named_pipe::NamedPipeServer, name: String, config: Arc<Config>) {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    loop {
+        if let Err(e) = server.connect().await {
+            logger::error(&format!("Failed to accept connection: {}", e), &[]);
+            continue;
+        }
+
+        let next = match ServerOptions::new().create(&name) {
+            Ok(next) => next,
+            Err(e) => {
+                logger::error(&format!("Failed to create pipe instance, {} closed: {}", name, e), &[]);
+                return;
+            }
+        };
+        let client = std::mem::replace(&mut server, next);
+        spawn_client(client, "pipe".to_string(), &config, config.socket_options());
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Addresses for `host` and `port`, an IPv6 literal may be written with or
+/// without brackets (`::1` or `[::1]`), names are resolved.
+async fn resolve(host: &str, port: &str) -> std::io::Result<Vec<SocketAddr>> {
+    let literal = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
 
-    #[test]
-    fn test_header_from_bytes() {
-        let bytes = [0, 10, 10, 0, 0, 0, 100, 30, 0, 0, 0, 50];
-        let header = Header::from_bytes(&bytes).unwrap();
+    if let Ok(ip) = literal.parse::<IpAddr>() {
+        let port = port.parse().map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid port '{}'", port))
+        })?;
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
 
-        assert_eq!(header.reserved, 0);
-        assert_eq!(header.control, CTRL_PARSE_TEMPLATE);
-        assert_eq!(header.content_format_1, CONTENT_JSON);
-        assert_eq!(header.content_length_1, 100);
-        assert_eq!(header.content_format_2, CONTENT_TEXT);
-        assert_eq!(header.content_length_2, 50);
+    Ok(tokio::net::lookup_host(format!("{}:{}", host, port)).await?.collect())
+}
+
+/// One listener per acceptor (`acceptors`), all bound to the same address
+/// with SO_REUSEPORT so the kernel spreads the connections between their
+/// accept loops. `reuse_port` alone lets several processes share the address.
+fn bind_acceptors(addresses: &[SocketAddr], config: &Config) -> std::io::Result<Vec<TcpListener>> {
+    let reuse_port = config.reuse_port || config.acceptors > 1;
+    let first = bind_tcp(addresses, config.dual_stack, reuse_port)?;
+
+    // The address actually bound, same port if it was 0.
+    let address = first.local_addr()?;
+    let mut listeners = vec![first];
+    for _ in 1..config.acceptors {
+        listeners.push(bind_tcp(&[address], config.dual_stack, true)?);
     }
 
-    #[test]
-    fn test_header_to_bytes() {
-        let header = Header {
-            reserved: 0,
-            control: CTRL_PARSE_TEMPLATE,
-            content_format_1: CONTENT_MSGPACK,
-            content_length_1: 256,
-            content_format_2: CONTENT_PATH,
-            content_length_2: 128,
-        };
+    Ok(listeners)
+}
 
-        let bytes = header.to_bytes();
-        assert_eq!(bytes[0], 0);
-        assert_eq!(bytes[1], CTRL_PARSE_TEMPLATE);
-        assert_eq!(bytes[2], CONTENT_MSGPACK);
-        assert_eq!([bytes[3], bytes[4], bytes[5], bytes[6]], [0, 0, 1, 0]); // 256
-        assert_eq!(bytes[7], CONTENT_PATH);
-        assert_eq!([bytes[8], bytes[9], bytes[10], bytes[11]], [0, 0, 0, 128]); // 128
+/// Bind the first address that works. IPv6 sockets also accept IPv4
+/// connections (as v4-mapped addresses) when `dual_stack` is set, so `::`
+/// listens on both, otherwise they are IPv6 only whatever the OS default is.
+fn bind_tcp(addresses: &[SocketAddr], dual_stack: bool, reuse_port: bool) -> std::io::Result<TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let mut last_error = std::io::Error::new(std::io::ErrorKind::InvalidInput, "no address to bind");
+
+    for address in addresses {
+        let bound = Socket::new(Domain::for_address(*address), Type::STREAM, Some(Protocol::TCP)).and_then(|socket| {
+            if address.is_ipv6() {
+                socket.set_only_v6(!dual_stack)?;
+            }
+            #[cfg(unix)]
+            socket.set_reuse_address(true)?;
+            #[cfg(unix)]
+            socket.set_reuse_port(reuse_port)?;
+            #[cfg(not(unix))]
+            if reuse_port {
+                return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "SO_REUSEPORT is not supported on this platform"));
+            }
+            socket.set_nonblocking(true)?;
+            socket.bind(&(*address).into())?;
+            socket.listen(1024)?;
+            TcpListener::from_std(socket.into())
+        });
+
+        match bound {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = e,
+        }
     }
 
-    #[test]
-    fn test_header_roundtrip() {
-        let original = Header {
-            reserved: 0,
-            control: CTRL_STATUS_OK,
-            content_format_1: CONTENT_MSGPACK,
-            content_length_1: 512,
-            content_format_2: CONTENT_TEXT,
-            content_length_2: 256,
-        };
+    Err(last_error)
+}
 
-        let bytes = original.to_bytes();
-        let parsed = Header::from_bytes(&bytes).unwrap();
+/// Bind the Unix domain socket, replacing a socket left by a previous run.
+#[cfg(unix)]
+fn bind_unix(path: &str) -> std::io::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
 
-        assert_eq!(original.reserved, parsed.reserved);
-        assert_eq!(original.control, parsed.control);
-        assert_eq!(original.content_format_1, parsed.content_format_1);
-        assert_eq!(original.content_length_1, parsed.content_length_1);
-        assert_eq!(original.content_format_2, parsed.content_format_2);
-        assert_eq!(original.content_length_2, parsed.content_length_2);
+    if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        fs::remove_file(path)?;
     }
 
-    #[test]
-    fn test_content_format_constants() {
-        assert_eq!(CONTENT_JSON, 10);
-        assert_eq!(CONTENT_MSGPACK, 50);
-        assert_eq!(CONTENT_PATH, 20);
-        assert_eq!(CONTENT_TEXT, 30);
+    UnixListener::bind(path)
+}
+
+fn spawn_client<S>(stream: S, peer: String, config: &Arc<Config>, options: ListenerOptions)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let config = Arc::clone(config);
+    tasks::spawn(peer.clone(), async move { run_client(stream, &peer, &config, options).await });
+}
+
+async fn run_client<S>(stream: S, peer: &str, config: &Config, options: ListenerOptions)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let stream = throttle::Throttled::new(stream, config.read_rate_limit, config.write_rate_limit);
+    if let Err(e) = handle_client(stream, peer, config, options).await {
+        logger::error(
+            &format!("Failed to handle client: {}", e),
+            &[("peer", peer), ("class", e.class().as_str())],
+        );
     }
+}
 
-    #[test]
-    fn test_header_size() {
-        assert_eq!(HEADER_SIZE, 12);
+/// Handle records one after the other until the client closes the connection,
+/// stays idle for `idle_timeout_secs`, the connection is recycled or a record
+/// fails. With an idle timeout of 0 the connection is closed after the first
+/// record.
+async fn handle_client<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    peer: &str,
+    config: &Config,
+    options: ListenerOptions,
+) -> Result<(), IpcError> {
+    let accepted = Instant::now();
+    let mut handled = 0;
+    let mut header_bytes = [0; HEADER_SIZE];
+    stream.read_exact(&mut header_bytes[..MAGIC.len()]).await?;
+    let magic = header_bytes[..MAGIC.len()] == MAGIC[..];
+    if magic {
+        stream.read_exact(&mut header_bytes).await?;
+    } else if config.magic_required {
+        return Err(IpcError::MissingMagic);
+    } else {
+        stream.read_exact(&mut header_bytes[MAGIC.len()..]).await?;
+    }
+    #[cfg(feature = "metrics")]
+    metrics::record_phase(metrics::HEADER_READ, accepted.elapsed());
+
+    loop {
+        // Version 1 records carry a request ID and may be pipelined.
+        if header_bytes[0] & !(extensions::EXTENDED | checksum::CHECKSUMS) == pipeline::VERSION {
+            if !magic {
+                return Err(IpcError::MissingMagic);
+            }
+            return pipeline::handle(stream, header_bytes, peer, config, options).await;
+        }
+
+        handled += 1;
+        let closing = recycle(config, handled, accepted);
+        handle_record(&mut stream, header_bytes, peer, config, options, closing).await?;
+        stream.flush().await?;
+
+        if closing {
+            logger::debug("Connection recycled", &[("peer", peer), ("requests", &handled.to_string())]);
+            return Ok(());
+        }
+
+        match next_header(&mut stream, peer, config).await? {
+            Some(next) => header_bytes = next,
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Whether a persistent connection has served its `connection_max_requests`
+/// records or `connection_max_age_secs` (0 no limit), to close it after the
+/// response to this record. Clients reconnect, which spreads them again
+/// across the instances behind a load balancer.
+fn recycle(config: &Config, handled: u64, opened: Instant) -> bool {
+    (config.connection_max_requests > 0 && handled >= config.connection_max_requests)
+        || (config.connection_max_age_secs > 0 && opened.elapsed() >= Duration::from_secs(config.connection_max_age_secs))
+}
+
+/// Wait for the header of the next record on a persistent connection, none
+/// if the client closed it, stayed idle for `idle_timeout_secs` or the server
+/// is shutting down.
+async fn next_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    peer: &str,
+    config: &Config,
+) -> Result<Option<[u8; HEADER_SIZE]>, IpcError> {
+    let idle_timeout = Duration::from_secs(config.idle_timeout_secs);
+    if idle_timeout.is_zero() {
+        return Ok(None);
+    }
+
+    let mut header_bytes = [0; HEADER_SIZE];
+    let read = tokio::select! {
+        read = tokio::time::timeout(idle_timeout, read_header(stream, &mut header_bytes)) => read,
+        () = tasks::draining() => {
+            logger::debug("Idle connection closed on shutdown", &[("peer", peer)]);
+            return Ok(None);
+        }
+    };
+    match read {
+        Ok(Ok(true)) => Ok(Some(header_bytes)),
+        Ok(Ok(false)) => Ok(None),
+        Ok(Err(e)) => Err(e.into()),
+        Err(_) => {
+            logger::debug("Idle connection closed", &[("peer", peer)]);
+            Ok(None)
+        }
+    }
+}
+
+/// Read a record header, false if the stream ends before it starts.
+async fn read_header<S: AsyncRead + Unpin>(stream: &mut S, header_bytes: &mut [u8; HEADER_SIZE]) -> std::io::Result<bool> {
+    let read = stream.read(header_bytes).await?;
+    if read == 0 {
+        return Ok(false);
+    }
+    stream.read_exact(&mut header_bytes[read..]).await?;
+
+    Ok(true)
+}
+
+/// Framing bits of a request header, set on top of the version and control.
+#[derive(Debug, Clone, Copy)]
+struct Framing {
+    signed: bool,
+    extended: bool,
+    checksummed: bool,
+    /// Lengths sent as 8 bytes after the header.
+    extended_lengths: [bool; 2],
+    /// Request ID of a version 1 record, sent after the extended lengths.
+    request_id: Option<u32>,
+}
+
+impl Framing {
+    /// The bits of the header, removed from it.
+    fn take(header: &mut Header) -> Self {
+        let framing = Framing {
+            signed: signature::is_signed(header.control),
+            extended: extensions::is_extended(header.version),
+            checksummed: checksum::is_checksummed(header.version),
+            extended_lengths: [false; 2],
+            request_id: None,
+        };
+        header.control &= !signature::SIGNED;
+        header.version &= !(extensions::EXTENDED | checksum::CHECKSUMS);
+        framing
+    }
+}
+
+/// What a request may use: the formats and output options of its listener,
+/// whether it is signed and the scopes of its key.
+#[derive(Debug, Clone, Copy)]
+struct Access<'a> {
+    formats: Formats,
+    output: OutputOptions,
+    signed: bool,
+    scopes: &'a [String],
+}
+
+/// Handle a record and write its response, flagged with `"closing": true`
+/// in content 1 if the connection is closed after it.
+async fn handle_record<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    header_bytes: [u8; HEADER_SIZE],
+    peer: &str,
+    config: &Config,
+    options: ListenerOptions,
+    closing: bool,
+) -> Result<(), IpcError> {
+    let trace_id = if config.trace_dump { trace::sample(config.trace_sample_rate) } else { None };
+    if let Some(id) = trace_id {
+        trace::dump(id, "request header", &header_bytes, HEADER_SIZE, false);
+    }
+
+    #[cfg(feature = "metrics")]
+    let started = Instant::now();
+
+    let mut header = Header::from_bytes(&header_bytes).ok_or(IpcError::InvalidHeader)?;
+    let extended_lengths = header.read_extended_lengths(&mut stream).await?;
+    let mut framing = Framing { extended_lengths, ..Framing::take(&mut header) };
+    if header.version == pipeline::VERSION {
+        framing.request_id = Some(stream.read_u32().await?);
+    }
+    let codec = compression::of(&header);
+    let chunked = header.control == CTRL_PARSE_TEMPLATE_CHUNKED;
+    let result = dispatch(&mut stream, &header, framing, config, options, trace_id).await;
+
+    #[cfg(feature = "metrics")]
+    let writing = Instant::now();
+    let outcome = match result {
+        Ok(mut result) => {
+            // Only rendered output is normalized: echoes and sources are sent
+            // as they are, a transaction normalizes each of its outputs.
+            if chunked || header.control == CTRL_PARSE_TEMPLATE {
+                result.text = options.output.apply(result.text);
+            }
+            if closing {
+                result.json = closing_json(result.json);
+            }
+            let sent = if chunked {
+                write_chunked_response(&mut stream, &result, codec, framing.checksummed, config).await
+            } else {
+                write_response(&mut stream, &result, codec, framing.checksummed, config, trace_id).await
+            };
+            sent.map(|sent| log_sent(sent, peer, framing.request_id, trace_id))
+        }
+        Err(e @ IpcError::Io(_)) => Err(e),
+        Err(e) => {
+            // The client is still waiting for a response, tell it what went wrong.
+            let error_result = ParseTemplateResult {
+                json: if closing { closing_json(e.to_json()) } else { e.to_json() },
+                text: String::new(),
+                status: e.control(),
+                binary: None,
+            };
+            let sent = write_response(&mut stream, &error_result, codec, framing.checksummed, config, trace_id).await?;
+            log_sent(sent, peer, framing.request_id, trace_id);
+            Err(e)
+        }
+    };
+
+    #[cfg(feature = "metrics")]
+    {
+        metrics::record_phase(metrics::WRITE, writing.elapsed());
+        metrics::record(header.control, started.elapsed(), outcome.is_ok());
+    }
+
+    outcome
+}
+
+/// Content 1 of the last response of a recycled connection: the records
+/// sent after its request are not answered.
+fn closing_json(json: String) -> String {
+    match serde_json::from_str::<serde_json::Value>(&json) {
+        Ok(serde_json::Value::Object(mut map)) => {
+            map.insert("closing".to_string(), true.into());
+            serde_json::Value::Object(map).to_string()
+        }
+        _ => json,
+    }
+}
+
+async fn dispatch<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    header: &Header,
+    framing: Framing,
+    config: &Config,
+    options: ListenerOptions,
+    trace_id: Option<u64>,
+) -> Result<ParseTemplateResult, IpcError> {
+    let received = Instant::now();
+    let formats = options.formats;
+    header.check_version()?;
+    if config.hmac_required && !framing.signed {
+        return Err(IpcError::Unauthorized("the request is not signed".to_string()));
+    }
+    compression::check(header)?;
+    let plain = compression::plain(header);
+    if config.strict_header {
+        plain.validate_strict()?;
+    }
+    formats.check(&plain)?;
+
+    let area = if framing.extended { Some(extensions::read_area(stream).await?) } else { None };
+    if let Some(area) = &area {
+        let extensions = extensions::decode(area)?;
+        extensions::check(&extensions)?;
+        let skipped: Vec<String> = extensions
+            .iter()
+            .filter(|extension| !extensions::is_known(extension.kind))
+            .map(|extension| format!("{} ({} bytes)", extension.kind, extension.value.len()))
+            .collect();
+        if !skipped.is_empty() {
+            logger::debug("Extensions skipped", &[("extensions", &skipped.join(", "))]);
+        }
+    }
+
+    if framing.signed {
+        let (contents, key_id) = signature::verify(stream, header, framing, area.as_deref(), config).await?;
+        if header.control == CTRL_SHUTDOWN && !config.admin_keys.contains(&key_id) {
+            return Err(IpcError::Unauthorized(format!("key '{}' is not an admin key", key_id)));
+        }
+        let access = Access { formats, output: options.output, signed: true, scopes: scopes::granted(Some(&key_id), config) };
+        return dispatch_contents(&mut std::io::Cursor::new(contents), header, plain, config, access, received, trace_id).await;
+    }
+    if header.control == CTRL_SHUTDOWN {
+        return Err(IpcError::Unauthorized("shutdown must be signed with an admin key".to_string()));
+    }
+    if header.control == CTRL_TEMPLATE_SOURCE {
+        return Err(IpcError::Unauthorized("template source must be signed".to_string()));
+    }
+    let access = Access { formats, output: options.output, signed: false, scopes: &[] };
+    if framing.checksummed {
+        let contents = checksum::read(stream, header).await?;
+        return dispatch_contents(&mut std::io::Cursor::new(contents), header, plain, config, access, received, trace_id).await;
+    }
+
+    dispatch_contents(stream, header, plain, config, access, received, trace_id).await
+}
+
+async fn dispatch_contents<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    header: &Header,
+    plain: Header,
+    config: &Config,
+    access: Access<'_>,
+    received: Instant,
+    trace_id: Option<u64>,
+) -> Result<ParseTemplateResult, IpcError> {
+    if compression::of(header) == compression::NONE {
+        return dispatch_control(stream, &plain, config, access, received, trace_id).await;
+    }
+
+    let (contents, plain) = compression::read(stream, header, config).await?;
+    dispatch_control(&mut std::io::Cursor::new(contents), &plain, config, access, received, trace_id).await
+}
+
+async fn dispatch_control<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    header: &Header,
+    config: &Config,
+    access: Access<'_>,
+    received: Instant,
+    trace_id: Option<u64>,
+) -> Result<ParseTemplateResult, IpcError> {
+    match header.control {
+        CTRL_PARSE_TEMPLATE | CTRL_PARSE_TEMPLATE_CHUNKED => read_parse_template(stream, header, config, access, received, trace_id).await,
+        #[cfg(feature = "metrics")]
+        CTRL_STATS => read_stats(stream, header).await,
+        CTRL_TEMPLATE_SOURCE => read_template_source(stream, header, config, access.scopes).await,
+        CTRL_NOOP => read_noop(stream, header, config).await,
+        CTRL_HELLO => read_hello(stream, header, config).await,
+        CTRL_INFO => read_info(stream, header, config).await,
+        CTRL_SHUTDOWN => read_shutdown(stream, header).await,
+        CTRL_PARSE_TRANSACTION => read_transaction(stream, header, config, access).await,
+        CTRL_KV => read_kv(stream, header, config, access.signed).await,
+        control => Err(IpcError::UnsupportedControl(control)),
+    }
+}
+
+/// `received` is when the header was read, the contents are counted from it
+/// so decompressing and checking the signature are part of the body read.
+async fn read_parse_template<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    header: &Header,
+    config: &Config,
+    access: Access<'_>,
+    received: Instant,
+    trace_id: Option<u64>,
+) -> Result<ParseTemplateResult, IpcError> {
+    if header.content_format_1 == CONTENT_MULTIPART {
+        return read_multipart_template(stream, header, config, access, received, trace_id).await;
+    }
+    check_template_formats(header)?;
+
+    let content_1_buffer = read_content(stream, header.content_length_1 as usize).await?;
+    let content_2_buffer = read_content(stream, header.content_length_2 as usize).await?;
+
+    parse_template(header, content_1_buffer, content_2_buffer, config, access, received, trace_id).await
+}
+
+/// Parse template with the schema and the template as the first two parts
+/// of a multipart content 1, content 2 must be empty.
+async fn read_multipart_template<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    header: &Header,
+    config: &Config,
+    access: Access<'_>,
+    received: Instant,
+    trace_id: Option<u64>,
+) -> Result<ParseTemplateResult, IpcError> {
+    if header.content_length_2 != 0 {
+        return Err(IpcError::Multipart("content-2 must be empty with a multipart content-1".to_string()));
+    }
+
+    let block = read_content(stream, header.content_length_1 as usize).await?;
+    let mut parts = multipart::decode(&block)?.into_iter();
+    let (Some(schema), Some(template)) = (parts.next(), parts.next()) else {
+        return Err(IpcError::Multipart("parse template takes the schema and the template parts".to_string()));
+    };
+
+    // The parts as the blocks of a plain request.
+    let header = Header {
+        version: header.version,
+        control: header.control,
+        content_format_1: schema.format,
+        content_length_1: schema.content.len() as u64,
+        content_format_2: template.format,
+        content_length_2: template.content.len() as u64,
+    };
+    access.formats.check(&header)?;
+    check_template_formats(&header)?;
+
+    parse_template(&header, schema.content, template.content, config, access, received, trace_id).await
+}
+
+fn check_template_formats(header: &Header) -> Result<(), IpcError> {
+    if !schema_format_supported(header.content_format_1) {
+        return Err(IpcError::InvalidFormat { block: 1, format: header.content_format_1, expected: SCHEMA_FORMATS });
+    }
+
+    if header.content_format_2 != CONTENT_TEXT && header.content_format_2 != CONTENT_PATH && header.content_format_2 != CONTENT_BIN {
+        return Err(IpcError::InvalidFormat { block: 2, format: header.content_format_2, expected: "TEXT, PATH or BIN" });
+    }
+
+    Ok(())
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+async fn parse_template(
+    header: &Header,
+    content_1_buffer: Vec<u8>,
+    content_2_buffer: Vec<u8>,
+    config: &Config,
+    access: Access<'_>,
+    received: Instant,
+    trace_id: Option<u64>,
+) -> Result<ParseTemplateResult, IpcError> {
+    #[cfg(feature = "metrics")]
+    metrics::record_phase(metrics::BODY_READ, received.elapsed());
+
+    if let Some(id) = trace_id {
+        trace::dump(id, "request content-1", &content_1_buffer, config.trace_max_bytes, config.trace_redact);
+        trace::dump(id, "request content-2", &content_2_buffer, config.trace_max_bytes, config.trace_redact);
+    }
+
+    mirror::mirror(config, header, &content_1_buffer, &content_2_buffer);
+    corpus::capture(config, header, &content_1_buffer, &content_2_buffer);
+
+    // A binary template blob is not rejected for invalid UTF-8, the engine
+    // gets it with the invalid sequences replaced.
+    if header.content_format_2 == CONTENT_BIN {
+        let template = String::from_utf8(content_2_buffer).unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned());
+        return render_template(config, Arc::new(content_1_buffer), header.content_format_1, template, CONTENT_TEXT, access.scopes).await;
+    }
+    let text_content = String::from_utf8(content_2_buffer)
+        .map_err(|source| IpcError::InvalidUtf8 { block: 2, source })?;
+    let (template, template_format) = fallback::resolve(text_content, header.content_format_2, config, access.formats).await;
+
+    render_template(config, Arc::new(content_1_buffer), header.content_format_1, template, template_format, access.scopes).await
+}
+
+/// Schema formats of the build, for format errors.
+const SCHEMA_FORMATS: &str = if cfg!(feature = "cbor") { "JSON, MSGPACK or CBOR" } else { "JSON or MSGPACK" };
+
+fn schema_format_supported(format: u8) -> bool {
+    format == CONTENT_JSON || format == CONTENT_MSGPACK || (cfg!(feature = "cbor") && format == CONTENT_CBOR)
+}
+
+/// A CBOR schema as JSON, the engine takes JSON or MsgPack. Other formats
+/// are returned as they are.
+fn decode_schema(schema: Arc<Vec<u8>>, schema_format: u8) -> Result<(Arc<Vec<u8>>, u8), IpcError> {
+    #[cfg(feature = "cbor")]
+    if schema_format == CONTENT_CBOR {
+        let value: serde_json::Value =
+            ciborium::from_reader(&schema[..]).map_err(|e| IpcError::Schema(format!("invalid CBOR: {}", e)))?;
+        return Ok((Arc::new(value.to_string().into_bytes()), CONTENT_JSON));
+    }
+
+    Ok((schema, schema_format))
+}
+
+/// Render with contents already read, shared by the transports. `scopes` are
+/// the scopes of the client, for template paths and inline templates. The
+/// schema is shared, the templates of a transaction render with the same one.
+async fn render_template(
+    config: &Config,
+    schema: Arc<Vec<u8>>,
+    schema_format: u8,
+    mut template: String,
+    template_format: u8,
+    scopes: &[String],
+) -> Result<ParseTemplateResult, IpcError> {
+    if template_format != CONTENT_PATH {
+        scopes::check_inline(scopes, config)?;
+    }
+    let (mut schema, schema_format) = decode_schema(schema, schema_format)?;
+    if template_format == CONTENT_PATH {
+        template = resolve_template_path(template, config)?;
+    }
+
+    let mut violations = Vec::new();
+    let mut trims = Vec::new();
+    if schema_format == CONTENT_JSON {
+        (schema, violations, trims) = prepare_schema(schema, &template, template_format == CONTENT_PATH, config).await?;
+    }
+
+    if !violations.is_empty() {
+        logger::warning(
+            "Schema does not match its JSON Schema",
+            &[("template", &template), ("violations", &violations.join("; "))],
+        );
+        if config.json_schema_mode == "enforce" {
+            return Err(IpcError::Schema(format!("does not match JSON Schema: {}", violations.join("; "))));
+        }
+    }
+
+    if !trims.is_empty() {
+        let paths: Vec<&str> = trims.iter().map(|trim| trim.path.as_str()).collect();
+        logger::warning("Schema values trimmed", &[("template", &template), ("paths", &paths.join(", "))]);
+    }
+
+    let mut permit = None;
+    if template_format == CONTENT_PATH {
+        scopes::check(&template, scopes, config).await?;
+        breaker::check(&template, config).await?;
+        permit = concurrency::acquire(&template, config).await?;
+    }
+
+    // The template slot goes with the render, as the worker permit: a
+    // cancelled request must not free it while the render still runs.
+    let backend = render_backend(config)?;
+    let mut result = workers::render(move || {
+        let _permit = permit;
+        backend.render(&RenderRequest {
+            schema: &schema,
+            schema_format,
+            template: &template,
+            template_format,
+        })
+    })
+    .await?;
+
+    if !violations.is_empty() {
+        result.json = validation::annotate(&result.json, &violations);
+    }
+    if !trims.is_empty() {
+        result.json = trim::annotate(&result.json, &trims);
+    }
+
+    Ok(result)
+}
+
+/// Resolve the kv references of a JSON schema, validate it against the JSON
+/// Schema of its template (`validate`, path templates) and trim it. Each step
+/// parses the whole schema, so they run in one blocking task.
+async fn prepare_schema(
+    schema: Arc<Vec<u8>>,
+    template: &str,
+    validate: bool,
+    config: &Config,
+) -> Result<(Arc<Vec<u8>>, Vec<String>, Vec<trim::Trim>), IpcError> {
+    let resolve = kv::wanted(&schema, config);
+    let validate = validate && validation::enabled();
+    let limits = trim::Limits::of(config);
+    if !resolve && !validate && limits.is_none() {
+        return Ok((schema, Vec::new(), Vec::new()));
+    }
+
+    let template = template.to_string();
+    tokio::task::spawn_blocking(move || {
+        let mut schema = schema;
+        if resolve {
+            if let Some(resolved) = kv::resolve(&schema)? {
+                schema = Arc::new(resolved);
+            }
+        }
+
+        let violations = if validate { validation::validate(&template, &schema) } else { Vec::new() };
+
+        let mut trims = Vec::new();
+        if let Some((trimmed, trimmed_values)) = limits.and_then(|limits| trim::trim(&schema, &limits)) {
+            schema = Arc::new(trimmed);
+            trims = trimmed_values;
+        }
+
+        Ok((schema, violations, trims))
+    })
+    .await
+    .map_err(|e| IpcError::Render(e.to_string()))?
+}
+
+/// Render every template of the manifest in content 2 with the schema in
+/// content 1, all or nothing.
+async fn read_transaction<S: AsyncRead + Unpin>(stream: &mut S, header: &Header, config: &Config, access: Access<'_>) -> Result<ParseTemplateResult, IpcError> {
+    if !schema_format_supported(header.content_format_1) {
+        return Err(IpcError::InvalidFormat { block: 1, format: header.content_format_1, expected: SCHEMA_FORMATS });
+    }
+
+    if header.content_format_2 != CONTENT_JSON {
+        return Err(IpcError::InvalidFormat { block: 2, format: header.content_format_2, expected: "JSON" });
+    }
+
+    let schema = read_content(stream, header.content_length_1 as usize).await?;
+    let manifest = read_content(stream, header.content_length_2 as usize).await?;
+    let templates = transaction::parse_manifest(&manifest, config)?;
+
+    transaction::render(config, schema, header.content_format_1, templates, access.output, access.scopes).await
+}
+
+/// A request to the key-value store, content 1 the request and content 2
+/// the value to set.
+async fn read_kv<S: AsyncRead + Unpin>(stream: &mut S, header: &Header, config: &Config, signed: bool) -> Result<ParseTemplateResult, IpcError> {
+    check_lengths(header, config)?;
+
+    let request = read_content(stream, header.content_length_1 as usize).await?;
+    let value = read_content(stream, header.content_length_2 as usize).await?;
+
+    Ok(ParseTemplateResult {
+        json: kv::handle(&request, &value, signed, config)?.to_string(),
+        text: String::new(),
+        status: CTRL_STATUS_OK,
+        binary: None,
+    })
+}
+
+#[cfg(feature = "metrics")]
+async fn read_stats<S: AsyncRead + Unpin>(stream: &mut S, header: &Header) -> Result<ParseTemplateResult, IpcError> {
+    discard_content(stream, header.content_length_1).await?;
+    discard_content(stream, header.content_length_2).await?;
+
+    Ok(ParseTemplateResult {
+        json: metrics::snapshot().to_string(),
+        text: String::new(),
+        status: CTRL_STATUS_OK,
+        binary: None,
+    })
+}
+
+async fn read_template_source<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    header: &Header,
+    config: &Config,
+    scopes: &[String],
+) -> Result<ParseTemplateResult, IpcError> {
+    if header.content_format_2 != CONTENT_PATH {
+        return Err(IpcError::InvalidFormat { block: 2, format: header.content_format_2, expected: "PATH" });
+    }
+
+    discard_content(stream, header.content_length_1).await?;
+    let content_2_buffer = read_content(stream, header.content_length_2 as usize).await?;
+    let path = String::from_utf8(content_2_buffer).map_err(|source| IpcError::InvalidUtf8 { block: 2, source })?;
+    let path = resolve_template_path(path, config)?;
+
+    source::read(&path, scopes, config).await
+}
+
+/// Reject a request over a limit of its control code known from the header
+/// alone, before its contents are read: the blocks of a pipelined record are
+/// buffered otherwise.
+fn check_lengths(header: &Header, config: &Config) -> Result<(), IpcError> {
+    let limits = match header.control {
+        CTRL_NOOP => vec![(2, header.content_length_2, config.noop_max_bytes)],
+        CTRL_HELLO => vec![(1, header.content_length_1, HELLO_MAX_BYTES)],
+        CTRL_KV if !kv::enabled(config) => return Err(IpcError::UnsupportedControl(CTRL_KV)),
+        CTRL_KV => vec![(1, header.content_length_1, config.kv_max_bytes as u64), (2, header.content_length_2, config.kv_max_bytes as u64)],
+        _ => return Ok(()),
+    };
+
+    match limits.into_iter().find(|(_, length, max)| length > max) {
+        Some((block, length, max)) => Err(IpcError::ContentTooLarge { block, length, max }),
+        None => Ok(()),
+    }
+}
+
+/// Echo the content-2 payload, for clients to measure the round trip, check
+/// the framing or keep an idle connection alive.
+async fn read_noop<S: AsyncRead + Unpin>(stream: &mut S, header: &Header, config: &Config) -> Result<ParseTemplateResult, IpcError> {
+    check_lengths(header, config)?;
+
+    discard_content(stream, header.content_length_1).await?;
+    let content_2_buffer = read_content(stream, header.content_length_2 as usize).await?;
+
+    // A binary payload is echoed as it is.
+    if header.content_format_2 == CONTENT_BIN {
+        return Ok(ParseTemplateResult {
+            json: "{}".to_string(),
+            text: String::new(),
+            status: CTRL_STATUS_OK,
+            binary: Some(content_2_buffer),
+        });
+    }
+    let payload = String::from_utf8(content_2_buffer).map_err(|source| IpcError::InvalidUtf8 { block: 2, source })?;
+
+    Ok(ParseTemplateResult {
+        json: "{}".to_string(),
+        text: payload,
+        status: CTRL_STATUS_OK,
+        binary: None,
+    })
+}
+
+/// Version negotiation. Content 1 is an optional JSON with the versions the
+/// client speaks, `{"versions": [0, 1]}`, without it the header version. The
+/// response has the highest version both speak, to use from then on.
+async fn read_hello<S: AsyncRead + Unpin>(stream: &mut S, header: &Header, config: &Config) -> Result<ParseTemplateResult, IpcError> {
+    check_lengths(header, config)?;
+
+    let content_1_buffer = read_content(stream, header.content_length_1 as usize).await?;
+    discard_content(stream, header.content_length_2).await?;
+
+    let client_versions: Vec<u8> = if content_1_buffer.is_empty() {
+        vec![header.version]
+    } else {
+        let hello: serde_json::Value =
+            serde_json::from_slice(&content_1_buffer).map_err(|e| IpcError::Schema(format!("hello: {}", e)))?;
+        hello["versions"]
+            .as_array()
+            .map(|versions| versions.iter().filter_map(|version| version.as_u64()).filter_map(|version| u8::try_from(version).ok()).collect())
+            .unwrap_or_default()
+    };
+
+    let version = negotiate(&client_versions).ok_or(IpcError::UnsupportedVersion(header.version))?;
+
+    Ok(ParseTemplateResult {
+        json: serde_json::json!({
+            "version": version,
+            "versions": PROTOCOL_VERSIONS,
+            "server": "neutral-ipc",
+            "server_version": env!("CARGO_PKG_VERSION")
+        })
+        .to_string(),
+        text: String::new(),
+        status: CTRL_STATUS_OK,
+        binary: None,
+    })
+}
+
+/// What the server supports, for clients to feature-detect.
+async fn read_info<S: AsyncRead + Unpin>(stream: &mut S, header: &Header, config: &Config) -> Result<ParseTemplateResult, IpcError> {
+    discard_content(stream, header.content_length_1).await?;
+    discard_content(stream, header.content_length_2).await?;
+
+    Ok(ParseTemplateResult {
+        json: info(config).to_string(),
+        text: String::new(),
+        status: CTRL_STATUS_OK,
+        binary: None,
+    })
+}
+
+/// Stop accepting and exit once the running connections finish, as on
+/// SIGTERM. Only reached with a valid admin signature, fresh and not seen
+/// before.
+async fn read_shutdown<S: AsyncRead + Unpin>(stream: &mut S, header: &Header) -> Result<ParseTemplateResult, IpcError> {
+    discard_content(stream, header.content_length_1).await?;
+    discard_content(stream, header.content_length_2).await?;
+
+    logger::warning("Shutdown requested by control code", &[]);
+    tasks::request_shutdown();
+
+    Ok(ParseTemplateResult {
+        json: serde_json::json!({ "shutting_down": true }).to_string(),
+        text: String::new(),
+        status: CTRL_STATUS_OK,
+        binary: None,
+    })
+}
+
+fn info(config: &Config) -> serde_json::Value {
+    let mut controls = serde_json::json!({
+        "parse_template": CTRL_PARSE_TEMPLATE,
+        "parse_template_chunked": CTRL_PARSE_TEMPLATE_CHUNKED,
+        "noop": CTRL_NOOP,
+        "hello": CTRL_HELLO,
+        "cancel": CTRL_CANCEL,
+        "info": CTRL_INFO,
+        "parse_transaction": CTRL_PARSE_TRANSACTION
+    });
+    #[cfg(feature = "metrics")]
+    {
+        controls["stats"] = CTRL_STATS.into();
+    }
+    if config.templates_root.is_some() && !config.hmac_keys.is_empty() {
+        controls["template_source"] = CTRL_TEMPLATE_SOURCE.into();
+    }
+    if !config.admin_keys.is_empty() {
+        controls["shutdown"] = CTRL_SHUTDOWN.into();
+    }
+    if kv::enabled(config) {
+        controls["kv"] = CTRL_KV.into();
+    }
+
+    let mut formats = serde_json::json!({
+        "json": CONTENT_JSON,
+        "path": CONTENT_PATH,
+        "text": CONTENT_TEXT,
+        "msgpack": CONTENT_MSGPACK,
+        "bin": CONTENT_BIN,
+        "multipart": CONTENT_MULTIPART
+    });
+    if cfg!(feature = "cbor") {
+        formats["cbor"] = CONTENT_CBOR.into();
+    }
+
+    let mut codecs = serde_json::json!({ "gzip": compression::GZIP });
+    if cfg!(feature = "zstd") {
+        codecs["zstd"] = compression::ZSTD.into();
+    }
+
+    serde_json::json!({
+        "server": "neutral-ipc",
+        "server_version": env!("CARGO_PKG_VERSION"),
+        "render_backend": config.render_backend,
+        "neutralts_version": backend::NEUTRALTS_VERSION,
+        "protocol_versions": PROTOCOL_VERSIONS,
+        "controls": controls,
+        "content_formats": formats,
+        "compression": codecs,
+        "signed": { "flag": signature::SIGNED, "required": config.hmac_required, "max_age_secs": config.hmac_max_age_secs },
+        "checksums": { "flag": checksum::CHECKSUMS },
+        "magic": { "bytes": String::from_utf8_lossy(MAGIC), "required": config.magic_required },
+        "limits": {
+            "noop_max_bytes": config.noop_max_bytes,
+            "hello_max_bytes": HELLO_MAX_BYTES,
+            "template_source_max_bytes": config.template_source_max_bytes,
+            "decompress_max_bytes": config.decompress_max_bytes,
+            "pipeline_max": config.pipeline_max,
+            "chunk_size": config.chunk_size,
+            "transaction_max_templates": config.transaction_max_templates,
+            "kv_max_bytes": config.kv_max_bytes,
+            "idle_timeout_secs": config.idle_timeout_secs,
+            "connection_max_requests": config.connection_max_requests,
+            "connection_max_age_secs": config.connection_max_age_secs
+        }
+    })
+}
+
+/// Highest version both the client and the server speak.
+fn negotiate(client_versions: &[u8]) -> Option<u8> {
+    PROTOCOL_VERSIONS.iter().rev().find(|&&version| client_versions.contains(&version)).copied()
+}
+
+/// Write a response, returns what was sent with `log_response_hash`.
+async fn write_response<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    result: &ParseTemplateResult,
+    codec: u8,
+    checksummed: bool,
+    config: &Config,
+    trace_id: Option<u64>,
+) -> Result<Option<Sent>, IpcError> {
+    let (format_1, content_1) = compress_block(CONTENT_JSON, result.json.as_bytes(), codec, config);
+    let (format_2, content_2) = result.content_2();
+    let (format_2, content_2) = compress_block(format_2, content_2, codec, config);
+    let response_header = Header {
+        version: if checksummed { checksum::CHECKSUMS } else { 0 },
+        control: result.status,
+        content_format_1: format_1,
+        content_length_1: content_1.len() as u64,
+        content_format_2: format_2,
+        content_length_2: content_2.len() as u64,
+    };
+
+    stream.write_all(&response_header.to_bytes()).await?;
+    stream.write_all(&response_header.extended_lengths()).await?;
+    stream.write_all(&content_1).await?;
+    stream.write_all(&content_2).await?;
+    if checksummed {
+        stream.write_all(&checksum::trailer(&content_1, &content_2)).await?;
+    }
+
+    if let Some(id) = trace_id {
+        trace::dump(id, "response header", &response_header.to_bytes(), HEADER_SIZE, false);
+        trace::dump(id, "response content-1", result.json.as_bytes(), config.trace_max_bytes, config.trace_redact);
+        trace::dump(id, "response content-2", result.content_2().1, config.trace_max_bytes, config.trace_redact);
+    }
+
+    if !config.log_response_hash {
+        return Ok(None);
+    }
+    let mut sent = Sent::new();
+    sent.update(&content_1);
+    sent.update(&content_2);
+
+    Ok(Some(sent))
+}
+
+/// Response of a chunked render: content 1 as usual, content-length 2 is 0
+/// and the output follows in chunks of at most `chunk_size` bytes, each one
+/// a 4 byte length (big endian) and the bytes, ended by a zero length chunk.
+/// Each chunk is flushed, so the client can start with the first ones.
+/// Returns what was sent with `log_response_hash`, the chunks as content 2.
+async fn write_chunked_response<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    result: &ParseTemplateResult,
+    codec: u8,
+    checksummed: bool,
+    config: &Config,
+) -> Result<Option<Sent>, IpcError> {
+    let (format_1, content_1) = compress_block(CONTENT_JSON, result.json.as_bytes(), codec, config);
+    let response_header = Header {
+        version: if checksummed { checksum::CHECKSUMS } else { 0 },
+        control: result.status,
+        content_format_1: format_1,
+        content_length_1: content_1.len() as u64,
+        content_format_2: result.content_2().0,
+        content_length_2: 0,
+    };
+
+    stream.write_all(&response_header.to_bytes()).await?;
+    stream.write_all(&response_header.extended_lengths()).await?;
+    stream.write_all(&content_1).await?;
+    let mut sent = config.log_response_hash.then(Sent::new);
+    if let Some(sent) = &mut sent {
+        sent.update(&content_1);
+    }
+
+    for chunk in result.content_2().1.chunks(config.chunk_size.max(1)) {
+        stream.write_all(&(chunk.len() as u32).to_be_bytes()).await?;
+        stream.write_all(chunk).await?;
+        stream.flush().await?;
+        if let Some(sent) = &mut sent {
+            sent.update(chunk);
+        }
+    }
+    stream.write_all(&0u32.to_be_bytes()).await?;
+    if checksummed {
+        stream.write_all(&checksum::trailer(&content_1, result.content_2().1)).await?;
+    }
+
+    Ok(sent)
+}
+
+/// A response block compressed with the codec of the request, if it is large
+/// enough to be worth it.
+fn compress_block<'a>(format: u8, content: &'a [u8], codec: u8, config: &Config) -> (u8, Cow<'a, [u8]>) {
+    if codec != compression::NONE && content.len() as u64 >= config.compress_min_bytes {
+        if let Some(compressed) = compression::compress(codec, content) {
+            return (format + codec, Cow::Owned(compressed));
+        }
+    }
+
+    (format, Cow::Borrowed(content))
+}
+
+/// Size and hash of the content blocks of a response as they were written,
+/// compressed if they were, for `log_response_hash`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Sent {
+    bytes: u64,
+    hash: u64,
+}
+
+impl Sent {
+    fn new() -> Self {
+        Sent { bytes: 0, hash: FNV_OFFSET }
+    }
+
+    fn update(&mut self, block: &[u8]) {
+        self.bytes += block.len() as u64;
+        self.hash = fnv1a_64_update(self.hash, block);
+    }
+}
+
+/// Log a response sent with `log_response_hash`, keyed by the request ID of
+/// a version 1 record or else the trace ID, by a sequence number otherwise.
+fn log_sent(sent: Option<Sent>, peer: &str, request_id: Option<u32>, trace_id: Option<u64>) {
+    let Some(sent) = sent else {
+        return;
+    };
+
+    let key = match (request_id, trace_id) {
+        (Some(id), _) => ("request_id", id.to_string()),
+        (None, Some(id)) => ("trace", id.to_string()),
+        (None, None) => ("response", (RESPONSE_SEQUENCE.fetch_add(1, Ordering::Relaxed) + 1).to_string()),
+    };
+    logger::info(
+        "Response sent",
+        &[
+            (key.0, &key.1),
+            ("peer", peer),
+            ("bytes", &sent.bytes.to_string()),
+            ("hash", &format!("{:016x}", sent.hash)),
+        ],
+    );
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+/// FNV-1a 64 of the bytes hashed so far (`hash`) followed by `block`.
+fn fnv1a_64_update(mut hash: u64, block: &[u8]) -> u64 {
+    for &byte in block {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn render_backend(config: &Config) -> Result<&'static dyn backend::RenderBackend, IpcError> {
+    backend::get(&config.render_backend)
+        .ok_or_else(|| IpcError::Config(format!("unknown render backend '{}'", config.render_backend)))
+}
+
+/// Resolves a virtual template name (`@name`) using `template_aliases`.
+fn resolve_template_path(path: String, config: &Config) -> Result<String, IpcError> {
+    if !path.starts_with('@') {
+        return Ok(path);
+    }
+
+    config.template_aliases.get(&path).cloned().ok_or(IpcError::UnknownAlias(path))
+}
+
+/// Reads exactly `length` bytes, growing the buffer in bounded steps.
+async fn read_content<R: AsyncRead + Unpin>(stream: &mut R, length: usize) -> std::io::Result<Vec<u8>> {
+    let mut buffer = Vec::with_capacity(length.min(READ_INITIAL_MAX));
+    let mut limited = stream.take(length as u64);
+
+    while buffer.len() < length {
+        if buffer.len() == buffer.capacity() {
+            buffer.reserve_exact((length - buffer.len()).min(READ_GROW_STEP));
+        }
+
+        if limited.read_buf(&mut buffer).await? == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("content truncated at {} of {} bytes", buffer.len(), length),
+            ));
+        }
+    }
+
+    Ok(buffer)
+}
+
+/// Reads and drops `length` bytes.
+async fn discard_content<R: AsyncRead + Unpin>(stream: &mut R, length: u64) -> std::io::Result<()> {
+    let copied = tokio::io::copy(&mut stream.take(length), &mut tokio::io::sink()).await?;
+    if copied < length {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("content truncated at {} of {} bytes", copied, length),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// FNV-1a 64 over the concatenation of the blocks.
+    fn fnv1a_64(blocks: &[&[u8]]) -> u64 {
+        blocks.iter().fold(FNV_OFFSET, |hash, block| fnv1a_64_update(hash, block))
+    }
+
+    #[tokio::test]
+    async fn test_resolve_ipv6_literal() {
+        let expected: SocketAddr = "[::1]:4273".parse().unwrap();
+        assert_eq!(resolve("::1", "4273").await.unwrap(), vec![expected]);
+        assert_eq!(resolve("[::1]", "4273").await.unwrap(), vec![expected]);
+
+        let expected: SocketAddr = "127.0.0.1:4273".parse().unwrap();
+        assert_eq!(resolve("127.0.0.1", "4273").await.unwrap(), vec![expected]);
+
+        assert!(resolve("::1", "port").await.is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_bind_acceptors_share_address() {
+        let mut config = Config::default();
+        config.acceptors = 3;
+        let addresses: Vec<SocketAddr> = vec!["127.0.0.1:0".parse().unwrap()];
+        let listeners = bind_acceptors(&addresses, &config).unwrap();
+
+        assert_eq!(listeners.len(), 3);
+        let address = listeners[0].local_addr().unwrap();
+        assert!(listeners.iter().all(|listener| listener.local_addr().unwrap() == address));
+    }
+
+    #[tokio::test]
+    async fn test_bind_tcp_first_usable_address() {
+        let addresses: Vec<SocketAddr> = vec!["192.0.2.1:0".parse().unwrap(), "127.0.0.1:0".parse().unwrap()];
+        let listener = bind_tcp(&addresses, true, false).unwrap();
+        assert!(listener.local_addr().unwrap().ip().is_loopback());
+
+        assert!(bind_tcp(&[], true, false).is_err());
+    }
+
+    #[test]
+    fn test_listener_from_value() {
+        let config = Config::default();
+
+        let (listener, options) = Listener::from_value(&serde_json::json!("tcp://127.0.0.1:4273"), &config).unwrap();
+        assert!(matches!(listener, Listener::Tcp { tls: false, .. }));
+        assert_eq!(options.formats, Formats::default());
+
+        let entry = serde_json::json!({ "address": "tcp://0.0.0.0:4273", "template_formats": ["text"] });
+        let (_, options) = Listener::from_value(&entry, &config).unwrap();
+        assert_ne!(options.formats, Formats::default());
+
+        assert!(Listener::from_value(&serde_json::json!({ "template_formats": ["text"] }), &config).is_err());
+        assert!(Listener::from_value(&serde_json::json!({ "address": "tcp://0.0.0.0:4273", "template_formats": ["html"] }), &config).is_err());
+    }
+
+    #[test]
+    fn test_parse_listener() {
+        assert_eq!(
+            Listener::parse("tcp://127.0.0.1:4273").unwrap(),
+            Listener::Tcp { host: "127.0.0.1".to_string(), port: "4273".to_string(), tls: false }
+        );
+        assert_eq!(
+            Listener::parse("tls://[::]:4274").unwrap(),
+            Listener::Tcp { host: "[::]".to_string(), port: "4274".to_string(), tls: true }
+        );
+        #[cfg(unix)]
+        assert_eq!(
+            Listener::parse("unix:///run/neutral-ipc.sock").unwrap(),
+            Listener::Unix("/run/neutral-ipc.sock".to_string())
+        );
+
+        assert!(Listener::parse("127.0.0.1:4273").is_err());
+        assert!(Listener::parse("tcp://127.0.0.1").is_err());
+        assert!(Listener::parse("udp://127.0.0.1:4273").is_err());
+        assert!(Listener::parse("unix://").is_err());
+        #[cfg(all(target_os = "linux", feature = "vsock"))]
+        {
+            assert_eq!(Listener::parse("vsock://4273").unwrap(), Listener::Vsock { cid: vsock::CID_ANY, port: 4273 });
+            assert_eq!(Listener::parse("vsock://2:4273").unwrap(), Listener::Vsock { cid: 2, port: 4273 });
+            assert!(Listener::parse("vsock://host:4273").is_err());
+        }
+    }
+
+    #[test]
+    fn test_header_from_bytes() {
+        let bytes = [0, 10, 10, 0, 0, 0, 100, 30, 0, 0, 0, 50];
+        let header = Header::from_bytes(&bytes).unwrap();
+
+        assert_eq!(header.version, 0);
+        assert_eq!(header.control, CTRL_PARSE_TEMPLATE);
+        assert_eq!(header.content_format_1, CONTENT_JSON);
+        assert_eq!(header.content_length_1, 100);
+        assert_eq!(header.content_format_2, CONTENT_TEXT);
+        assert_eq!(header.content_length_2, 50);
+    }
+
+    #[test]
+    fn test_header_to_bytes() {
+        let header = Header {
+            version: 0,
+            control: CTRL_PARSE_TEMPLATE,
+            content_format_1: CONTENT_MSGPACK,
+            content_length_1: 256,
+            content_format_2: CONTENT_PATH,
+            content_length_2: 128,
+        };
+
+        let bytes = header.to_bytes();
+        assert_eq!(bytes[0], 0);
+        assert_eq!(bytes[1], CTRL_PARSE_TEMPLATE);
+        assert_eq!(bytes[2], CONTENT_MSGPACK);
+        assert_eq!([bytes[3], bytes[4], bytes[5], bytes[6]], [0, 0, 1, 0]); // 256
+        assert_eq!(bytes[7], CONTENT_PATH);
+        assert_eq!([bytes[8], bytes[9], bytes[10], bytes[11]], [0, 0, 0, 128]); // 128
+    }
+
+    #[test]
+    fn test_header_roundtrip() {
+        let original = Header {
+            version: 0,
+            control: CTRL_STATUS_OK,
+            content_format_1: CONTENT_MSGPACK,
+            content_length_1: 512,
+            content_format_2: CONTENT_TEXT,
+            content_length_2: 256,
+        };
+
+        let bytes = original.to_bytes();
+        let parsed = Header::from_bytes(&bytes).unwrap();
+
+        assert_eq!(original.version, parsed.version);
+        assert_eq!(original.control, parsed.control);
+        assert_eq!(original.content_format_1, parsed.content_format_1);
+        assert_eq!(original.content_length_1, parsed.content_length_1);
+        assert_eq!(original.content_format_2, parsed.content_format_2);
+        assert_eq!(original.content_length_2, parsed.content_length_2);
+    }
+
+    #[test]
+    fn test_content_format_constants() {
+        assert_eq!(CONTENT_JSON, 10);
+        assert_eq!(CONTENT_MSGPACK, 50);
+        assert_eq!(CONTENT_PATH, 20);
+        assert_eq!(CONTENT_TEXT, 30);
+    }
+
+    #[test]
+    fn test_header_size() {
+        assert_eq!(HEADER_SIZE, 12);
+    }
+
+    #[test]
+    fn test_header_validate_strict() {
+        let valid = Header::from_bytes(&[0, 10, 10, 0, 0, 0, 2, 30, 0, 0, 0, 5]).unwrap();
+        assert!(valid.validate_strict().is_ok());
+
+        let noop = Header::from_bytes(&[0, 40, 10, 0, 0, 0, 2, 30, 0, 0, 0, 5]).unwrap();
+        assert!(matches!(noop.validate_strict(), Err(IpcError::StrictHeader(_))));
+    }
+
+    #[test]
+    fn test_header_check_version() {
+        let current = Header::from_bytes(&[0, 10, 10, 0, 0, 0, 2, 30, 0, 0, 0, 5]).unwrap();
+        assert!(current.check_version().is_ok());
+
+        let unknown = Header::from_bytes(&[9, 10, 10, 0, 0, 0, 2, 30, 0, 0, 0, 5]).unwrap();
+        assert!(matches!(unknown.check_version(), Err(IpcError::UnsupportedVersion(9))));
+
+        let hello = Header::from_bytes(&[7, 50, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+        assert!(hello.check_version().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_hello_negotiation() {
+        let versions = br#"{"versions": [0, 1, 2]}"#;
+        let header = Header {
+            version: 2,
+            control: CTRL_HELLO,
+            content_format_1: CONTENT_JSON,
+            content_length_1: versions.len() as u64,
+            content_format_2: 0,
+            content_length_2: 0,
+        };
+        let result = read_hello(&mut &versions[..], &header, &Config::default()).await.unwrap();
+        let hello: serde_json::Value = serde_json::from_str(&result.json).unwrap();
+        assert_eq!(hello["version"], 1);
+
+        let header = Header { content_length_1: 0, ..header };
+        assert!(matches!(read_hello(&mut &b""[..], &header, &Config::default()).await, Err(IpcError::UnsupportedVersion(2))));
+
+        assert_eq!(negotiate(&[5, 0]), Some(0));
+        assert_eq!(negotiate(&[0, 1]), Some(1));
+        assert_eq!(negotiate(&[]), None);
+    }
+
+    #[test]
+    fn test_check_public_bind() {
+        let mut config = Config::default();
+        let loopback: SocketAddr = "127.0.0.1:4273".parse().unwrap();
+        let public: SocketAddr = "0.0.0.0:4273".parse().unwrap();
+
+        assert!(check_public_bind(&[loopback, "[::1]:4273".parse().unwrap()], &config, false).is_ok());
+        assert!(matches!(check_public_bind(&[loopback, public], &config, false), Err(IpcError::Config(_))));
+        assert!(check_public_bind(&[public], &config, true).is_ok());
+
+        config.allow_insecure_public = true;
+        assert!(check_public_bind(&[public], &config, false).is_ok());
+    }
+
+    #[test]
+    fn test_check_unsigned_gateways() {
+        let mut config = Config::default();
+        config.http = Some("127.0.0.1:8080".to_string());
+        assert!(check_unsigned_gateways(&config).is_ok());
+
+        config.hmac_required = true;
+        assert!(matches!(check_unsigned_gateways(&config), Err(IpcError::Config(_))));
+        config.http = None;
+        config.grpc = Some("127.0.0.1:50051".to_string());
+        assert!(matches!(check_unsigned_gateways(&config), Err(IpcError::Config(_))));
+        config.grpc = None;
+        assert!(check_unsigned_gateways(&config).is_ok());
+    }
+
+    #[test]
+    fn test_fnv1a_64() {
+        assert_eq!(fnv1a_64(&[]), 0xcbf29ce484222325);
+        assert_eq!(fnv1a_64(&[b"a"]), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a_64(&[b"foo", b"bar"]), fnv1a_64(&[b"foobar"]));
+    }
+
+    #[tokio::test]
+    async fn test_sent_hash() {
+        let mut config = Config::default();
+        config.log_response_hash = true;
+        config.compress_min_bytes = 0;
+        config.chunk_size = 2;
+        let result = ParseTemplateResult {
+            json: "{}".to_string(),
+            text: "hello".to_string(),
+            status: CTRL_STATUS_OK,
+            binary: None,
+        };
+
+        // The compressed blocks as written are hashed.
+        let mut response = Vec::new();
+        let sent = write_response(&mut response, &result, compression::GZIP, false, &config, None).await.unwrap().unwrap();
+        assert_eq!(sent.bytes as usize, response.len() - HEADER_SIZE);
+        assert_eq!(sent.hash, fnv1a_64(&[&response[HEADER_SIZE..]]));
+
+        // A chunked output is hashed as one content 2.
+        let mut response = Vec::new();
+        let sent = write_chunked_response(&mut response, &result, compression::NONE, false, &config).await.unwrap().unwrap();
+        assert_eq!(sent, Sent { bytes: 7, hash: fnv1a_64(&[b"{}hello"]) });
+
+        config.log_response_hash = false;
+        assert!(write_response(&mut Vec::new(), &result, compression::NONE, false, &config, None).await.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_resolve_template_path() {
+        let mut config = Config::default();
+        config.template_aliases.insert("@home".to_string(), "/srv/tpl/home.ntpl".to_string());
+
+        assert_eq!(resolve_template_path("@home".to_string(), &config).unwrap(), "/srv/tpl/home.ntpl");
+        assert_eq!(resolve_template_path("/srv/tpl/a.ntpl".to_string(), &config).unwrap(), "/srv/tpl/a.ntpl");
+        assert!(matches!(
+            resolve_template_path("@checkout".to_string(), &config),
+            Err(IpcError::UnknownAlias(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_content_grows_in_steps() {
+        let data: Vec<u8> = (0..READ_INITIAL_MAX * 2 + 100).map(|i| i as u8).collect();
+        let mut reader = &data[..];
+        let content = read_content(&mut reader, data.len() - 10).await.unwrap();
+
+        assert_eq!(content, &data[..data.len() - 10]);
+        assert_eq!(reader.len(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_read_content_truncated() {
+        let data = [1u8; 100];
+        let mut reader = &data[..];
+        let err = read_content(&mut reader, u32::MAX as usize).await.unwrap_err();
+
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn test_handle_client_error_response() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let request = Header {
+            version: 0,
+            control: 99,
+            content_format_1: 0,
+            content_length_1: 0,
+            content_format_2: 0,
+            content_length_2: 0,
+        };
+        client.write_all(&request.to_bytes()).await.unwrap();
+
+        let result = handle_client(server, "test", &Config::default(), ListenerOptions::default()).await;
+        assert!(matches!(result, Err(IpcError::UnsupportedControl(99))));
+
+        let mut response = [0; HEADER_SIZE];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[1], CTRL_STATUS_KO);
+    }
+
+    #[tokio::test]
+    async fn test_magic() {
+        let ping = [0, CTRL_NOOP, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut config = Config::default();
+        config.magic_required = true;
+        config.idle_timeout_secs = 0;
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(MAGIC).await.unwrap();
+        client.write_all(&ping).await.unwrap();
+        handle_client(server, "test", &config, ListenerOptions::default()).await.unwrap();
+        let mut response = [0; HEADER_SIZE];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[1], CTRL_STATUS_OK);
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(&ping).await.unwrap();
+        let result = handle_client(server, "test", &config, ListenerOptions::default()).await;
+        assert!(matches!(result, Err(IpcError::MissingMagic)));
+
+        // Version 1 needs the magic even when it is not required.
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(&[pipeline::VERSION, CTRL_NOOP, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).await.unwrap();
+        let result = handle_client(server, "test", &Config::default(), ListenerOptions::default()).await;
+        assert!(matches!(result, Err(IpcError::MissingMagic)));
+    }
+
+    #[tokio::test]
+    async fn test_connection_recycling() {
+        let ping = [0, CTRL_NOOP, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut config = Config::default();
+        config.connection_max_requests = 2;
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        for _ in 0..3 {
+            client.write_all(&ping).await.unwrap();
+        }
+        handle_client(server, "test", &config, ListenerOptions::default()).await.unwrap();
+
+        // The third record is not answered, the second response says so.
+        let mut responses = Vec::new();
+        client.read_to_end(&mut responses).await.unwrap();
+        let first = Header::from_bytes(&responses).unwrap();
+        let second_at = HEADER_SIZE + first.content_length_1 as usize;
+        let second = Header::from_bytes(&responses[second_at..]).unwrap();
+        assert_eq!(&responses[HEADER_SIZE..second_at], b"{}");
+        assert_eq!(&responses[second_at + HEADER_SIZE..], br#"{"closing":true}"#);
+        assert_eq!(second.content_length_1 as usize, responses.len() - second_at - HEADER_SIZE);
+        assert!(recycle(&config, 2, Instant::now()));
+        assert!(!recycle(&Config::default(), 1000, Instant::now()));
+    }
+
+    #[tokio::test]
+    async fn test_noop_echo() {
+        let mut config = Config::default();
+        let header = Header {
+            version: 0,
+            control: CTRL_NOOP,
+            content_format_1: 0,
+            content_length_1: 0,
+            content_format_2: CONTENT_TEXT,
+            content_length_2: 5,
+        };
+
+        let result = read_noop(&mut &b"nonce"[..], &header, &config).await.unwrap();
+        assert_eq!(result.status, CTRL_STATUS_OK);
+        assert_eq!(result.text, "nonce");
+
+        config.noop_max_bytes = 4;
+        assert!(matches!(
+            read_noop(&mut &b"nonce"[..], &header, &config).await,
+            Err(IpcError::ContentTooLarge { block: 2, length: 5, max: 4 })
+        ));
+
+        // The echo is not normalized by the output options of the listener.
+        let payload = b"a\nb";
+        let request = Header { content_length_2: payload.len() as u64, ..header };
+        let mut exchange = exchange::Exchange::new(payload.to_vec());
+        let output = OutputOptions::from_value(&serde_json::json!({ "newline": "crlf", "bom": "emit" }));
+        let options = ListenerOptions { output, formats: Formats::default() };
+        handle_record(&mut exchange, request.to_bytes(), "test", &Config::default(), options, false).await.unwrap();
+        assert_eq!(&exchange.response[HEADER_SIZE + 2..], payload);
+    }
+
+    #[test]
+    fn test_info() {
+        let mut config = Config::default();
+        let value = info(&config);
+
+        assert_eq!(value["controls"]["info"], CTRL_INFO);
+        assert!(value["controls"].get("template_source").is_none());
+        assert_eq!(value["content_formats"]["msgpack"], CONTENT_MSGPACK);
+        assert_eq!(value["protocol_versions"], serde_json::json!(PROTOCOL_VERSIONS));
+        assert_eq!(value["limits"]["noop_max_bytes"], 1024);
+
+        config.templates_root = Some("/srv/templates".to_string());
+        assert!(info(&config)["controls"].get("template_source").is_none());
+        config.hmac_keys.insert("debugger".to_string(), "secret".to_string());
+        assert_eq!(info(&config)["controls"]["template_source"], CTRL_TEMPLATE_SOURCE);
+    }
+
+    #[tokio::test]
+    async fn test_hmac_required() {
+        let mut config = Config::default();
+        config.hmac_required = true;
+        let ping = [0, CTRL_NOOP, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+        let mut exchange = exchange::Exchange::new(Vec::new());
+        let outcome = handle_record(&mut exchange, ping, "test", &config, ListenerOptions::default(), false).await;
+
+        assert!(matches!(outcome, Err(IpcError::Unauthorized(_))));
+        let response = Header::from_bytes(&exchange.response).unwrap();
+        assert_eq!(response.control, CTRL_STATUS_KO);
+    }
+
+    #[tokio::test]
+    async fn test_unsigned_shutdown() {
+        let mut config = Config::default();
+        config.admin_keys.push("admin".to_string());
+        let shutdown = [0, CTRL_SHUTDOWN, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+        let mut exchange = exchange::Exchange::new(Vec::new());
+        let outcome = handle_record(&mut exchange, shutdown, "test", &config, ListenerOptions::default(), false).await;
+
+        assert!(matches!(outcome, Err(IpcError::Unauthorized(_))));
+        let response = Header::from_bytes(&exchange.response).unwrap();
+        assert_eq!(response.control, CTRL_STATUS_KO);
+    }
+
+    #[tokio::test]
+    async fn test_replayed_shutdown() {
+        let mut config = Config::default();
+        config.hmac_keys.insert("admin".to_string(), "secret".to_string());
+        config.admin_keys.push("admin".to_string());
+        let header = Header { version: 0, control: CTRL_SHUTDOWN, content_format_1: 0, content_length_1: 0, content_format_2: 0, content_length_2: 0 };
+        let framing = Framing { signed: true, extended: true, checksummed: false, extended_lengths: [false; 2], request_id: None };
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        let area = signature::tests::area(now);
+        let mut record = area.clone();
+        record.extend(signature::tests::sign("secret", "admin", &header, framing, &area, b""));
+        let mut shutdown = header.to_bytes();
+        shutdown[0] |= extensions::EXTENDED;
+        shutdown[1] |= signature::SIGNED;
+
+        let mut exchange = exchange::Exchange::new(record.clone());
+        handle_record(&mut exchange, shutdown, "test", &config, ListenerOptions::default(), false).await.unwrap();
+        assert_eq!(Header::from_bytes(&exchange.response).unwrap().control, CTRL_STATUS_OK);
+
+        // The same record captured and sent again.
+        let mut exchange = exchange::Exchange::new(record);
+        let outcome = handle_record(&mut exchange, shutdown, "test", &config, ListenerOptions::default(), false).await;
+        assert!(matches!(outcome, Err(IpcError::Unauthorized(_))));
+        assert_eq!(Header::from_bytes(&exchange.response).unwrap().control, CTRL_STATUS_KO);
+    }
+
+    #[tokio::test]
+    async fn test_unsigned_template_source() {
+        let mut config = Config::default();
+        config.templates_root = Some(".".to_string());
+        let path = b"README.md";
+        let source = Header {
+            version: 0,
+            control: CTRL_TEMPLATE_SOURCE,
+            content_format_1: 0,
+            content_length_1: 0,
+            content_format_2: CONTENT_PATH,
+            content_length_2: path.len() as u64,
+        };
+
+        let mut exchange = exchange::Exchange::new(path.to_vec());
+        let outcome = handle_record(&mut exchange, source.to_bytes(), "test", &config, ListenerOptions::default(), false).await;
+
+        assert!(matches!(outcome, Err(IpcError::Unauthorized(_))));
+        let response = Header::from_bytes(&exchange.response).unwrap();
+        assert_eq!(response.control, CTRL_STATUS_KO);
+    }
+
+    #[tokio::test]
+    async fn test_ping() {
+        let ping = [0, CTRL_NOOP, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+        let mut exchange = exchange::Exchange::new(Vec::new());
+        handle_record(&mut exchange, ping, "test", &Config::default(), ListenerOptions::default(), false).await.unwrap();
+
+        let response = Header::from_bytes(&exchange.response).unwrap();
+        assert_eq!(response.control, CTRL_STATUS_OK);
+        assert_eq!(response.content_length_2, 0);
+        assert_eq!(&exchange.response[HEADER_SIZE..], b"{}");
+    }
+
+    #[cfg(feature = "cbor")]
+    #[tokio::test]
+    async fn test_cbor_schema() {
+        let mut schema = Vec::new();
+        ciborium::into_writer(&serde_json::json!({ "data": { "name": "cbor" } }), &mut schema).unwrap();
+
+        let (decoded, format) = decode_schema(Arc::new(schema), CONTENT_CBOR).unwrap();
+        assert_eq!(format, CONTENT_JSON);
+        assert_eq!(*decoded, br#"{"data":{"name":"cbor"}}"#);
+        assert!(matches!(decode_schema(Arc::new(vec![0xff]), CONTENT_CBOR), Err(IpcError::Schema(_))));
+        assert_eq!(decode_schema(Arc::new(b"{}".to_vec()), CONTENT_JSON).unwrap().1, CONTENT_JSON);
+    }
+
+    #[tokio::test]
+    async fn test_extended_lengths() {
+        let large = Header {
+            version: 0,
+            control: CTRL_NOOP,
+            content_format_1: 0,
+            content_length_1: 0,
+            content_format_2: CONTENT_BIN,
+            content_length_2: 5 << 30,
+        };
+        assert_eq!(large.to_bytes()[8..], LENGTH_EXTENDED.to_be_bytes());
+        assert_eq!(large.extended_lengths(), (5u64 << 30).to_be_bytes());
+
+        let mut parsed = Header::from_bytes(&large.to_bytes()).unwrap();
+        parsed.read_extended_lengths(&mut &large.extended_lengths()[..]).await.unwrap();
+        assert_eq!(parsed.content_length_1, 0);
+        assert_eq!(parsed.content_length_2, 5 << 30);
+        assert!(Header { content_length_2: 5, ..large }.extended_lengths().is_empty());
+
+        // Small lengths may be extended too.
+        let header_bytes = [0, CTRL_NOOP, 0, 0, 0, 0, 0, CONTENT_TEXT, 0xff, 0xff, 0xff, 0xff];
+        let mut request = 5u64.to_be_bytes().to_vec();
+        request.extend_from_slice(b"nonce");
+        let mut exchange = exchange::Exchange::new(request);
+        handle_record(&mut exchange, header_bytes, "test", &Config::default(), ListenerOptions::default(), false).await.unwrap();
+        assert_eq!(&exchange.response[HEADER_SIZE + 2..], b"nonce");
+
+        let small = Header { content_format_2: CONTENT_TEXT, content_length_2: 5, ..large };
+        assert_eq!(small.to_bytes_as([false, true]), header_bytes);
+        assert_eq!(small.extended_lengths_as([false, true]), 5u64.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_transaction() {
+        let mut config = Config::default();
+        config.render_backend = "mock".to_string();
+        let manifest = br#"["a.ntpl", "bb.ntpl"]"#;
+        let request = Header {
+            version: 0,
+            control: CTRL_PARSE_TRANSACTION,
+            content_format_1: CONTENT_JSON,
+            content_length_1: 2,
+            content_format_2: CONTENT_JSON,
+            content_length_2: manifest.len() as u64,
+        };
+        let mut record = b"{}".to_vec();
+        record.extend_from_slice(manifest);
+
+        let mut exchange = exchange::Exchange::new(record);
+        handle_record(&mut exchange, request.to_bytes(), "test", &config, ListenerOptions::default(), false).await.unwrap();
+        let response = Header::from_bytes(&exchange.response).unwrap();
+        assert_eq!(response.control, CTRL_STATUS_OK);
+        assert!(exchange.response.ends_with(b"a.ntplbb.ntpl"));
+    }
+
+    #[tokio::test]
+    async fn test_multipart_template() {
+        let mut config = Config::default();
+        config.render_backend = "mock".to_string();
+        let block = multipart::tests::encode(&[
+            multipart::Part { format: CONTENT_JSON, content: b"{}".to_vec() },
+            multipart::Part { format: CONTENT_TEXT, content: b"hello".to_vec() },
+            multipart::Part { format: CONTENT_JSON, content: b"{}".to_vec() },
+        ]);
+        let request = Header {
+            version: 0,
+            control: CTRL_PARSE_TEMPLATE,
+            content_format_1: CONTENT_MULTIPART,
+            content_length_1: block.len() as u64,
+            content_format_2: 0,
+            content_length_2: 0,
+        };
+
+        let mut exchange = exchange::Exchange::new(block.clone());
+        handle_record(&mut exchange, request.to_bytes(), "test", &config, ListenerOptions::default(), false).await.unwrap();
+        let response = Header::from_bytes(&exchange.response).unwrap();
+        assert_eq!(response.control, CTRL_STATUS_OK);
+        assert!(exchange.response.ends_with(b"hello"));
+
+        // The parts are checked against the formats of the listener.
+        let options = ListenerOptions {
+            formats: Formats::from_value(&serde_json::json!({ "template_formats": ["path"] })).unwrap(),
+            ..ListenerOptions::default()
+        };
+        let mut exchange = exchange::Exchange::new(block);
+        let outcome = handle_record(&mut exchange, request.to_bytes(), "test", &config, options, false).await;
+        assert!(matches!(outcome, Err(IpcError::FormatNotAllowed { block: 2, .. })));
+    }
+
+    #[tokio::test]
+    async fn test_binary_noop() {
+        let payload = vec![0xff, 0x00, 0xfe];
+        let noop = Header {
+            version: 0,
+            control: CTRL_NOOP,
+            content_format_1: 0,
+            content_length_1: 0,
+            content_format_2: CONTENT_BIN,
+            content_length_2: payload.len() as u64,
+        };
+
+        let mut exchange = exchange::Exchange::new(payload.clone());
+        handle_record(&mut exchange, noop.to_bytes(), "test", &Config::default(), ListenerOptions::default(), false).await.unwrap();
+
+        let response = Header::from_bytes(&exchange.response).unwrap();
+        assert_eq!(response.control, CTRL_STATUS_OK);
+        assert_eq!(response.content_format_2, CONTENT_BIN);
+        assert_eq!(&exchange.response[HEADER_SIZE + 2..], &payload[..]);
+    }
+
+    #[tokio::test]
+    async fn test_extended_request() {
+        let ping = [extensions::EXTENDED, CTRL_NOOP, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+        let optional = [extensions::Extension { kind: 2, value: b"trace-1".to_vec() }];
+        let mut exchange = exchange::Exchange::new(extensions::tests::encode(&optional));
+        handle_record(&mut exchange, ping, "test", &Config::default(), ListenerOptions::default(), false).await.unwrap();
+        assert_eq!(exchange.response[1], CTRL_STATUS_OK);
+
+        let critical = [extensions::Extension { kind: 0x82, value: Vec::new() }];
+        let mut exchange = exchange::Exchange::new(extensions::tests::encode(&critical));
+        let outcome = handle_record(&mut exchange, ping, "test", &Config::default(), ListenerOptions::default(), false).await;
+        assert!(matches!(outcome, Err(IpcError::Extension(_))));
+        assert_eq!(exchange.response[1], CTRL_STATUS_KO);
+    }
+
+    #[tokio::test]
+    async fn test_checksummed_request() {
+        let noop = [checksum::CHECKSUMS, CTRL_NOOP, 0, 0, 0, 0, 0, CONTENT_TEXT, 0, 0, 0, 5];
+
+        let mut request = b"nonce".to_vec();
+        request.extend(checksum::trailer(b"", b"nonce"));
+        let mut exchange = exchange::Exchange::new(request);
+        handle_record(&mut exchange, noop, "test", &Config::default(), ListenerOptions::default(), false).await.unwrap();
+        let response = Header::from_bytes(&exchange.response).unwrap();
+        assert_eq!(response.version, checksum::CHECKSUMS);
+        assert_eq!(response.control, CTRL_STATUS_OK);
+        let (contents, crcs) = exchange.response[HEADER_SIZE..].split_at(exchange.response.len() - HEADER_SIZE - checksum::SIZE);
+        assert_eq!(crcs, checksum::trailer(b"{}", &contents[2..]));
+
+        let mut corrupted = b"nonse".to_vec();
+        corrupted.extend(checksum::trailer(b"", b"nonce"));
+        let mut exchange = exchange::Exchange::new(corrupted);
+        let outcome = handle_record(&mut exchange, noop, "test", &Config::default(), ListenerOptions::default(), false).await;
+        assert!(matches!(outcome, Err(IpcError::Checksum { block: 2, .. })));
+    }
+
+    #[tokio::test]
+    async fn test_compressed_request_and_response() {
+        let mut config = Config::default();
+        config.compress_min_bytes = 0;
+        let payload = compression::compress(compression::GZIP, b"nonce").unwrap();
+        let header = Header {
+            version: 0,
+            control: CTRL_NOOP,
+            content_format_1: 0,
+            content_length_1: 0,
+            content_format_2: CONTENT_TEXT + compression::GZIP,
+            content_length_2: payload.len() as u64,
+        };
+
+        let mut exchange = exchange::Exchange::new(payload);
+        handle_record(&mut exchange, header.to_bytes(), "test", &config, ListenerOptions::default(), false).await.unwrap();
+
+        let response = Header::from_bytes(&exchange.response).unwrap();
+        assert_eq!(response.control, CTRL_STATUS_OK);
+        assert_eq!(response.content_format_2, CONTENT_TEXT + compression::GZIP);
+        let content_2 = &exchange.response[HEADER_SIZE + response.content_length_1 as usize..];
+        assert_eq!(compression::decompress(2, compression::GZIP, content_2, 1024).unwrap(), b"nonce");
+    }
+
+    #[tokio::test]
+    async fn test_inline_include_needs_scope() {
+        let mut config = Config::default();
+        config.render_backend = "mock".to_string();
+        config.template_scopes = vec![(PathBuf::from("/srv/tpl/internal"), vec!["internal".to_string()])];
+        let template = b"{:include; /srv/tpl/internal/x.ntpl :}";
+        let request = Header {
+            version: 0,
+            control: CTRL_PARSE_TEMPLATE,
+            content_format_1: CONTENT_JSON,
+            content_length_1: 2,
+            content_format_2: CONTENT_TEXT,
+            content_length_2: template.len() as u64,
+        };
+        let mut record = b"{}".to_vec();
+        record.extend_from_slice(template);
+
+        // An inline template would include a scoped file unchecked.
+        let mut exchange = exchange::Exchange::new(record);
+        let outcome = handle_record(&mut exchange, request.to_bytes(), "test", &config, ListenerOptions::default(), false).await;
+        assert!(matches!(outcome, Err(IpcError::Unauthorized(_))));
+        assert_eq!(Header::from_bytes(&exchange.response).unwrap().control, CTRL_STATUS_KO);
+
+        // The HTTP and gRPC gateways have no scopes.
+        let rendered = render_template(&config, Arc::new(b"{}".to_vec()), CONTENT_JSON, String::from_utf8_lossy(template).into_owned(), CONTENT_TEXT, &[]).await;
+        assert!(matches!(rendered, Err(IpcError::Unauthorized(_))));
+
+        let inline = ["inline".to_string()];
+        assert!(render_template(&config, Arc::new(b"{}".to_vec()), CONTENT_JSON, "hello".to_string(), CONTENT_TEXT, &inline).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_chunked_response() {
+        let mut config = Config::default();
+        config.render_backend = "mock".to_string();
+        config.chunk_size = 2;
+        let request = Header {
+            version: 0,
+            control: CTRL_PARSE_TEMPLATE_CHUNKED,
+            content_format_1: CONTENT_JSON,
+            content_length_1: 2,
+            content_format_2: CONTENT_TEXT,
+            content_length_2: 5,
+        };
+
+        let mut exchange = exchange::Exchange::new(b"{}hello".to_vec());
+        handle_record(&mut exchange, request.to_bytes(), "test", &config, ListenerOptions::default(), false).await.unwrap();
+
+        let response = Header::from_bytes(&exchange.response).unwrap();
+        assert_eq!(response.control, CTRL_STATUS_OK);
+        assert_eq!(response.content_length_2, 0);
+
+        let mut chunks = &exchange.response[HEADER_SIZE + response.content_length_1 as usize..];
+        let mut output = Vec::new();
+        loop {
+            let length = u32::from_be_bytes(chunks[..4].try_into().unwrap()) as usize;
+            if length == 0 {
+                break;
+            }
+            assert!(length <= 2);
+            output.extend_from_slice(&chunks[4..4 + length]);
+            chunks = &chunks[4 + length..];
+        }
+        assert_eq!(output, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_handle_client_persistent_connection() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let mut config = Config::default();
+        config.render_backend = "mock".to_string();
+        let handler = tokio::spawn(async move { handle_client(server, "test", &config, ListenerOptions::default()).await });
+
+        let request = Header {
+            version: 0,
+            control: CTRL_PARSE_TEMPLATE,
+            content_format_1: CONTENT_JSON,
+            content_length_1: 2,
+            content_format_2: CONTENT_TEXT,
+            content_length_2: 2,
+        };
+        for _ in 0..2 {
+            client.write_all(&request.to_bytes()).await.unwrap();
+            client.write_all(b"{}Hi").await.unwrap();
+
+            let mut header_bytes = [0; HEADER_SIZE];
+            client.read_exact(&mut header_bytes).await.unwrap();
+            let response = Header::from_bytes(&header_bytes).unwrap();
+            assert_eq!(response.control, CTRL_STATUS_OK);
+            let mut contents = vec![0; (response.content_length_1 + response.content_length_2) as usize];
+            client.read_exact(&mut contents).await.unwrap();
+            assert!(contents.ends_with(b"Hi"));
+        }
+
+        // Closed between records.
+        drop(client);
+        assert!(handler.await.unwrap().is_ok());
+    }
+
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn test_serve_pipe() {
+        use tokio::net::windows::named_pipe::{ClientOptions, ServerOptions};
+
+        let name = format!(r"\\.\pipe\neutral-ipc-test-{}", std::process::id());
+        let server = ServerOptions::new().first_pipe_instance(true).create(&name).unwrap();
+        tokio::spawn(serve_pipe(server, name.clone(), Arc::new(Config::default())));
+
+        for _ in 0..2 {
+            let mut client = ClientOptions::new().open(&name).unwrap();
+            let request = Header {
+                version: 0,
+                control: 99,
+                content_format_1: 0,
+                content_length_1: 0,
+                content_format_2: 0,
+                content_length_2: 0,
+            };
+            client.write_all(&request.to_bytes()).await.unwrap();
+
+            let mut response = [0; HEADER_SIZE];
+            client.read_exact(&mut response).await.unwrap();
+            assert_eq!(response[1], CTRL_STATUS_KO);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_bind_unix_replaces_stale_socket() {
+        let path = std::env::temp_dir().join(format!("neutral-ipc-test-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        drop(bind_unix(path).unwrap());
+        // The socket file is left behind, as after a crash.
+        assert!(std::path::Path::new(path).exists());
+        drop(bind_unix(path).unwrap());
+
+        fs::remove_file(path).unwrap();
     }
 }
@@ -0,0 +1,193 @@
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::{
+    CTRL_CANCEL, CTRL_HELLO, CTRL_INFO, CTRL_KV, CTRL_NOOP, CTRL_PARSE_TEMPLATE,
+    CTRL_PARSE_TEMPLATE_CHUNKED, CTRL_PARSE_TRANSACTION, CTRL_SHUTDOWN, CTRL_STATS,
+    CTRL_TEMPLATE_SOURCE,
+};
+
+// ============================================
+// Metrics
+// ============================================
+//
+// Request counts and latency histograms broken down by control code, so slow
+// admin/stats requests don't hide regressions in the render path, and by
+// phase of a request so a slow client, a full render queue and a slow engine
+// tell apart. Returned as JSON by the stats control code (CTRL_STATS).
+
+/// From the connection to its first header.
+pub const HEADER_READ: &str = "header_read";
+/// From the header to the contents of a render request.
+pub const BODY_READ: &str = "body_read";
+/// Waiting for a render worker.
+pub const QUEUE_WAIT: &str = "queue_wait";
+/// Rendering on a worker.
+pub const RENDER: &str = "render";
+/// Writing the response.
+pub const WRITE: &str = "write";
+
+/// Upper bounds of the histogram buckets in microseconds, plus +Inf.
+const BUCKETS_US: [u64; 16] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000,
+    1_000_000, 2_500_000, 5_000_000, 10_000_000,
+];
+
+#[derive(Default)]
+struct Histogram {
+    count: u64,
+    errors: u64,
+    sum_us: u64,
+    buckets: [u64; BUCKETS_US.len() + 1],
+}
+
+impl Histogram {
+    fn record(&mut self, elapsed: Duration, ok: bool) {
+        let us = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        let index = BUCKETS_US
+            .iter()
+            .position(|&le| us <= le)
+            .unwrap_or(BUCKETS_US.len());
+
+        self.buckets[index] += 1;
+        self.count += 1;
+        self.sum_us = self.sum_us.saturating_add(us);
+        if !ok {
+            self.errors += 1;
+        }
+    }
+
+    /// Cumulative buckets, Prometheus style.
+    fn to_json(&self) -> Value {
+        let mut cumulative = 0;
+        let buckets: Vec<Value> = self
+            .buckets
+            .iter()
+            .enumerate()
+            .map(|(i, n)| {
+                cumulative += n;
+                json!({ "le_us": BUCKETS_US.get(i), "count": cumulative })
+            })
+            .collect();
+
+        json!({
+            "count": self.count,
+            "errors": self.errors,
+            "sum_us": self.sum_us,
+            "buckets": buckets
+        })
+    }
+}
+
+struct Metrics {
+    started: Instant,
+    controls: Mutex<BTreeMap<u8, Histogram>>,
+    phases: Mutex<BTreeMap<&'static str, Histogram>>,
+}
+
+fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| Metrics {
+        started: Instant::now(),
+        controls: Mutex::new(BTreeMap::new()),
+        phases: Mutex::new(BTreeMap::new()),
+    })
+}
+
+pub fn control_name(control: u8) -> &'static str {
+    match control {
+        CTRL_PARSE_TEMPLATE => "parse_template",
+        CTRL_STATS => "stats",
+        CTRL_TEMPLATE_SOURCE => "template_source",
+        CTRL_NOOP => "noop",
+        CTRL_HELLO => "hello",
+        CTRL_PARSE_TEMPLATE_CHUNKED => "parse_template_chunked",
+        CTRL_CANCEL => "cancel",
+        CTRL_INFO => "info",
+        CTRL_SHUTDOWN => "shutdown",
+        CTRL_PARSE_TRANSACTION => "parse_transaction",
+        CTRL_KV => "kv",
+        _ => "unknown",
+    }
+}
+
+/// Record a processed request.
+pub fn record(control: u8, elapsed: Duration, ok: bool) {
+    let mut controls = metrics().controls.lock().unwrap_or_else(|e| e.into_inner());
+    controls.entry(control).or_default().record(elapsed, ok);
+}
+
+/// Record the time of a phase of a request.
+pub fn record_phase(phase: &'static str, elapsed: Duration) {
+    let mut phases = metrics().phases.lock().unwrap_or_else(|e| e.into_inner());
+    phases.entry(phase).or_default().record(elapsed, true);
+}
+
+/// All metrics as a JSON document.
+pub fn snapshot() -> Value {
+    let metrics = metrics();
+    let controls = metrics.controls.lock().unwrap_or_else(|e| e.into_inner());
+    let mut by_control = Map::new();
+
+    for (control, histogram) in controls.iter() {
+        let mut entry = histogram.to_json();
+        entry["name"] = json!(control_name(*control));
+        by_control.insert(control.to_string(), entry);
+    }
+
+    let phases = metrics.phases.lock().unwrap_or_else(|e| e.into_inner());
+    let by_phase: Map<String, Value> = phases
+        .iter()
+        .map(|(phase, histogram)| (phase.to_string(), histogram.to_json()))
+        .collect();
+
+    json!({
+        "uptime_secs": metrics.started.elapsed().as_secs(),
+        "tasks_panicked": crate::tasks::panicked(),
+        "controls": by_control,
+        "phases": by_phase
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets() {
+        let mut histogram = Histogram::default();
+        histogram.record(Duration::from_micros(50), true);
+        histogram.record(Duration::from_micros(100), true);
+        histogram.record(Duration::from_millis(3), false);
+        histogram.record(Duration::from_secs(60), true);
+
+        let value = histogram.to_json();
+        assert_eq!(value["count"], 4);
+        assert_eq!(value["errors"], 1);
+        assert_eq!(value["buckets"][0]["count"], 2);
+        assert_eq!(value["buckets"][5]["le_us"], 5_000);
+        assert_eq!(value["buckets"][5]["count"], 3);
+        assert_eq!(value["buckets"][16]["le_us"], Value::Null);
+        assert_eq!(value["buckets"][16]["count"], 4);
+    }
+
+    #[test]
+    fn test_snapshot_by_control() {
+        record(CTRL_STATS, Duration::from_micros(10), true);
+        let value = snapshot();
+
+        assert_eq!(value["controls"]["20"]["name"], "stats");
+        assert!(value["controls"]["20"]["count"].as_u64().unwrap() >= 1);
+    }
+
+    #[test]
+    fn test_snapshot_by_phase() {
+        record_phase(QUEUE_WAIT, Duration::from_micros(300));
+        let value = snapshot();
+
+        assert!(value["phases"]["queue_wait"]["count"].as_u64().unwrap() >= 1);
+        assert!(value["phases"]["queue_wait"]["sum_us"].as_u64().unwrap() >= 300);
+    }
+}
@@ -0,0 +1,324 @@
+use serde_json::{Map, Value};
+use std::fs;
+use std::io;
+
+use crate::CONFIG_FILE;
+
+// ============================================
+// Config migration
+// ============================================
+//
+// `neutral-ipc migrate-config [PATH]` upgrades a config file to the current
+// format, writing a backup (PATH.bak) first and printing the changes.
+//
+// Values written as strings in old configs ("port": "4273") are converted to
+// the type the server expects now, otherwise the server ignores them and
+// uses the default. Renamed keys get their new name, and a list of hosts in
+// `host` ("127.0.0.1, ::1" or an array), which the server can't bind, becomes
+// one `listeners` entry per host.
+
+/// Type of a config key with a number or boolean value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Number,
+    Bool,
+}
+
+/// The keys `Config::new` reads as numbers or booleans, a test checks it
+/// against the parser so they don't drift apart.
+const TYPED_KEYS: &[(&str, Kind)] = &[
+    // Read as a number or a string.
+    ("port", Kind::Number),
+    ("acceptors", Kind::Number),
+    ("trace_sample_rate", Kind::Number),
+    ("trace_max_bytes", Kind::Number),
+    ("mirror_sample_rate", Kind::Number),
+    ("corpus_sample_rate", Kind::Number),
+    ("corpus_max_files", Kind::Number),
+    ("log_rotate_size", Kind::Number),
+    ("log_rotate_interval", Kind::Number),
+    ("log_retention", Kind::Number),
+    ("fs_breaker_threshold", Kind::Number),
+    ("fs_breaker_timeout_ms", Kind::Number),
+    ("fs_breaker_cooldown_secs", Kind::Number),
+    ("render_workers", Kind::Number),
+    ("render_workers_min", Kind::Number),
+    ("render_workers_max", Kind::Number),
+    ("template_source_max_bytes", Kind::Number),
+    ("read_rate_limit", Kind::Number),
+    ("write_rate_limit", Kind::Number),
+    ("http_max_body", Kind::Number),
+    ("max_connections", Kind::Number),
+    ("shutdown_grace_secs", Kind::Number),
+    ("idle_timeout_secs", Kind::Number),
+    ("connection_max_requests", Kind::Number),
+    ("connection_max_age_secs", Kind::Number),
+    ("noop_max_bytes", Kind::Number),
+    ("pipeline_max", Kind::Number),
+    ("decompress_max_bytes", Kind::Number),
+    ("compress_min_bytes", Kind::Number),
+    ("hmac_max_age_secs", Kind::Number),
+    ("template_max_concurrent", Kind::Number),
+    ("chunk_size", Kind::Number),
+    ("schema_value_max_bytes", Kind::Number),
+    ("transaction_max_templates", Kind::Number),
+    ("kv_max_bytes", Kind::Number),
+    ("trace_dump", Kind::Bool),
+    ("trace_redact", Kind::Bool),
+    ("corpus_redact", Kind::Bool),
+    ("log_compress", Kind::Bool),
+    ("strict_header", Kind::Bool),
+    ("log_response_hash", Kind::Bool),
+    ("allow_insecure_public", Kind::Bool),
+    ("render_autotune", Kind::Bool),
+    ("tcp", Kind::Bool),
+    ("dual_stack", Kind::Bool),
+    ("reuse_port", Kind::Bool),
+    ("hmac_required", Kind::Bool),
+    ("magic_required", Kind::Bool),
+];
+
+/// Keys renamed since the first release, old name first. None has been
+/// renamed yet, a renamed key is added here so old configs keep working.
+const RENAMED_KEYS: &[(&str, &str)] = &[];
+
+/// Entry point for `neutral-ipc migrate-config`.
+pub fn run(args: &[String]) -> io::Result<()> {
+    let path = args.first().map(String::as_str).unwrap_or(CONFIG_FILE);
+    let content = fs::read_to_string(path)?;
+    let mut config: Value = serde_json::from_str(&content)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, e)))?;
+
+    let changes = migrate(&mut config);
+    if changes.is_empty() {
+        println!("{} is up to date", path);
+        return Ok(());
+    }
+
+    let backup = format!("{}.bak", path);
+    fs::write(&backup, &content)?;
+    fs::write(path, serde_json::to_string_pretty(&config)? + "\n")?;
+
+    println!("Migrated {} (backup in {}):", path, backup);
+    for change in changes {
+        println!("  - {}", change);
+    }
+
+    Ok(())
+}
+
+/// Upgrade the config in place, returns a description of each change.
+pub fn migrate(config: &mut Value) -> Vec<String> {
+    let mut changes = Vec::new();
+    let Some(object) = config.as_object_mut() else {
+        return changes;
+    };
+
+    rename(object, RENAMED_KEYS, &mut changes);
+
+    for (key, kind) in TYPED_KEYS {
+        let Some(Value::String(text)) = object.get(*key) else {
+            continue;
+        };
+        let (value, kind_name) = match kind {
+            Kind::Number => (
+                text.trim()
+                    .parse::<u64>()
+                    .map(Value::from)
+                    .or_else(|_| text.trim().parse::<f64>().map(Value::from))
+                    .ok(),
+                "number",
+            ),
+            Kind::Bool => (text.trim().parse::<bool>().ok().map(Value::Bool), "boolean"),
+        };
+        if let Some(value) = value {
+            changes.push(format!(
+                "{}: string \"{}\" to {} {}",
+                key, text, kind_name, value
+            ));
+            object.insert(key.to_string(), value);
+        }
+    }
+
+    split_hosts(object, &mut changes);
+
+    changes
+}
+
+/// Move the values of renamed keys to their new name, unless the new one is
+/// already set.
+fn rename(object: &mut Map<String, Value>, renamed: &[(&str, &str)], changes: &mut Vec<String>) {
+    for (old, new) in renamed {
+        if object.contains_key(*new) {
+            continue;
+        }
+        if let Some(value) = object.remove(*old) {
+            changes.push(format!("{}: renamed to {}", old, new));
+            object.insert(new.to_string(), value);
+        }
+    }
+}
+
+/// A list of hosts in `host` as `listeners`, one per host on `port`, with
+/// the first host left in `host` for the tools that connect to it.
+fn split_hosts(object: &mut Map<String, Value>, changes: &mut Vec<String>) {
+    if object.contains_key("listeners") || object.get("tcp") == Some(&Value::Bool(false)) {
+        return;
+    }
+
+    let hosts: Vec<String> = match object.get("host") {
+        Some(Value::String(host)) if host.contains(',') => host
+            .split(',')
+            .map(|host| host.trim().to_string())
+            .collect(),
+        Some(Value::Array(hosts)) => hosts
+            .iter()
+            .filter_map(|host| host.as_str().map(|host| host.trim().to_string()))
+            .collect(),
+        _ => return,
+    };
+    let hosts: Vec<String> = hosts.into_iter().filter(|host| !host.is_empty()).collect();
+    let Some(first) = hosts.first().cloned() else {
+        return;
+    };
+
+    let port = match object.get("port") {
+        Some(Value::Number(port)) => port.to_string(),
+        Some(Value::String(port)) => port.trim().to_string(),
+        _ => "4273".to_string(),
+    };
+    let scheme = if object.contains_key("tls_cert") {
+        "tls"
+    } else {
+        "tcp"
+    };
+    let mut listeners: Vec<Value> = hosts
+        .iter()
+        .map(|host| {
+            // IPv6 literals need the brackets before the port.
+            let host = if host.contains(':') && !host.starts_with('[') {
+                format!("[{}]", host)
+            } else {
+                host.clone()
+            };
+            Value::from(format!("{}://{}:{}", scheme, host, port))
+        })
+        .collect();
+    // The listeners replace the socket too.
+    if let Some(Value::String(socket)) = object.get("socket") {
+        listeners.push(Value::from(format!("unix://{}", socket)));
+    }
+
+    changes.push(format!(
+        "host: {} hosts to listeners {}",
+        hosts.len(),
+        Value::Array(listeners.clone())
+    ));
+    object.insert("host".to_string(), Value::from(first));
+    object.insert("listeners".to_string(), Value::Array(listeners));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_migrate_string_values() {
+        let mut config = json!({
+            "host": "127.0.0.1",
+            "port": "4273",
+            "trace_sample_rate": "0.5",
+            "trace_dump": "true",
+            "log_retention": "many"
+        });
+        let changes = migrate(&mut config);
+
+        assert_eq!(changes.len(), 3);
+        assert_eq!(config["port"], 4273);
+        assert_eq!(config["trace_sample_rate"], 0.5);
+        assert_eq!(config["trace_dump"], true);
+        assert_eq!(config["log_retention"], "many");
+        assert_eq!(config["host"], "127.0.0.1");
+    }
+
+    #[test]
+    fn test_migrate_host_list() {
+        let mut config =
+            json!({ "host": "127.0.0.1, ::1", "port": "4273", "socket": "/run/nipc.sock" });
+        migrate(&mut config);
+
+        assert_eq!(config["host"], "127.0.0.1");
+        assert_eq!(
+            config["listeners"],
+            json!([
+                "tcp://127.0.0.1:4273",
+                "tcp://[::1]:4273",
+                "unix:///run/nipc.sock"
+            ])
+        );
+
+        let mut config = json!({ "host": ["0.0.0.0"], "tls_cert": "cert.pem" });
+        migrate(&mut config);
+        assert_eq!(config["listeners"], json!(["tls://0.0.0.0:4273"]));
+
+        // Listeners already set are kept.
+        let mut config = json!({ "host": "a, b", "listeners": ["tcp://c:1"] });
+        assert!(migrate(&mut config).is_empty());
+    }
+
+    #[test]
+    fn test_rename() {
+        let mut object = json!({ "old_key": 1, "kept": 2, "new_kept": 3 });
+        let object = object.as_object_mut().unwrap();
+        let mut changes = Vec::new();
+        rename(
+            object,
+            &[("old_key", "new_key"), ("kept", "new_kept")],
+            &mut changes,
+        );
+
+        assert_eq!(changes.len(), 1);
+        assert_eq!(object["new_key"], 1);
+        assert!(!object.contains_key("old_key"));
+        assert_eq!(object["kept"], 2);
+    }
+
+    #[test]
+    fn test_typed_keys_match_parser() {
+        // Every number or boolean read by Config::new, and nothing else.
+        let source = include_str!("main.rs");
+        let mut parsed = Vec::new();
+        for (accessor, kind) in [
+            (".as_u64()", Kind::Number),
+            (".as_f64()", Kind::Number),
+            (".as_bool()", Kind::Bool),
+        ] {
+            for (at, _) in source.match_indices(&format!("\"]{}", accessor)) {
+                let start = source[..at].rfind("config[\"").unwrap() + "config[\"".len();
+                parsed.push((&source[start..at], kind));
+            }
+        }
+
+        for &(key, kind) in &parsed {
+            assert!(
+                TYPED_KEYS.contains(&(key, kind)),
+                "{} is not in TYPED_KEYS",
+                key
+            );
+        }
+        for &(key, kind) in TYPED_KEYS.iter().filter(|(key, _)| *key != "port") {
+            assert!(
+                parsed.contains(&(key, kind)),
+                "{} is not read by Config::new",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn test_migrate_up_to_date() {
+        let mut config = json!({ "host": "127.0.0.1", "port": 4273 });
+        assert!(migrate(&mut config).is_empty());
+    }
+}
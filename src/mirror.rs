@@ -0,0 +1,103 @@
+use std::io;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+use crate::logger;
+use crate::sampler::Sampler;
+use crate::{Config, Header, MAGIC};
+
+// ============================================
+// Request mirroring
+// ============================================
+//
+// A fraction of the render requests is copied (fire-and-forget) to a
+// secondary daemon, the response of the secondary is read and dropped. The
+// copy is a version 0 record after the magic, whatever the version and
+// framing of the request: its request ID, signature or compression are not
+// relayed.
+// Used to load-test new hardware or validate another server implementation
+// with production traffic shapes, it never affects the primary response.
+
+const MIRROR_TIMEOUT: Duration = Duration::from_secs(10);
+
+static SAMPLER: Sampler = Sampler::new();
+
+/// Mirror the record if mirroring is enabled and the request is sampled.
+pub fn mirror(config: &Config, header: &Header, content_1: &[u8], content_2: &[u8]) {
+    let Some(address) = &config.mirror_address else {
+        return;
+    };
+
+    if SAMPLER.sample(config.mirror_sample_rate).is_none() {
+        return;
+    }
+
+    let mut record = MAGIC.to_vec();
+    record.extend(crate::corpus::record(header, content_1, content_2));
+
+    let address = address.clone();
+    tokio::spawn(async move {
+        let error = match tokio::time::timeout(MIRROR_TIMEOUT, send(&address, &record)).await {
+            Ok(Ok(())) => return,
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => "timeout".to_string(),
+        };
+        logger::debug(
+            &format!("Failed to mirror request: {}", error),
+            &[("mirror", &address)],
+        );
+    });
+}
+
+async fn send(address: &str, record: &[u8]) -> io::Result<()> {
+    let mut stream = TcpStream::connect(address).await?;
+    stream.write_all(record).await?;
+    // The secondary keeps a persistent connection open until the client
+    // closes it, the response ends at its end of the stream.
+    stream.shutdown().await?;
+    tokio::io::copy(&mut stream, &mut tokio::io::sink()).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CONTENT_JSON, CONTENT_TEXT, CTRL_PARSE_TEMPLATE, HEADER_SIZE};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_mirror_pipelined_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = Config::default();
+        config.mirror_address = Some(listener.local_addr().unwrap().to_string());
+        config.mirror_sample_rate = 1.0;
+
+        // A version 1 request is mirrored as version 0.
+        let header = Header {
+            version: crate::pipeline::VERSION,
+            control: CTRL_PARSE_TEMPLATE,
+            content_format_1: CONTENT_JSON,
+            content_length_1: 2,
+            content_format_2: CONTENT_TEXT,
+            content_length_2: 2,
+        };
+        mirror(&config, &header, b"{}", b"Hi");
+
+        let (mut secondary, _) = tokio::time::timeout(Duration::from_secs(5), listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut record = Vec::new();
+        secondary.read_to_end(&mut record).await.unwrap();
+
+        let (magic, record) = record.split_at(MAGIC.len());
+        assert_eq!(magic, MAGIC);
+        let mirrored = Header::from_bytes(record).unwrap();
+        assert_eq!(mirrored.version, 0);
+        assert_eq!(mirrored.control, CTRL_PARSE_TEMPLATE);
+        assert_eq!(&record[HEADER_SIZE..], b"{}Hi");
+    }
+}
@@ -0,0 +1,117 @@
+use crate::error::IpcError;
+
+// ============================================
+// Multipart content blocks
+// ============================================
+//
+// A block with format CONTENT_MULTIPART carries any number of typed parts,
+// for requests that need more than the two blocks of the header:
+//
+// \x00              # part count
+// \x00              # part format
+// \x00\x00\x00\x00  # part length (big endian)
+// ...               # format and length of the other parts
+// ...               # contents of the parts, one after the other
+//
+// Part formats are the content formats of the header, a multipart block can
+// be compressed as a whole but not its parts, nor nested. Parse template
+// takes the schema and the template as the first two parts of a multipart
+// content 1 with an empty content 2, later parts are reserved for request
+// metadata and skipped for now.
+
+const DESCRIPTOR_SIZE: usize = 5;
+
+#[derive(Debug, PartialEq)]
+pub struct Part {
+    pub format: u8,
+    pub content: Vec<u8>,
+}
+
+/// The parts of a multipart block.
+pub fn decode(block: &[u8]) -> Result<Vec<Part>, IpcError> {
+    let truncated = || IpcError::Multipart("truncated multipart block".to_string());
+
+    let (&count, rest) = block.split_first().ok_or_else(truncated)?;
+    let table_size = count as usize * DESCRIPTOR_SIZE;
+    let table = rest.get(..table_size).ok_or_else(truncated)?;
+    let mut contents = &rest[table_size..];
+
+    let mut parts = Vec::with_capacity(count as usize);
+    for descriptor in table.chunks(DESCRIPTOR_SIZE) {
+        let length =
+            u32::from_be_bytes([descriptor[1], descriptor[2], descriptor[3], descriptor[4]]);
+        let content = contents.get(..length as usize).ok_or_else(truncated)?;
+        parts.push(Part {
+            format: descriptor[0],
+            content: content.to_vec(),
+        });
+        contents = &contents[length as usize..];
+    }
+    if !contents.is_empty() {
+        return Err(IpcError::Multipart(format!(
+            "{} bytes after the last part",
+            contents.len()
+        )));
+    }
+
+    Ok(parts)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::{CONTENT_JSON, CONTENT_TEXT};
+
+    /// The multipart block a client sends.
+    pub fn encode(parts: &[Part]) -> Vec<u8> {
+        let mut block = vec![parts.len() as u8];
+        for part in parts {
+            block.push(part.format);
+            block.extend_from_slice(&(part.content.len() as u32).to_be_bytes());
+        }
+        for part in parts {
+            block.extend_from_slice(&part.content);
+        }
+        block
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let parts = vec![
+            Part {
+                format: CONTENT_JSON,
+                content: b"{}".to_vec(),
+            },
+            Part {
+                format: CONTENT_TEXT,
+                content: b"template".to_vec(),
+            },
+            Part {
+                format: CONTENT_JSON,
+                content: Vec::new(),
+            },
+        ];
+
+        let block = encode(&parts);
+        assert_eq!(block.len(), 1 + 3 * DESCRIPTOR_SIZE + 10);
+        assert_eq!(decode(&block).unwrap(), parts);
+        assert_eq!(decode(&[0]).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn test_invalid_blocks() {
+        assert!(matches!(decode(&[]), Err(IpcError::Multipart(_))));
+        assert!(matches!(
+            decode(&[1, 10, 0, 0]),
+            Err(IpcError::Multipart(_))
+        ));
+        assert!(matches!(
+            decode(&[1, 10, 0, 0, 0, 3, b'{', b'}']),
+            Err(IpcError::Multipart(_))
+        ));
+        assert!(matches!(
+            decode(&[1, 10, 0, 0, 0, 1, b'{', b'}']),
+            Err(IpcError::Multipart(_))
+        ));
+    }
+}
@@ -0,0 +1,106 @@
+use serde_json::Value;
+
+// ============================================
+// Rendered output normalization
+// ============================================
+//
+// Per listener options applied to the rendered text before it is sent:
+//
+// "newline": "keep" | "lf" | "crlf"
+// "bom":     "keep" | "strip" | "emit"   (UTF-8 byte order mark)
+
+const BOM: char = '\u{feff}';
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Newline {
+    #[default]
+    Keep,
+    Lf,
+    Crlf,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Bom {
+    #[default]
+    Keep,
+    Strip,
+    Emit,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct OutputOptions {
+    pub newline: Newline,
+    pub bom: Bom,
+}
+
+impl OutputOptions {
+    /// Options from a config object, unknown or missing values keep the output as is.
+    pub fn from_value(value: &Value) -> Self {
+        let newline = match value["newline"].as_str() {
+            Some("lf") => Newline::Lf,
+            Some("crlf") => Newline::Crlf,
+            _ => Newline::Keep,
+        };
+        let bom = match value["bom"].as_str() {
+            Some("strip") => Bom::Strip,
+            Some("emit") => Bom::Emit,
+            _ => Bom::Keep,
+        };
+
+        OutputOptions { newline, bom }
+    }
+
+    pub fn apply(&self, text: String) -> String {
+        let text = match self.newline {
+            Newline::Keep => text,
+            Newline::Lf => text.replace("\r\n", "\n"),
+            Newline::Crlf => text.replace("\r\n", "\n").replace('\n', "\r\n"),
+        };
+
+        match self.bom {
+            Bom::Keep => text,
+            Bom::Strip => match text.strip_prefix(BOM) {
+                Some(stripped) => stripped.to_string(),
+                None => text,
+            },
+            Bom::Emit if text.starts_with(BOM) => text,
+            Bom::Emit => format!("{}{}", BOM, text),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_from_value() {
+        let options = OutputOptions::from_value(&json!({ "newline": "crlf", "bom": "strip" }));
+        assert_eq!(options.newline, Newline::Crlf);
+        assert_eq!(options.bom, Bom::Strip);
+
+        let options = OutputOptions::from_value(&Value::Null);
+        assert_eq!(options, OutputOptions::default());
+    }
+
+    #[test]
+    fn test_apply() {
+        let text = "\u{feff}a\r\nb\nc".to_string();
+
+        let crlf = OutputOptions {
+            newline: Newline::Crlf,
+            bom: Bom::Strip,
+        };
+        assert_eq!(crlf.apply(text.clone()), "a\r\nb\r\nc");
+
+        let lf = OutputOptions {
+            newline: Newline::Lf,
+            bom: Bom::Emit,
+        };
+        assert_eq!(lf.apply(text.clone()), "\u{feff}a\nb\nc");
+        assert_eq!(lf.apply("x".to_string()), "\u{feff}x");
+
+        assert_eq!(OutputOptions::default().apply(text.clone()), text);
+    }
+}
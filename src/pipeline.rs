@@ -0,0 +1,574 @@
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use std::collections::HashMap;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot, Mutex};
+
+use crate::error::IpcError;
+use crate::exchange::Exchange;
+use crate::logger;
+use crate::{Config, Header, ListenerOptions, ParseTemplateResult, CTRL_CANCEL, HEADER_SIZE};
+
+// ============================================
+// Pipelined records (protocol version 1)
+// ============================================
+//
+// Version 1 records have a 4 byte request ID (big endian) after the header,
+// the response carries the ID of its request (also after the header, with
+// version 1). Once a connection sends a version 1 record all its records
+// must be version 1: they are read as they arrive and handled concurrently,
+// up to `pipeline_max` per connection, and the responses are written as they
+// are ready, in any order. A record over a limit known from its header
+// (`noop_max_bytes`, the hello and kv limits) is answered with the error
+// without reading its contents, and the connection is closed after it.
+//
+// A cancel record (control 70) carries the ID of an earlier request of the
+// connection: if it is still waiting or rendering it is answered at once
+// with a cancelled error. A render already running can't be stopped, it
+// keeps its worker and its template slot (`template_max_concurrent`) until
+// it ends and its output is dropped. The cancel record itself has no
+// response.
+
+pub const VERSION: u8 = 1;
+const ID_SIZE: usize = 4;
+
+struct Record {
+    header_bytes: [u8; HEADER_SIZE],
+    id: [u8; ID_SIZE],
+    contents: Vec<u8>,
+    /// Last record read before the connection is recycled.
+    closing: bool,
+    /// Over a limit known from the header, its contents were not read.
+    refused: Option<IpcError>,
+}
+
+/// Requests of the connection that can still be cancelled.
+type Cancels = std::sync::Mutex<HashMap<[u8; ID_SIZE], oneshot::Sender<()>>>;
+
+pub async fn handle<S>(
+    stream: S,
+    first: [u8; HEADER_SIZE],
+    peer: &str,
+    config: &Config,
+    options: ListenerOptions,
+) -> Result<(), IpcError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut reader, writer) = tokio::io::split(stream);
+    let writer = Mutex::new(writer);
+    let max = config.pipeline_max.max(1);
+    let (records, mut queue) = mpsc::channel(max);
+    let cancels = Cancels::default();
+    let cancels = &cancels;
+
+    // Reading goes on while records are handled, a read is never cancelled
+    // half way through a record.
+    let reading = async move {
+        let opened = std::time::Instant::now();
+        let mut read = 0;
+        let mut header_bytes = first;
+        loop {
+            let version =
+                header_bytes[0] & !(crate::extensions::EXTENDED | crate::checksum::CHECKSUMS);
+            if version != VERSION {
+                return Err(IpcError::StrictHeader(format!(
+                    "version {} record on a pipelined (version {}) connection",
+                    version, VERSION
+                )));
+            }
+
+            let mut record = read_record(&mut reader, header_bytes, config).await?;
+            // Recycled: the records read are still answered, the response to
+            // the last one is flagged. A refused record is the last one, its
+            // contents are left unread.
+            read += 1;
+            record.closing = record.refused.is_some() || crate::recycle(config, read, opened);
+            let closing = record.closing;
+            if record.header_bytes[1] == CTRL_CANCEL {
+                cancel(cancels, record.id);
+            } else {
+                let cancelled = register(cancels, record.id);
+                if records.send((record, cancelled)).await.is_err() {
+                    return Ok(());
+                }
+            }
+
+            if closing {
+                return Ok(());
+            }
+
+            match crate::next_header(&mut reader, peer, config).await? {
+                Some(next) => header_bytes = next,
+                None => return Ok(()),
+            }
+        }
+    };
+
+    let handling = async {
+        let mut in_flight = FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                record = queue.recv(), if in_flight.len() < max => match record {
+                    Some((record, cancelled)) => {
+                        in_flight.push(respond(record, cancelled, &writer, peer, config, options))
+                    }
+                    None => break,
+                },
+                Some(()) = in_flight.next() => {}
+            }
+        }
+        while in_flight.next().await.is_some() {}
+    };
+
+    let (read, ()) = tokio::join!(reading, handling);
+    read
+}
+
+/// Make a request cancellable, the receiver fires if it is cancelled.
+fn register(cancels: &Cancels, id: [u8; ID_SIZE]) -> oneshot::Receiver<()> {
+    let mut cancels = cancels.lock().unwrap_or_else(|e| e.into_inner());
+    // Forget the requests already answered.
+    cancels.retain(|_, cancel| !cancel.is_closed());
+
+    let (cancel, cancelled) = oneshot::channel();
+    cancels.insert(id, cancel);
+    cancelled
+}
+
+fn cancel(cancels: &Cancels, id: [u8; ID_SIZE]) {
+    let mut cancels = cancels.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(cancel) = cancels.remove(&id) {
+        let _ = cancel.send(());
+    }
+}
+
+async fn read_record<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    header_bytes: [u8; HEADER_SIZE],
+    config: &Config,
+) -> Result<Record, IpcError> {
+    let mut header = Header::from_bytes(&header_bytes).ok_or(IpcError::InvalidHeader)?;
+    let extended_lengths = header.read_extended_lengths(reader).await?;
+
+    let mut id = [0; ID_SIZE];
+    reader.read_exact(&mut id).await?;
+
+    // The limits a record handled at once checks before reading its blocks.
+    header.control &= !crate::signature::SIGNED;
+    if let Err(e) = crate::check_lengths(&header, config) {
+        return Ok(Record {
+            header_bytes,
+            id,
+            contents: Vec::new(),
+            closing: true,
+            refused: Some(e),
+        });
+    }
+
+    // The extended lengths, as sent, the request ID and the extension area
+    // go with the contents, read again when handled.
+    let mut contents = header.extended_lengths_as(extended_lengths);
+    contents.extend(id);
+    if crate::extensions::is_extended(header_bytes[0]) {
+        contents.extend(crate::extensions::read_area(reader).await?);
+    }
+
+    contents.extend(crate::read_content(reader, header.content_length_1 as usize).await?);
+    contents.extend(crate::read_content(reader, header.content_length_2 as usize).await?);
+    if crate::signature::is_signed(header_bytes[1]) {
+        contents.extend(crate::signature::read_trailer(reader).await?);
+    }
+    if crate::checksum::is_checksummed(header_bytes[0]) {
+        let mut crcs = [0; crate::checksum::SIZE];
+        reader.read_exact(&mut crcs).await?;
+        contents.extend(crcs);
+    }
+
+    Ok(Record {
+        header_bytes,
+        id,
+        contents,
+        closing: false,
+        refused: None,
+    })
+}
+
+/// Handle a record with its contents in memory and write the response.
+async fn respond<W: AsyncWrite + Unpin>(
+    record: Record,
+    cancelled: oneshot::Receiver<()>,
+    writer: &Mutex<W>,
+    peer: &str,
+    config: &Config,
+    options: ListenerOptions,
+) {
+    // A dropped sender is not a cancel, the request can't be cancelled anymore.
+    let cancelled = async {
+        if cancelled.await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    };
+
+    let mut exchange = Exchange::new(record.contents);
+    // Errors answered here, handle_record answers its own.
+    let unanswered = match record.refused {
+        Some(e) => {
+            logger::error(
+                &format!("Failed to handle client: {}", e),
+                &[("peer", peer), ("class", e.class().as_str())],
+            );
+            Some(e)
+        }
+        None => {
+            let outcome = tokio::select! {
+                biased;
+                () = cancelled => Err(IpcError::Cancelled),
+                outcome = crate::handle_record(
+                    &mut exchange,
+                    record.header_bytes,
+                    peer,
+                    config,
+                    options,
+                    record.closing,
+                ) => outcome,
+            };
+            match outcome {
+                Err(IpcError::Cancelled) => {
+                    logger::info("Request cancelled", &[("peer", peer)]);
+                    Some(IpcError::Cancelled)
+                }
+                Err(e) => {
+                    logger::error(
+                        &format!("Failed to handle client: {}", e),
+                        &[("peer", peer), ("class", e.class().as_str())],
+                    );
+                    None
+                }
+                Ok(()) => None,
+            }
+        }
+    };
+
+    if let Some(e) = unanswered {
+        exchange = Exchange::new(Vec::new());
+        let json = e.to_json();
+        let error = ParseTemplateResult {
+            json: if record.closing {
+                crate::closing_json(json)
+            } else {
+                json
+            },
+            text: String::new(),
+            status: e.control(),
+            binary: None,
+        };
+        if let Ok(sent) = crate::write_response(
+            &mut exchange,
+            &error,
+            crate::compression::NONE,
+            crate::checksum::is_checksummed(record.header_bytes[0]),
+            config,
+            None,
+        )
+        .await
+        {
+            crate::log_sent(sent, peer, Some(u32::from_be_bytes(record.id)), None);
+        }
+    }
+
+    let Some(response) = with_id(exchange.response, record.id) else {
+        return;
+    };
+
+    let mut writer = writer.lock().await;
+    if let Err(e) = async {
+        writer.write_all(&response).await?;
+        writer.flush().await
+    }
+    .await
+    {
+        logger::debug(
+            &format!("Failed to send response: {}", e),
+            &[("peer", peer)],
+        );
+    }
+}
+
+/// A version 0 response record as version 1, with the request ID after the
+/// header and its extended lengths.
+fn with_id(mut response: Vec<u8>, id: [u8; ID_SIZE]) -> Option<Vec<u8>> {
+    let header = Header::from_bytes(&response)?;
+    let at = HEADER_SIZE + header.extended_lengths().len();
+    if response.len() < at {
+        return None;
+    }
+
+    response[0] = VERSION | response[0] & crate::checksum::CHECKSUMS;
+    response.splice(at..at, id);
+
+    Some(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        CONTENT_JSON, CONTENT_PATH, CONTENT_TEXT, CTRL_PARSE_TEMPLATE, CTRL_STATUS_KO,
+        CTRL_STATUS_OK,
+    };
+
+    #[test]
+    fn test_with_id() {
+        let response = with_id(vec![0; HEADER_SIZE + 2], [0, 0, 0, 7]).unwrap();
+        assert_eq!(response.len(), HEADER_SIZE + ID_SIZE + 2);
+        assert_eq!(response[0], VERSION);
+        assert_eq!(&response[HEADER_SIZE..HEADER_SIZE + ID_SIZE], &[0, 0, 0, 7]);
+
+        assert!(with_id(Vec::new(), [0; ID_SIZE]).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_requests() {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let mut config = Config::default();
+        config.render_backend = "mock".to_string();
+
+        let mut requests = Vec::new();
+        for id in 1..=3u32 {
+            let template = format!("T{}", id);
+            let header = Header {
+                version: VERSION,
+                control: CTRL_PARSE_TEMPLATE,
+                content_format_1: CONTENT_JSON,
+                content_length_1: 2,
+                content_format_2: CONTENT_TEXT,
+                content_length_2: template.len() as u64,
+            };
+            requests.extend_from_slice(&header.to_bytes());
+            requests.extend_from_slice(&id.to_be_bytes());
+            requests.extend_from_slice(b"{}");
+            requests.extend_from_slice(template.as_bytes());
+        }
+        client.write_all(&requests).await.unwrap();
+
+        let handler = tokio::spawn(async move {
+            let mut server = server;
+            let mut first = [0; HEADER_SIZE];
+            server.read_exact(&mut first).await.unwrap();
+            handle(server, first, "test", &config, ListenerOptions::default()).await
+        });
+
+        // Every response has the ID of its request, in any order.
+        let mut header_bytes = [0; HEADER_SIZE];
+        let mut seen = Vec::new();
+        for _ in 0..3 {
+            client.read_exact(&mut header_bytes).await.unwrap();
+            let response = Header::from_bytes(&header_bytes).unwrap();
+            assert_eq!(response.version, VERSION);
+            assert_eq!(response.control, CTRL_STATUS_OK);
+
+            let mut id = [0; ID_SIZE];
+            client.read_exact(&mut id).await.unwrap();
+            let id = u32::from_be_bytes(id);
+            let mut contents =
+                vec![0; (response.content_length_1 + response.content_length_2) as usize];
+            client.read_exact(&mut contents).await.unwrap();
+            assert!(contents.ends_with(format!("T{}", id).as_bytes()));
+            seen.push(id);
+        }
+        seen.sort();
+        assert_eq!(seen, vec![1, 2, 3]);
+
+        drop(client);
+        assert!(handler.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_small_extended_length() {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let mut config = Config::default();
+        config.render_backend = "mock".to_string();
+
+        // Content-length 1 is sent extended although it fits in 4 bytes.
+        let mut record = vec![VERSION, CTRL_PARSE_TEMPLATE, CONTENT_JSON];
+        record.extend_from_slice(&u32::MAX.to_be_bytes());
+        record.push(CONTENT_TEXT);
+        record.extend_from_slice(&5u32.to_be_bytes());
+        record.extend_from_slice(&2u64.to_be_bytes());
+        record.extend_from_slice(&9u32.to_be_bytes());
+        record.extend_from_slice(b"{}hello");
+        client.write_all(&record).await.unwrap();
+
+        let handler = tokio::spawn(async move {
+            let mut server = server;
+            let mut first = [0; HEADER_SIZE];
+            server.read_exact(&mut first).await.unwrap();
+            handle(server, first, "test", &config, ListenerOptions::default()).await
+        });
+
+        let mut header_bytes = [0; HEADER_SIZE];
+        client.read_exact(&mut header_bytes).await.unwrap();
+        let response = Header::from_bytes(&header_bytes).unwrap();
+        assert_eq!(response.control, CTRL_STATUS_OK);
+        let mut id = [0; ID_SIZE];
+        client.read_exact(&mut id).await.unwrap();
+        assert_eq!(u32::from_be_bytes(id), 9);
+        let mut contents =
+            vec![0; (response.content_length_1 + response.content_length_2) as usize];
+        client.read_exact(&mut contents).await.unwrap();
+        assert!(contents.ends_with(b"hello"));
+
+        drop(client);
+        assert!(handler.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_refuse_oversized_record_unread() {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let mut config = Config::default();
+        config.noop_max_bytes = 8;
+
+        // A 1 TiB noop payload, announced and never sent.
+        let noop = Header {
+            version: VERSION,
+            control: crate::CTRL_NOOP,
+            content_format_1: 0,
+            content_length_1: 0,
+            content_format_2: 0,
+            content_length_2: 1 << 40,
+        };
+        client
+            .write_all(&noop.to_bytes_as([false, true]))
+            .await
+            .unwrap();
+        client.write_all(&(1u64 << 40).to_be_bytes()).await.unwrap();
+        client.write_all(&5u32.to_be_bytes()).await.unwrap();
+
+        let mut server = server;
+        let mut first = [0; HEADER_SIZE];
+        server.read_exact(&mut first).await.unwrap();
+        handle(server, first, "test", &config, ListenerOptions::default())
+            .await
+            .unwrap();
+
+        let mut header_bytes = [0; HEADER_SIZE];
+        client.read_exact(&mut header_bytes).await.unwrap();
+        let response = Header::from_bytes(&header_bytes).unwrap();
+        assert_eq!(response.control, CTRL_STATUS_KO);
+        let mut id = [0; ID_SIZE];
+        client.read_exact(&mut id).await.unwrap();
+        assert_eq!(u32::from_be_bytes(id), 5);
+        let mut contents = vec![0; response.content_length_1 as usize];
+        client.read_exact(&mut contents).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&contents).unwrap();
+        assert_eq!(json["closing"], true);
+    }
+
+    #[tokio::test]
+    async fn test_recycled_connection() {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let mut config = Config::default();
+        config.connection_max_requests = 2;
+
+        let noop = Header {
+            version: VERSION,
+            control: crate::CTRL_NOOP,
+            content_format_1: 0,
+            content_length_1: 0,
+            content_format_2: 0,
+            content_length_2: 0,
+        };
+        for id in 1..=3u32 {
+            client.write_all(&noop.to_bytes()).await.unwrap();
+            client.write_all(&id.to_be_bytes()).await.unwrap();
+        }
+
+        let mut server = server;
+        let mut first = [0; HEADER_SIZE];
+        server.read_exact(&mut first).await.unwrap();
+        handle(server, first, "test", &config, ListenerOptions::default())
+            .await
+            .unwrap();
+
+        // Record 3 is not answered, the response to record 2 says so.
+        let mut closing = Vec::new();
+        for _ in 0..2 {
+            let mut header_bytes = [0; HEADER_SIZE];
+            client.read_exact(&mut header_bytes).await.unwrap();
+            let response = Header::from_bytes(&header_bytes).unwrap();
+            let mut id = [0; ID_SIZE];
+            client.read_exact(&mut id).await.unwrap();
+            let mut contents =
+                vec![0; (response.content_length_1 + response.content_length_2) as usize];
+            client.read_exact(&mut contents).await.unwrap();
+            if contents.starts_with(br#"{"closing":true}"#) {
+                closing.push(u32::from_be_bytes(id));
+            }
+        }
+        assert_eq!(closing, vec![2]);
+
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_request() {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let mut config = Config::default();
+        config.render_backend = "mock".to_string();
+        config.template_max_concurrent = 1;
+
+        // The template is busy, the request waits until it is cancelled.
+        let path = "/pipeline/cancel.ntpl";
+        let busy = crate::concurrency::acquire(path, &config).await.unwrap();
+
+        let request = Header {
+            version: VERSION,
+            control: CTRL_PARSE_TEMPLATE,
+            content_format_1: CONTENT_JSON,
+            content_length_1: 2,
+            content_format_2: CONTENT_PATH,
+            content_length_2: path.len() as u64,
+        };
+        let cancel = Header {
+            version: VERSION,
+            control: CTRL_CANCEL,
+            content_format_1: 0,
+            content_length_1: 0,
+            content_format_2: 0,
+            content_length_2: 0,
+        };
+        let mut records = request.to_bytes().to_vec();
+        records.extend_from_slice(&5u32.to_be_bytes());
+        records.extend_from_slice(b"{}");
+        records.extend_from_slice(path.as_bytes());
+        records.extend_from_slice(&cancel.to_bytes());
+        records.extend_from_slice(&5u32.to_be_bytes());
+
+        let handler = tokio::spawn(async move {
+            let mut server = server;
+            let mut first = [0; HEADER_SIZE];
+            server.read_exact(&mut first).await.unwrap();
+            handle(server, first, "test", &config, ListenerOptions::default()).await
+        });
+        client.write_all(&records).await.unwrap();
+
+        let mut header_bytes = [0; HEADER_SIZE];
+        client.read_exact(&mut header_bytes).await.unwrap();
+        let response = Header::from_bytes(&header_bytes).unwrap();
+        assert_eq!(response.control, CTRL_STATUS_KO);
+
+        let mut id = [0; ID_SIZE];
+        client.read_exact(&mut id).await.unwrap();
+        assert_eq!(u32::from_be_bytes(id), 5);
+        let mut contents =
+            vec![0; (response.content_length_1 + response.content_length_2) as usize];
+        client.read_exact(&mut contents).await.unwrap();
+        assert!(String::from_utf8_lossy(&contents).contains("cancelled"));
+
+        drop(busy);
+        drop(client);
+        assert!(handler.await.unwrap().is_ok());
+    }
+}
@@ -0,0 +1,91 @@
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::Endpoint;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+
+use crate::error::IpcError;
+use crate::logger;
+use crate::Config;
+
+// ============================================
+// QUIC transport (experimental)
+// ============================================
+//
+// Each bidirectional stream carries one request record and its response,
+// the client opens a stream per request and can have several in flight on
+// one connection. QUIC is always encrypted, the certificate is `tls_cert`
+// and `tls_key`, the ALPN protocol is "neutral-ipc".
+
+pub const ALPN: &[u8] = b"neutral-ipc";
+
+pub fn bind(address: SocketAddr, config: &Config) -> Result<Endpoint, IpcError> {
+    let (Some(cert), Some(key)) = (&config.tls_cert, &config.tls_key) else {
+        return Err(IpcError::Config(
+            "quic needs tls_cert and tls_key".to_string(),
+        ));
+    };
+
+    let mut tls = crate::tls::server_config(cert, key)?;
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    let crypto =
+        QuicServerConfig::try_from(tls).map_err(|e| IpcError::Config(format!("QUIC: {}", e)))?;
+
+    Ok(Endpoint::server(
+        quinn::ServerConfig::with_crypto(Arc::new(crypto)),
+        address,
+    )?)
+}
+
+pub async fn serve(endpoint: Endpoint, config: Arc<Config>) {
+    while let Some(incoming) = endpoint.accept().await {
+        let config = Arc::clone(&config);
+        let peer = incoming.remote_address().to_string();
+        crate::tasks::spawn(peer.clone(), async move {
+            let connection = match incoming.await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    logger::warning(&format!("QUIC handshake failed: {}", e), &[("peer", &peer)]);
+                    return;
+                }
+            };
+
+            loop {
+                let (send, recv) = match connection.accept_bi().await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        logger::debug(
+                            &format!("QUIC connection closed: {}", e),
+                            &[("peer", &peer)],
+                        );
+                        return;
+                    }
+                };
+
+                let config = Arc::clone(&config);
+                let peer = peer.clone();
+                crate::tasks::spawn(peer.clone(), async move {
+                    let mut stream = tokio::io::join(recv, send);
+                    crate::run_client(&mut stream, &peer, &config, config.listener_options()).await;
+                    // Finish the send side, the client reads to the end.
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bind_needs_certificate() {
+        let address = "127.0.0.1:0".parse().unwrap();
+
+        assert!(matches!(
+            bind(address, &Config::default()),
+            Err(IpcError::Config(_))
+        ));
+    }
+}
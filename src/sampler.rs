@@ -0,0 +1,44 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Deterministic sampler selecting a fraction of the calls evenly spread,
+/// e.g. with a rate of 0.25 one call in four.
+pub struct Sampler {
+    sequence: AtomicU64,
+}
+
+impl Sampler {
+    pub const fn new() -> Self {
+        Sampler {
+            sequence: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the sequence number of the call if it has been selected,
+    /// `rate` goes from 0.0 (none) to 1.0 (all).
+    pub fn sample(&self, rate: f64) -> Option<u64> {
+        let rate = rate.clamp(0.0, 1.0);
+        let n = self.sequence.fetch_add(1, Ordering::Relaxed) + 1;
+
+        if (n as f64 * rate).floor() > ((n - 1) as f64 * rate).floor() {
+            Some(n)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_rate() {
+        let sampler = Sampler::new();
+        let sampled = (0..100).filter(|_| sampler.sample(0.25).is_some()).count();
+        assert_eq!(sampled, 25);
+
+        let sampler = Sampler::new();
+        assert!((0..10).all(|_| sampler.sample(1.0).is_some()));
+        assert!((0..10).all(|_| sampler.sample(0.0).is_none()));
+    }
+}
@@ -0,0 +1,323 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::IpcError;
+use crate::logger;
+use crate::Config;
+
+// ============================================
+// Template scopes
+// ============================================
+//
+// `template_scopes` tags template files, or directories, with the scopes
+// needed to render them or read their source, and `key_scopes` gives scopes
+// to the HMAC keys of signed requests:
+//
+// "template_scopes": { "internal/": ["internal"], "admin/users.ntpl": ["admin"] }
+// "key_scopes": { "backoffice": ["internal", "admin"] }
+//
+// Entries are relative to `templates_root` (the working directory without
+// it) and canonicalized at load. A template is canonicalized too, after
+// alias resolution, as the file that is rendered or read: `./`, `..`, double
+// slashes, symlinks or an absolute path name the same file and match the
+// same entries. A template needs the scopes of every entry it is or is
+// under, so one daemon can hold public and internal templates. Unsigned
+// requests, and the HTTP and gRPC transports, have no scopes.
+//
+// An inline template can include any file, so while `template_scopes` is
+// set it needs the INLINE scope. The files a template file includes are not
+// checked, its author picks them: keep internal templates out of the
+// directories public ones include.
+
+/// The scope of inline templates while any template is scoped.
+pub const INLINE: &str = "inline";
+
+/// A config object of scope lists by name.
+pub fn parse(value: &Value) -> HashMap<String, Vec<String>> {
+    value
+        .as_object()
+        .map(|entries| {
+            entries
+                .iter()
+                .map(|(name, scopes)| {
+                    let scopes = scopes
+                        .as_array()
+                        .map(|scopes| {
+                            scopes
+                                .iter()
+                                .filter_map(|scope| scope.as_str().map(String::from))
+                                .collect()
+                        })
+                        .unwrap_or_default();
+                    (name.clone(), scopes)
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// `template_scopes` with the canonical paths of its entries.
+pub fn parse_templates(value: &Value, root: Option<&str>) -> Vec<(PathBuf, Vec<String>)> {
+    let base = match root {
+        Some(root) => PathBuf::from(root),
+        None => std::env::current_dir().unwrap_or_default(),
+    };
+    let base = std::fs::canonicalize(&base).unwrap_or(base);
+
+    parse(value)
+        .into_iter()
+        .map(|(path, scopes)| {
+            let joined = normalize(&base.join(&path));
+            let canonical = std::fs::canonicalize(&joined).unwrap_or_else(|_| {
+                logger::warning(
+                    "Template scope path not found",
+                    &[("path", &joined.display().to_string())],
+                );
+                joined
+            });
+            (canonical, scopes)
+        })
+        .collect()
+}
+
+/// The scopes of a key, none for unsigned requests.
+pub fn granted<'a>(key_id: Option<&str>, config: &'a Config) -> &'a [String] {
+    key_id
+        .and_then(|key_id| config.key_scopes.get(key_id))
+        .map_or(&[], Vec::as_slice)
+}
+
+/// Reject a template file whose required scopes are not all granted. A
+/// relative `template` is the file relative to the working directory, as the
+/// engine opens it.
+pub async fn check(template: &str, granted: &[String], config: &Config) -> Result<(), IpcError> {
+    if config.template_scopes.is_empty() {
+        return Ok(());
+    }
+
+    let file = match tokio::fs::canonicalize(template).await {
+        Ok(file) => file,
+        Err(_) => absolute(Path::new(template)),
+    };
+    let required = config
+        .template_scopes
+        .iter()
+        .filter(|(path, _)| file.starts_with(path))
+        .flat_map(|(_, scopes)| scopes);
+
+    for scope in required {
+        if !granted.contains(scope) {
+            return Err(IpcError::Unauthorized(format!(
+                "template '{}' requires scope '{}'",
+                template, scope
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Reject an inline template without the INLINE scope while
+/// `template_scopes` is set.
+pub fn check_inline(granted: &[String], config: &Config) -> Result<(), IpcError> {
+    if config.template_scopes.is_empty() || granted.iter().any(|scope| scope == INLINE) {
+        return Ok(());
+    }
+
+    Err(IpcError::Unauthorized(format!(
+        "inline templates require scope '{}'",
+        INLINE
+    )))
+}
+
+/// The canonical path of a file, only normalized if it doesn't exist. A
+/// relative `path` is relative to the working directory.
+pub fn canonical(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| absolute(path))
+}
+
+/// `path` made absolute from the working directory and normalized.
+fn absolute(path: &Path) -> PathBuf {
+    let cwd = std::env::current_dir().unwrap_or_default();
+    normalize(&cwd.join(path))
+}
+
+/// `path` without `.` and `..` components, symlinks are not resolved.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+    use serde_json::json;
+    use std::fs;
+
+    fn config(name: &str) -> (Config, PathBuf) {
+        let root = temp_dir(name);
+        fs::create_dir_all(root.join("internal")).unwrap();
+        fs::create_dir_all(root.join("public")).unwrap();
+        for file in [
+            "internal/report.ntpl",
+            "internal/admin.ntpl",
+            "public/index.ntpl",
+            "internal-other.ntpl",
+        ] {
+            fs::write(root.join(file), "").unwrap();
+        }
+
+        let mut config = Config::default();
+        config.templates_root = Some(root.to_string_lossy().into_owned());
+        config.template_scopes = parse_templates(
+            &json!({
+                "internal/": ["internal"],
+                "./internal//admin.ntpl": ["admin"]
+            }),
+            config.templates_root.as_deref(),
+        );
+        config.key_scopes = parse(&json!({
+            "backoffice": ["internal"],
+            "root": ["internal", "admin"],
+            "editor": ["inline"]
+        }));
+        (config, root)
+    }
+
+    fn path(root: &Path, template: &str) -> String {
+        format!("{}/{}", root.display(), template)
+    }
+
+    #[tokio::test]
+    async fn test_check_scopes() {
+        let (config, root) = config("scopes");
+        let backoffice = granted(Some("backoffice"), &config);
+        let root_key = granted(Some("root"), &config);
+
+        assert!(check(&path(&root, "public/index.ntpl"), &[], &config)
+            .await
+            .is_ok());
+        assert!(
+            check(&path(&root, "internal/report.ntpl"), backoffice, &config)
+                .await
+                .is_ok()
+        );
+        assert!(
+            check(&path(&root, "internal/admin.ntpl"), root_key, &config)
+                .await
+                .is_ok()
+        );
+        assert!(check(&path(&root, "internal-other.ntpl"), &[], &config)
+            .await
+            .is_ok());
+
+        assert!(matches!(
+            check(&path(&root, "internal/report.ntpl"), &[], &config).await,
+            Err(IpcError::Unauthorized(_))
+        ));
+        assert!(matches!(
+            check(&path(&root, "internal/admin.ntpl"), backoffice, &config).await,
+            Err(IpcError::Unauthorized(_))
+        ));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_check_equivalent_paths() {
+        let (config, root) = config("scopes-paths");
+        let backoffice = granted(Some("backoffice"), &config);
+
+        // Every form names internal/admin.ntpl, a missing file is normalized.
+        for template in [
+            "./internal/admin.ntpl",
+            "public/../internal/admin.ntpl",
+            "internal//admin.ntpl",
+            "public/../internal/missing/../admin.ntpl",
+            "internal/missing.ntpl",
+        ] {
+            assert!(
+                matches!(
+                    check(&path(&root, template), &[], &config).await,
+                    Err(IpcError::Unauthorized(_))
+                ),
+                "{}",
+                template
+            );
+        }
+        assert!(matches!(
+            check(
+                &path(&root, "public/../internal//admin.ntpl"),
+                backoffice,
+                &config
+            )
+            .await,
+            Err(IpcError::Unauthorized(_))
+        ));
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(
+                root.join("internal/admin.ntpl"),
+                root.join("public/link.ntpl"),
+            )
+            .unwrap();
+            assert!(matches!(
+                check(&path(&root, "public/link.ntpl"), &[], &config).await,
+                Err(IpcError::Unauthorized(_))
+            ));
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_check_inline() {
+        let (mut config, root) = config("scopes-inline");
+        assert!(check_inline(granted(Some("editor"), &config), &config).is_ok());
+        assert!(matches!(
+            check_inline(granted(Some("root"), &config), &config),
+            Err(IpcError::Unauthorized(_))
+        ));
+        assert!(matches!(
+            check_inline(&[], &config),
+            Err(IpcError::Unauthorized(_))
+        ));
+
+        config.template_scopes.clear();
+        assert!(check_inline(&[], &config).is_ok());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_granted() {
+        let (config, root) = config("scopes-granted");
+        assert!(granted(None, &config).is_empty());
+        assert!(granted(Some("unknown"), &config).is_empty());
+        assert_eq!(granted(Some("root"), &config), ["internal", "admin"]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(
+            normalize(Path::new("/srv/./a/../b//c.ntpl")),
+            Path::new("/srv/b/c.ntpl")
+        );
+        assert_eq!(
+            normalize(Path::new("/srv/../../c.ntpl")),
+            Path::new("/c.ntpl")
+        );
+    }
+}
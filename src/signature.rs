@@ -0,0 +1,468 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::error::IpcError;
+use crate::{Config, Framing, Header};
+
+// ============================================
+// Signed requests
+// ============================================
+//
+// A request with the SIGNED bit set in the control byte has a trailer after
+// content 2:
+//
+// \x00              # key ID length
+// ...               # key ID, a key of `hmac_keys`
+// ... (32 bytes)    # HMAC-SHA256 of the header, the extended lengths and
+//                   # the request ID (version 1) as sent, the extension
+//                   # area, content 1, content 2 and the key ID
+//
+// The extension area must have a TIMESTAMP extension, the time the request
+// was signed at, within `hmac_max_age_secs` of the server clock either way:
+// a captured request is refused once the window has passed. The MACs
+// verified are remembered until their window has passed too, a request
+// replayed inside it is refused as well. The contents are read and
+// verified before the request is handled. With `hmac_required` unsigned
+// requests are rejected. It authenticates the client and protects the
+// integrity of the record, not its confidentiality.
+
+pub const SIGNED: u8 = 0x80;
+const MAC_SIZE: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The MACs verified, with the time they can be forgotten in order.
+#[derive(Default)]
+struct Seen {
+    macs: HashSet<Vec<u8>>,
+    expiry: VecDeque<(u64, Vec<u8>)>,
+}
+
+static SEEN: OnceLock<Mutex<Seen>> = OnceLock::new();
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+pub fn is_signed(control: u8) -> bool {
+    control & SIGNED != 0
+}
+
+/// Read the trailer of a signed record as it is.
+pub async fn read_trailer<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Vec<u8>> {
+    let id_length = reader.read_u8().await?;
+    let mut trailer = vec![0; 1 + id_length as usize + MAC_SIZE];
+    trailer[0] = id_length;
+    reader.read_exact(&mut trailer[1..]).await?;
+
+    Ok(trailer)
+}
+
+/// Read the contents and the trailer of a signed request and verify it.
+/// `header` is the request header without the bits of its `framing`, and
+/// `extensions` the extension area of an extended request. The CRCs of a
+/// checksummed request are verified first, a corrupted record is reported
+/// as such and not as a bad signature, then the MAC and the timestamp.
+/// Returns the contents, content 1 followed by content 2, and the key ID.
+pub async fn verify<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    header: &Header,
+    framing: Framing,
+    extensions: Option<&[u8]>,
+    config: &Config,
+) -> Result<(Vec<u8>, String), IpcError> {
+    let mut contents = crate::read_content(stream, header.content_length_1 as usize).await?;
+    contents.extend(crate::read_content(stream, header.content_length_2 as usize).await?);
+    let trailer = read_trailer(stream).await?;
+    if framing.checksummed {
+        crate::checksum::verify(stream, &contents, header).await?;
+    }
+
+    let (key_id, mac) = trailer[1..].split_at(trailer[0] as usize);
+    let key_id = std::str::from_utf8(key_id)
+        .map_err(|_| IpcError::Unauthorized("key ID is not valid UTF-8".to_string()))?;
+    let key = config
+        .hmac_keys
+        .get(key_id)
+        .ok_or_else(|| IpcError::Unauthorized(format!("unknown key '{}'", key_id)))?;
+
+    mac_of(key, header, framing, extensions, &contents, key_id)
+        .verify_slice(mac)
+        .map_err(|_| IpcError::Unauthorized(format!("invalid signature for key '{}'", key_id)))?;
+
+    let extensions = match extensions {
+        Some(area) => crate::extensions::decode(area)?,
+        None => Vec::new(),
+    };
+    check_timestamp(crate::extensions::timestamp(&extensions)?, config)?;
+    check_replay(mac, config)?;
+
+    Ok((contents, key_id.to_string()))
+}
+
+/// Refuse a signature without a timestamp or made outside the window.
+fn check_timestamp(timestamp: Option<u64>, config: &Config) -> Result<(), IpcError> {
+    let timestamp = timestamp.ok_or_else(|| {
+        IpcError::Unauthorized("signed requests need a timestamp extension".to_string())
+    })?;
+    if now().abs_diff(timestamp) > config.hmac_max_age_secs {
+        return Err(IpcError::Unauthorized(format!(
+            "signature timestamp {} is not within {} seconds of the server time",
+            timestamp, config.hmac_max_age_secs
+        )));
+    }
+
+    Ok(())
+}
+
+/// Refuse a MAC verified before, remember it otherwise. A timestamp is
+/// accepted for at most twice the window from now, the MAC is kept as long.
+fn check_replay(mac: &[u8], config: &Config) -> Result<(), IpcError> {
+    let now = now();
+    let mut seen = SEEN
+        .get_or_init(Mutex::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    while let Some((expires, _)) = seen.expiry.front() {
+        if *expires >= now {
+            break;
+        }
+        if let Some((_, old)) = seen.expiry.pop_front() {
+            seen.macs.remove(&old);
+        }
+    }
+
+    if !seen.macs.insert(mac.to_vec()) {
+        return Err(IpcError::Unauthorized(
+            "the signed request was already received".to_string(),
+        ));
+    }
+    seen.expiry
+        .push_back((now + 2 * config.hmac_max_age_secs, mac.to_vec()));
+
+    Ok(())
+}
+
+fn mac_of(
+    key: &str,
+    header: &Header,
+    framing: Framing,
+    extensions: Option<&[u8]>,
+    contents: &[u8],
+    key_id: &str,
+) -> HmacSha256 {
+    let mut header_bytes = header.to_bytes_as(framing.extended_lengths);
+    header_bytes[1] |= SIGNED;
+    if framing.extended {
+        header_bytes[0] |= crate::extensions::EXTENDED;
+    }
+    if framing.checksummed {
+        header_bytes[0] |= crate::checksum::CHECKSUMS;
+    }
+
+    let mut mac = HmacSha256::new_from_slice(key.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(&header_bytes);
+    mac.update(&header.extended_lengths_as(framing.extended_lengths));
+    if let Some(request_id) = framing.request_id {
+        mac.update(&request_id.to_be_bytes());
+    }
+    mac.update(extensions.unwrap_or_default());
+    mac.update(contents);
+    mac.update(key_id.as_bytes());
+    mac
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::extensions::{tests::encode, Extension, TIMESTAMP};
+    use crate::{CONTENT_TEXT, CTRL_NOOP};
+
+    const FRAMING: Framing = Framing {
+        signed: true,
+        extended: true,
+        checksummed: false,
+        extended_lengths: [false; 2],
+        request_id: None,
+    };
+
+    /// The extension area of a request signed at `timestamp`.
+    pub fn area(timestamp: u64) -> Vec<u8> {
+        encode(&[Extension {
+            kind: TIMESTAMP,
+            value: timestamp.to_be_bytes().to_vec(),
+        }])
+    }
+
+    /// The trailer a client appends to sign a request.
+    pub fn sign(
+        key: &str,
+        key_id: &str,
+        header: &Header,
+        framing: Framing,
+        area: &[u8],
+        contents: &[u8],
+    ) -> Vec<u8> {
+        let mut trailer = vec![key_id.len() as u8];
+        trailer.extend_from_slice(key_id.as_bytes());
+        trailer.extend_from_slice(
+            &mac_of(key, header, framing, Some(area), contents, key_id)
+                .finalize()
+                .into_bytes(),
+        );
+        trailer
+    }
+
+    fn config() -> Config {
+        let mut config = Config::default();
+        config
+            .hmac_keys
+            .insert("client-a".to_string(), "secret".to_string());
+        config
+    }
+
+    fn header() -> Header {
+        Header {
+            version: 0,
+            control: CTRL_NOOP,
+            content_format_1: 0,
+            content_length_1: 0,
+            content_format_2: CONTENT_TEXT,
+            content_length_2: 5,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_signed_request() {
+        let area = area(now());
+        let mut record = b"nonce".to_vec();
+        record.extend(sign(
+            "secret",
+            "client-a",
+            &header(),
+            FRAMING,
+            &area,
+            b"nonce",
+        ));
+
+        let (contents, key_id) =
+            verify(&mut &record[..], &header(), FRAMING, Some(&area), &config())
+                .await
+                .unwrap();
+        assert_eq!(contents, b"nonce");
+        assert_eq!(key_id, "client-a");
+    }
+
+    #[tokio::test]
+    async fn test_verify_small_extended_length() {
+        // The client signs content-length 2 as sent, extended.
+        let area = area(now());
+        let mut wire = [
+            crate::extensions::EXTENDED,
+            CTRL_NOOP | SIGNED,
+            0,
+            0,
+            0,
+            0,
+            0,
+            CONTENT_TEXT,
+        ]
+        .to_vec();
+        wire.extend_from_slice(&u32::MAX.to_be_bytes());
+        wire.extend_from_slice(&5u64.to_be_bytes());
+        let mut mac = HmacSha256::new_from_slice(b"secret").unwrap();
+        mac.update(&wire);
+        mac.update(&area);
+        mac.update(b"nonce");
+        mac.update(b"client-a");
+
+        let mut record = b"nonce".to_vec();
+        record.push(8);
+        record.extend_from_slice(b"client-a");
+        record.extend_from_slice(&mac.finalize().into_bytes());
+
+        let framing = Framing {
+            extended_lengths: [false, true],
+            ..FRAMING
+        };
+        let (contents, _) = verify(&mut &record[..], &header(), framing, Some(&area), &config())
+            .await
+            .unwrap();
+        assert_eq!(contents, b"nonce");
+        assert!(matches!(
+            verify(&mut &record[..], &header(), FRAMING, Some(&area), &config()).await,
+            Err(IpcError::Unauthorized(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_before_signature() {
+        let area = area(now());
+        let mut record = b"nonse".to_vec();
+        record.extend(sign(
+            "secret",
+            "client-a",
+            &header(),
+            FRAMING,
+            &area,
+            b"nonce",
+        ));
+        record.extend(crate::checksum::trailer(b"", b"nonce"));
+
+        let framing = Framing {
+            checksummed: true,
+            ..FRAMING
+        };
+        assert!(matches!(
+            verify(&mut &record[..], &header(), framing, Some(&area), &config()).await,
+            Err(IpcError::Checksum { block: 2, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_reject_bad_signatures() {
+        let area = area(now());
+        let mut tampered = b"nonse".to_vec();
+        tampered.extend(sign(
+            "secret",
+            "client-a",
+            &header(),
+            FRAMING,
+            &area,
+            b"nonce",
+        ));
+        assert!(matches!(
+            verify(
+                &mut &tampered[..],
+                &header(),
+                FRAMING,
+                Some(&area),
+                &config()
+            )
+            .await,
+            Err(IpcError::Unauthorized(_))
+        ));
+
+        let mut wrong_key = b"nonce".to_vec();
+        wrong_key.extend(sign(
+            "other",
+            "client-b",
+            &header(),
+            FRAMING,
+            &area,
+            b"nonce",
+        ));
+        assert!(matches!(
+            verify(
+                &mut &wrong_key[..],
+                &header(),
+                FRAMING,
+                Some(&area),
+                &config()
+            )
+            .await,
+            Err(IpcError::Unauthorized(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_reject_replayed_request() {
+        let area = area(now());
+        let mut record = b"again".to_vec();
+        record.extend(sign(
+            "secret",
+            "client-a",
+            &header(),
+            FRAMING,
+            &area,
+            b"again",
+        ));
+
+        assert!(
+            verify(&mut &record[..], &header(), FRAMING, Some(&area), &config())
+                .await
+                .is_ok()
+        );
+        assert!(matches!(
+            verify(&mut &record[..], &header(), FRAMING, Some(&area), &config()).await,
+            Err(IpcError::Unauthorized(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_request_id_is_signed() {
+        let area = area(now());
+        let framing = Framing {
+            request_id: Some(7),
+            ..FRAMING
+        };
+        let mut record = b"nonce".to_vec();
+        record.extend(sign(
+            "secret",
+            "client-a",
+            &header(),
+            framing,
+            &area,
+            b"nonce",
+        ));
+
+        assert!(
+            verify(&mut &record[..], &header(), framing, Some(&area), &config())
+                .await
+                .is_ok()
+        );
+        let other = Framing {
+            request_id: Some(8),
+            ..FRAMING
+        };
+        assert!(matches!(
+            verify(&mut &record[..], &header(), other, Some(&area), &config()).await,
+            Err(IpcError::Unauthorized(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_reject_stale_or_missing_timestamp() {
+        let config = config();
+        for area in [area(now() - 120), area(now() + 120), encode(&[])] {
+            let mut record = b"nonce".to_vec();
+            record.extend(sign(
+                "secret",
+                "client-a",
+                &header(),
+                FRAMING,
+                &area,
+                b"nonce",
+            ));
+            assert!(matches!(
+                verify(&mut &record[..], &header(), FRAMING, Some(&area), &config).await,
+                Err(IpcError::Unauthorized(_))
+            ));
+        }
+
+        let unextended = Framing {
+            extended: false,
+            ..FRAMING
+        };
+        let mut record = b"nonce".to_vec();
+        let mut trailer = vec![8];
+        trailer.extend_from_slice(b"client-a");
+        trailer.extend_from_slice(
+            &mac_of("secret", &header(), unextended, None, b"nonce", "client-a")
+                .finalize()
+                .into_bytes(),
+        );
+        record.extend(trailer);
+        assert!(matches!(
+            verify(&mut &record[..], &header(), unextended, None, &config).await,
+            Err(IpcError::Unauthorized(_))
+        ));
+    }
+}
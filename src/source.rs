@@ -0,0 +1,164 @@
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncReadExt;
+
+use crate::error::IpcError;
+use crate::scopes;
+use crate::{Config, ParseTemplateResult, CTRL_STATUS_OK};
+
+// ============================================
+// Template source
+// ============================================
+//
+// CTRL_TEMPLATE_SOURCE returns the raw source of a template, for debugging
+// tools to show it next to a render error. The request must be signed, only
+// files inside `templates_root` are served and at most
+// `template_source_max_bytes`, as bytes (CONTENT_BIN) not checked as UTF-8
+// nor normalized. The control is disabled if `templates_root` is not set.
+
+/// Read the source of `path` (absolute, or relative to `templates_root`) if
+/// its scopes are granted.
+pub async fn read(
+    path: &str,
+    granted: &[String],
+    config: &Config,
+) -> Result<ParseTemplateResult, IpcError> {
+    let Some(root) = &config.templates_root else {
+        return Err(IpcError::UnsupportedControl(crate::CTRL_TEMPLATE_SOURCE));
+    };
+
+    let root = tokio::fs::canonicalize(root)
+        .await
+        .map_err(|e| IpcError::Config(format!("templates_root {}: {}", root, e)))?;
+    // Checked before the file is looked up, a missing scoped file is refused
+    // the same way.
+    scopes::check(&root.join(path).to_string_lossy(), granted, config).await?;
+    let file = contained(&root, Path::new(path)).await.ok_or_else(|| {
+        IpcError::TemplateSource(format!("'{}' not found in templates_root", path))
+    })?;
+
+    let max = config.template_source_max_bytes;
+    let mut source = Vec::new();
+    tokio::fs::File::open(&file)
+        .await
+        .map_err(|e| IpcError::TemplateSource(format!("'{}': {}", path, e)))?
+        .take(max + 1)
+        .read_to_end(&mut source)
+        .await
+        .map_err(|e| IpcError::TemplateSource(format!("'{}': {}", path, e)))?;
+
+    let truncated = source.len() as u64 > max;
+    source.truncate(max as usize);
+
+    let result = json!({
+        "has_error": false,
+        "status_code": "200",
+        "status_text": "OK",
+        "status_param": "",
+        "path": file.to_string_lossy(),
+        "truncated": truncated
+    });
+
+    Ok(ParseTemplateResult {
+        json: result.to_string(),
+        text: String::new(),
+        status: CTRL_STATUS_OK,
+        binary: Some(source),
+    })
+}
+
+/// Canonical path of an existing file inside `root`, symlinks resolved.
+pub async fn contained(root: &Path, path: &Path) -> Option<PathBuf> {
+    let file = tokio::fs::canonicalize(root.join(path)).await.ok()?;
+    let is_file = tokio::fs::metadata(&file).await.ok()?.is_file();
+
+    (is_file && file.starts_with(root)).then_some(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+    use std::fs;
+
+    #[tokio::test]
+    async fn test_read_inside_root() {
+        let dir = temp_dir("source");
+        fs::create_dir_all(dir.join("root")).unwrap();
+        fs::write(dir.join("root/home.ntpl"), "0123456789").unwrap();
+        fs::write(dir.join("secret.txt"), "secret").unwrap();
+
+        let mut config = Config::default();
+        config.templates_root = Some(dir.join("root").to_string_lossy().into_owned());
+        config.template_source_max_bytes = 4;
+
+        let result = read("home.ntpl", &[], &config).await.unwrap();
+        assert_eq!(result.binary.unwrap(), b"0123");
+
+        // Bytes as they are, even cut in a UTF-8 sequence.
+        fs::write(dir.join("root/latin1.ntpl"), b"a\xf1\xc3\xb1").unwrap();
+        let result = read("latin1.ntpl", &[], &config).await.unwrap();
+        assert_eq!(result.binary.unwrap(), b"a\xf1\xc3\xb1");
+        config.template_source_max_bytes = 3;
+        let result = read("latin1.ntpl", &[], &config).await.unwrap();
+        assert_eq!(result.binary.unwrap(), b"a\xf1\xc3");
+        assert!(result.json.contains("\"truncated\":true"));
+
+        assert!(matches!(
+            read("../secret.txt", &[], &config).await,
+            Err(IpcError::TemplateSource(_))
+        ));
+        let absolute = dir.join("secret.txt").to_string_lossy().into_owned();
+        assert!(matches!(
+            read(&absolute, &[], &config).await,
+            Err(IpcError::TemplateSource(_))
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_scoped_source() {
+        let dir = temp_dir("source-scopes");
+        fs::create_dir_all(dir.join("root")).unwrap();
+        fs::create_dir_all(dir.join("root/internal")).unwrap();
+        fs::write(dir.join("root/internal/admin.ntpl"), "admin").unwrap();
+
+        let mut config = Config::default();
+        config.templates_root = Some(dir.join("root").to_string_lossy().into_owned());
+        config.template_scopes = scopes::parse_templates(
+            &json!({ "internal/": ["internal"] }),
+            config.templates_root.as_deref(),
+        );
+
+        for path in [
+            "internal/admin.ntpl",
+            "./internal/../internal/admin.ntpl",
+            "internal/missing.ntpl",
+        ] {
+            assert!(matches!(
+                read(path, &[], &config).await,
+                Err(IpcError::Unauthorized(_))
+            ));
+        }
+        let granted = ["internal".to_string()];
+        assert_eq!(
+            read("internal/admin.ntpl", &granted, &config)
+                .await
+                .unwrap()
+                .binary
+                .unwrap(),
+            b"admin"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_disabled_without_root() {
+        assert!(matches!(
+            read("home.ntpl", &[], &Config::default()).await,
+            Err(IpcError::UnsupportedControl(_))
+        ));
+    }
+}
@@ -0,0 +1,121 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf,
+};
+
+use crate::Config;
+
+// ============================================
+// stdio mode
+// ============================================
+//
+// `neutral-ipc --stdio` reads records from stdin and writes the responses to
+// stdout until stdin is closed, no socket is bound. For inetd/xinetd style
+// deployment or to run the renderer as a subprocess. Logs go to stderr or the
+// configured log backend, never to stdout.
+
+pub async fn serve(config: &Config) -> io::Result<()> {
+    serve_stream(
+        BufReader::new(tokio::io::stdin()),
+        tokio::io::stdout(),
+        config,
+    )
+    .await
+}
+
+async fn serve_stream<R, W>(reader: R, writer: W, config: &Config) -> io::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut stdio = Stdio { reader, writer };
+
+    // Stop at the end of input between records, a truncated record is
+    // reported by the client handler.
+    while !stdio.reader.fill_buf().await?.is_empty() {
+        crate::run_client(&mut stdio, "stdio", config, config.listener_options()).await;
+        stdio.writer.flush().await?;
+    }
+
+    Ok(())
+}
+
+/// Input and output as one stream.
+struct Stdio<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R: AsyncRead + Unpin, W: Unpin> AsyncRead for Stdio<R, W> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().reader).poll_read(cx, buf)
+    }
+}
+
+impl<R: Unpin, W: AsyncWrite + Unpin> AsyncWrite for Stdio<R, W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().writer).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().writer).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Header, CTRL_STATUS_KO, HEADER_SIZE};
+    use std::io::Cursor;
+
+    #[tokio::test]
+    async fn test_serve_several_records() {
+        let request = Header {
+            version: 0,
+            control: 99,
+            content_format_1: 0,
+            content_length_1: 0,
+            content_format_2: 0,
+            content_length_2: 0,
+        };
+        let mut input = request.to_bytes().to_vec();
+        input.extend_from_slice(&request.to_bytes());
+
+        let mut output = Vec::new();
+        serve_stream(Cursor::new(input), &mut output, &Config::default())
+            .await
+            .unwrap();
+
+        // Two error responses, one per record.
+        let first = Header::from_bytes(&output[..HEADER_SIZE]).unwrap();
+        assert_eq!(first.control, CTRL_STATUS_KO);
+        let first_length =
+            HEADER_SIZE + first.content_length_1 as usize + first.content_length_2 as usize;
+        let second = Header::from_bytes(&output[first_length..first_length + HEADER_SIZE]).unwrap();
+        assert_eq!(second.control, CTRL_STATUS_KO);
+    }
+
+    #[tokio::test]
+    async fn test_serve_empty_input() {
+        let mut output = Vec::new();
+        serve_stream(Cursor::new(Vec::new()), &mut output, &Config::default())
+            .await
+            .unwrap();
+
+        assert!(output.is_empty());
+    }
+}
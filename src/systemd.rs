@@ -0,0 +1,74 @@
+use std::env;
+use std::io;
+use std::net::TcpListener;
+use std::os::fd::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+
+use crate::Activated;
+
+// ============================================
+// systemd socket activation
+// ============================================
+//
+// With a neutral-ipc.socket unit systemd owns the listening sockets and
+// starts the daemon on the first connection, passing them as descriptors
+// from 3 on (LISTEN_FDS, LISTEN_PID), see sd_listen_fds(3).
+
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Sockets passed by systemd, empty if not socket activated.
+pub fn activated_listeners() -> io::Result<Vec<Activated>> {
+    let count = listen_fds(
+        env::var("LISTEN_PID").ok().as_deref(),
+        env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    );
+
+    // Not for child processes.
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count as RawFd)
+        .map(|fd| {
+            // SAFETY: systemd passes `count` listening sockets from fd 3 on,
+            // owned by this process and not used anywhere else.
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            if listener.local_addr().is_ok() {
+                return Ok(Activated::Tcp(listener));
+            }
+
+            // Not an IP socket, std fails to convert the address.
+            // SAFETY: same descriptor, ownership moves to the UnixListener.
+            let listener = unsafe { UnixListener::from_raw_fd(listener.into_raw_fd()) };
+            listener.local_addr()?;
+            Ok(Activated::Unix(listener))
+        })
+        .collect()
+}
+
+/// Number of sockets passed to process `pid`.
+fn listen_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> usize {
+    let for_us = listen_pid.and_then(|value| value.parse::<u32>().ok()) == Some(pid);
+    if !for_us {
+        return 0;
+    }
+
+    listen_fds
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_fds() {
+        assert_eq!(listen_fds(Some("42"), Some("2"), 42), 2);
+        assert_eq!(listen_fds(Some("41"), Some("2"), 42), 0);
+        assert_eq!(listen_fds(None, Some("2"), 42), 0);
+        assert_eq!(listen_fds(Some("42"), None, 42), 0);
+        assert_eq!(listen_fds(Some("42"), Some("x"), 42), 0);
+    }
+}
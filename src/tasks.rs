@@ -0,0 +1,141 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::{Notify, Semaphore};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+use crate::logger;
+use crate::Config;
+
+// ============================================
+// Connection tasks
+// ============================================
+//
+// Every connection task is spawned through here so the server knows the
+// outstanding ones: `max_connections` caps them (0 unlimited, over the cap
+// new connections are closed), panics are logged instead of lost, and on
+// shutdown the accept loops stop and the tasks get `shutdown_grace_secs` to
+// finish: idle persistent connections are closed at once, the others after
+// the response in progress. Besides SIGTERM and Ctrl-C, the shutdown control
+// code requests it.
+
+static TRACKER: OnceLock<TaskTracker> = OnceLock::new();
+static LIMIT: OnceLock<Arc<Semaphore>> = OnceLock::new();
+static PANICKED: AtomicU64 = AtomicU64::new(0);
+static SHUTDOWN: OnceLock<Notify> = OnceLock::new();
+static DRAINING: OnceLock<CancellationToken> = OnceLock::new();
+
+fn tracker() -> &'static TaskTracker {
+    TRACKER.get_or_init(TaskTracker::new)
+}
+
+/// Ask the server to shut down, as on SIGTERM.
+pub fn request_shutdown() {
+    SHUTDOWN.get_or_init(Notify::new).notify_one();
+}
+
+/// Wait for a shutdown request.
+pub async fn shutdown_requested() {
+    SHUTDOWN.get_or_init(Notify::new).notified().await
+}
+
+fn draining_token() -> &'static CancellationToken {
+    DRAINING.get_or_init(CancellationToken::new)
+}
+
+/// Wait until shutdown starts, for the connections waiting for a record to
+/// close.
+pub async fn draining() {
+    draining_token().cancelled().await
+}
+
+/// Set the connection limit, called once at startup.
+pub fn init(config: &Config) {
+    if config.max_connections > 0 {
+        let _ = LIMIT.set(Arc::new(Semaphore::new(config.max_connections)));
+    }
+}
+
+/// Spawn a tracked connection task, or close it if the limit is reached.
+pub fn spawn<F>(peer: String, task: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let permit = match LIMIT
+        .get()
+        .map(|limit| Arc::clone(limit).try_acquire_owned())
+    {
+        Some(Ok(permit)) => Some(permit),
+        Some(Err(_)) => {
+            logger::warning(
+                "Connection limit reached, connection closed",
+                &[("peer", &peer)],
+            );
+            return;
+        }
+        None => None,
+    };
+
+    let handle = tokio::spawn(task);
+    tracker().spawn(async move {
+        if let Err(e) = handle.await {
+            if e.is_panic() {
+                PANICKED.fetch_add(1, Ordering::Relaxed);
+                logger::error(
+                    &format!("Connection task panicked: {}", e),
+                    &[("peer", &peer)],
+                );
+            }
+        }
+        drop(permit);
+    });
+}
+
+/// Outstanding connection tasks.
+pub fn active() -> usize {
+    tracker().len()
+}
+
+/// Connection tasks that panicked since startup.
+#[cfg(any(test, feature = "metrics"))]
+pub fn panicked() -> u64 {
+    PANICKED.load(Ordering::Relaxed)
+}
+
+/// Wait for the outstanding tasks, up to `grace`. Returns how many were
+/// still running when it gave up.
+pub async fn shutdown(grace: Duration) -> usize {
+    let tracker = tracker();
+    tracker.close();
+    draining_token().cancel();
+
+    if tokio::time::timeout(grace, tracker.wait()).await.is_err() {
+        return tracker.len();
+    }
+
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_track_and_report_panics() {
+        let before = panicked();
+        spawn("test".to_string(), async {});
+        spawn("test".to_string(), async { panic!("test panic") });
+
+        // Wait without closing the tracker, it is shared by all the tests.
+        for _ in 0..100 {
+            if panicked() > before {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(panicked(), before + 1);
+    }
+}
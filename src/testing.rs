@@ -0,0 +1,17 @@
+use std::fs;
+use std::path::PathBuf;
+
+// ============================================
+// Test fixtures
+// ============================================
+//
+// Helpers shared by the tests of several modules.
+
+/// An empty directory in the system temp dir, named after the test and the
+/// process. Each test uses its own and removes it at the end.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("neutral-ipc-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,182 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+// ============================================
+// Per connection bandwidth throttling
+// ============================================
+//
+// Token bucket per direction, the rate is in bytes per second (0 unlimited)
+// and up to one second of traffic can be sent in a burst.
+
+/// Smallest transfer worth waiting for, so a throttled connection doesn't
+/// move one byte at a time.
+const MIN_CHUNK: f64 = 4096.0;
+
+struct Bucket {
+    rate: u64,
+    available: f64,
+    last: Instant,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        Bucket {
+            rate,
+            available: rate as f64,
+            last: Instant::now(),
+            sleep: None,
+        }
+    }
+
+    /// Bytes that may be transferred now, out of `wanted`.
+    fn poll_allow(&mut self, cx: &mut Context<'_>, wanted: usize) -> Poll<usize> {
+        if self.rate == 0 || wanted == 0 {
+            return Poll::Ready(wanted);
+        }
+
+        let rate = self.rate as f64;
+        let needed = (wanted as f64).min(MIN_CHUNK).min(rate);
+
+        loop {
+            if let Some(sleep) = self.sleep.as_mut() {
+                ready!(sleep.as_mut().poll(cx));
+                self.sleep = None;
+            }
+
+            let now = Instant::now();
+            let elapsed = now.duration_since(self.last).as_secs_f64();
+            self.available = (self.available + elapsed * rate).min(rate);
+            self.last = now;
+
+            if self.available >= needed {
+                return Poll::Ready(wanted.min(self.available as usize));
+            }
+
+            let wait = Duration::from_secs_f64((needed - self.available) / rate);
+            self.sleep = Some(Box::pin(tokio::time::sleep(wait)));
+        }
+    }
+
+    fn consume(&mut self, bytes: usize) {
+        if self.rate > 0 {
+            self.available -= bytes as f64;
+        }
+    }
+}
+
+/// A stream with read and write rate limits.
+pub struct Throttled<S> {
+    inner: S,
+    read: Bucket,
+    write: Bucket,
+}
+
+impl<S> Throttled<S> {
+    pub fn new(inner: S, read_rate: u64, write_rate: u64) -> Self {
+        Throttled {
+            inner,
+            read: Bucket::new(read_rate),
+            write: Bucket::new(write_rate),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let allowed = ready!(this.read.poll_allow(cx, buf.remaining()));
+        if allowed == buf.remaining() {
+            let before = buf.filled().len();
+            ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+            this.read.consume(buf.filled().len() - before);
+            return Poll::Ready(Ok(()));
+        }
+
+        let read = {
+            let mut limited = ReadBuf::new(buf.initialize_unfilled_to(allowed));
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+            limited.filled().len()
+        };
+        buf.advance(read);
+        this.read.consume(read);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let allowed = ready!(this.write.poll_allow(cx, buf.len()));
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..allowed]))?;
+        this.write.consume(written);
+
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_write_rate_limit() {
+        let (client, mut server) = tokio::io::duplex(64 * 1024);
+        let mut throttled = Throttled::new(client, 0, 10_000);
+        let started = std::time::Instant::now();
+
+        // One second of burst, then half a second at the rate.
+        throttled.write_all(&[0; 15_000]).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(400));
+
+        let mut received = vec![0; 15_000];
+        server.read_exact(&mut received).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_rate_limit() {
+        let (client, mut server) = tokio::io::duplex(64 * 1024);
+        let mut throttled = Throttled::new(client, 10_000, 0);
+        server.write_all(&[1; 15_000]).await.unwrap();
+        let started = std::time::Instant::now();
+
+        let mut received = vec![0; 15_000];
+        throttled.read_exact(&mut received).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(400));
+        assert!(received.iter().all(|&byte| byte == 1));
+    }
+
+    #[tokio::test]
+    async fn test_unlimited() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut throttled = Throttled::new(client, 0, 0);
+
+        throttled.write_all(b"ping").await.unwrap();
+        let mut received = [0; 4];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+    }
+}
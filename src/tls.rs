@@ -0,0 +1,112 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+use crate::error::IpcError;
+use crate::logger;
+use crate::{Config, ListenerOptions};
+
+// ============================================
+// TLS
+// ============================================
+//
+// With `tls_cert` and `tls_key` (PEM files) set, TCP connections are TLS,
+// the protocol inside is the same. The Unix domain socket is not affected.
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+static ACCEPTOR: OnceLock<TlsAcceptor> = OnceLock::new();
+
+/// Load the certificate and key, called once at startup.
+pub fn init(config: &Config) -> Result<(), IpcError> {
+    let (cert, key) = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => (cert, key),
+        (None, None) => return Ok(()),
+        _ => {
+            return Err(IpcError::Config(
+                "tls_cert and tls_key must be set together".to_string(),
+            ))
+        }
+    };
+
+    let _ = ACCEPTOR.set(load(cert, key)?);
+
+    Ok(())
+}
+
+/// The acceptor if TLS is enabled.
+pub fn acceptor() -> Option<&'static TlsAcceptor> {
+    ACCEPTOR.get()
+}
+
+fn config_error(path: &str, e: impl std::fmt::Display) -> IpcError {
+    IpcError::Config(format!("TLS {}: {}", path, e))
+}
+
+fn load(cert: &str, key: &str) -> Result<TlsAcceptor, IpcError> {
+    Ok(TlsAcceptor::from(Arc::new(server_config(cert, key)?)))
+}
+
+/// Server config with the certificate chain and key from PEM files.
+pub fn server_config(cert: &str, key: &str) -> Result<ServerConfig, IpcError> {
+    let mut reader = BufReader::new(File::open(cert).map_err(|e| config_error(cert, e))?);
+    let certs = rustls_pemfile::certs(&mut reader)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| config_error(cert, e))?;
+    if certs.is_empty() {
+        return Err(config_error(cert, "no certificate found"));
+    }
+
+    let mut reader = BufReader::new(File::open(key).map_err(|e| config_error(key, e))?);
+    let key_der = rustls_pemfile::private_key(&mut reader)
+        .map_err(|e| config_error(key, e))?
+        .ok_or_else(|| config_error(key, "no private key found"))?;
+
+    ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key_der)
+        .map_err(|e| config_error(cert, e))
+}
+
+/// Handshake and handle the client in a new task.
+pub fn spawn_client(
+    acceptor: &TlsAcceptor,
+    stream: TcpStream,
+    peer: String,
+    config: &Arc<Config>,
+    options: ListenerOptions,
+) {
+    let acceptor = acceptor.clone();
+    let config = Arc::clone(config);
+
+    crate::tasks::spawn(peer.clone(), async move {
+        match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+            Ok(Ok(stream)) => crate::run_client(stream, &peer, &config, options).await,
+            Ok(Err(e)) => {
+                logger::warning(&format!("TLS handshake failed: {}", e), &[("peer", &peer)])
+            }
+            Err(_) => logger::warning("TLS handshake timeout", &[("peer", &peer)]),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_init_requires_cert_and_key() {
+        let mut config = Config::default();
+        assert!(init(&config).is_ok());
+
+        config.tls_cert = Some("/nonexistent/cert.pem".to_string());
+        assert!(matches!(init(&config), Err(IpcError::Config(_))));
+
+        config.tls_key = Some("/nonexistent/key.pem".to_string());
+        assert!(matches!(init(&config), Err(IpcError::Config(_))));
+    }
+}
@@ -0,0 +1,87 @@
+use crate::logger;
+use crate::sampler::Sampler;
+
+// ============================================
+// Protocol tracing dump
+// ============================================
+//
+// Hex dump of the header and the first bytes of each content block for a
+// sampled fraction of requests, written to the log with debug priority.
+//
+// Redaction masks letters, digits and non-ASCII bytes so that the framing
+// and structure ({, ", :, etc.) remain visible but not the data.
+
+static SAMPLER: Sampler = Sampler::new();
+
+const BYTES_PER_LINE: usize = 16;
+
+/// Returns the trace id if this request has been sampled.
+pub fn sample(rate: f64) -> Option<u64> {
+    SAMPLER.sample(rate)
+}
+
+/// Dump a block to the debug log, the header blocks must not be redacted.
+pub fn dump(id: u64, label: &str, bytes: &[u8], max_bytes: usize, redact: bool) {
+    logger::debug(&format_block(id, label, bytes, max_bytes, redact), &[]);
+}
+
+fn format_block(id: u64, label: &str, bytes: &[u8], max_bytes: usize, redact: bool) -> String {
+    let shown = &bytes[..bytes.len().min(max_bytes)];
+    let mut out = format!(
+        "[trace #{}] {} ({} bytes, showing {})",
+        id,
+        label,
+        bytes.len(),
+        shown.len()
+    );
+
+    for (line, chunk) in shown.chunks(BYTES_PER_LINE).enumerate() {
+        let mut hex = String::with_capacity(BYTES_PER_LINE * 3);
+        let mut ascii = String::with_capacity(BYTES_PER_LINE);
+
+        for &byte in chunk {
+            if redact && is_redacted(byte) {
+                hex.push_str("** ");
+                ascii.push('*');
+            } else {
+                hex.push_str(&format!("{:02x} ", byte));
+                ascii.push(if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' });
+            }
+        }
+
+        out.push_str(&format!(
+            "\n[trace #{}]   {:08x}  {:<48} {}",
+            id,
+            line * BYTES_PER_LINE,
+            hex,
+            ascii
+        ));
+    }
+
+    out
+}
+
+fn is_redacted(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || !byte.is_ascii()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_block_redacted() {
+        let dump = format_block(1, "content-1", b"{\"a\":1}", 64, true);
+        assert!(dump.contains("7 bytes, showing 7"));
+        assert!(dump.contains("7b 22 ** 22 3a ** 7d"));
+        assert!(dump.contains("{\"*\":*}"));
+    }
+
+    #[test]
+    fn test_format_block_truncated() {
+        let dump = format_block(1, "header", &[0u8; 40], 20, false);
+        assert!(dump.contains("40 bytes, showing 20"));
+        assert!(dump.contains("00000010"));
+        assert!(!dump.contains("00000020"));
+    }
+}
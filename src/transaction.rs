@@ -0,0 +1,200 @@
+use futures_util::future::join_all;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+use crate::error::IpcError;
+use crate::output::OutputOptions;
+use crate::{Config, ParseTemplateResult, CONTENT_PATH, CTRL_STATUS_OK};
+
+// ============================================
+// Transaction render
+// ============================================
+//
+// A transaction request (CTRL_PARSE_TRANSACTION) renders every template of a
+// manifest with the same schema, all or nothing: publishing pipelines must not
+// write half of an updated page set. Content 2 is the manifest, a JSON array
+// of template paths (at most `transaction_max_templates`):
+//
+// ["pages/index.ntpl", "pages/about.ntpl"]
+//
+// If every template renders without error content 1 has the metadata of each
+// one and the byte length of its output, and content 2 the outputs one after
+// the other in manifest order. The output options of the listener apply to
+// each output, the lengths are of the normalized outputs. Otherwise the
+// request fails and no output is returned, the failed templates are listed
+// in "failures".
+
+/// Template paths of a manifest.
+pub fn parse_manifest(manifest: &[u8], config: &Config) -> Result<Vec<String>, IpcError> {
+    let invalid = |message: &str| IpcError::Schema(format!("transaction manifest: {}", message));
+
+    let templates: Vec<String> =
+        serde_json::from_slice(manifest).map_err(|e| invalid(&e.to_string()))?;
+    if templates.is_empty() {
+        return Err(invalid("no templates"));
+    }
+    if templates.len() > config.transaction_max_templates {
+        return Err(invalid(&format!(
+            "{} templates, at most {} allowed",
+            templates.len(),
+            config.transaction_max_templates
+        )));
+    }
+
+    Ok(templates)
+}
+
+/// Render all the templates or none.
+pub async fn render(
+    config: &Config,
+    schema: Vec<u8>,
+    schema_format: u8,
+    templates: Vec<String>,
+    output: OutputOptions,
+    scopes: &[String],
+) -> Result<ParseTemplateResult, IpcError> {
+    let schema = Arc::new(schema);
+    let renders = templates.iter().map(|template| {
+        crate::render_template(
+            config,
+            Arc::clone(&schema),
+            schema_format,
+            template.clone(),
+            CONTENT_PATH,
+            scopes,
+        )
+    });
+    let results = join_all(renders).await;
+
+    let mut rendered = Vec::new();
+    let mut failures = Vec::new();
+    let mut text = String::new();
+    for (template, result) in templates.iter().zip(results) {
+        let failure = match result {
+            Ok(mut result) => {
+                result.text = output.apply(result.text);
+                let metadata: Value = serde_json::from_str(&result.json).unwrap_or(Value::Null);
+                if result.status == CTRL_STATUS_OK && metadata["has_error"] != true {
+                    rendered.push(json!({
+                        "template": template,
+                        "metadata": metadata,
+                        "length": result.text.len()
+                    }));
+                    text.push_str(&result.text);
+                    continue;
+                }
+                metadata
+            }
+            Err(e) => serde_json::from_str(&e.to_json()).unwrap_or(Value::Null),
+        };
+        failures.push(json!({
+            "template": template,
+            "status_code": failure["status_code"],
+            "status_param": failure["status_param"]
+        }));
+    }
+
+    if !failures.is_empty() {
+        return Err(IpcError::Transaction {
+            total: templates.len(),
+            failures,
+        });
+    }
+
+    Ok(ParseTemplateResult {
+        json: json!({ "has_error": false, "templates": rendered }).to_string(),
+        text,
+        status: CTRL_STATUS_OK,
+        binary: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CONTENT_JSON;
+
+    fn mock_config() -> Config {
+        let mut config = Config::default();
+        config.render_backend = "mock".to_string();
+        config
+    }
+
+    #[test]
+    fn test_parse_manifest() {
+        let mut config = mock_config();
+        config.transaction_max_templates = 2;
+
+        assert_eq!(
+            parse_manifest(br#"["a.ntpl", "b.ntpl"]"#, &config).unwrap(),
+            vec!["a.ntpl", "b.ntpl"]
+        );
+        assert!(parse_manifest(b"[]", &config).is_err());
+        assert!(parse_manifest(br#"{"a": 1}"#, &config).is_err());
+        assert!(parse_manifest(br#"["a", "b", "c"]"#, &config).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_render_all() {
+        let templates = vec!["a.ntpl".to_string(), "bb.ntpl".to_string()];
+        let result = render(
+            &mock_config(),
+            b"{}".to_vec(),
+            CONTENT_JSON,
+            templates,
+            OutputOptions::default(),
+            &[],
+        )
+        .await
+        .unwrap();
+        let metadata: Value = serde_json::from_str(&result.json).unwrap();
+
+        assert_eq!(result.text, "a.ntplbb.ntpl");
+        assert_eq!(metadata["templates"][0]["length"], 6);
+        assert_eq!(metadata["templates"][1]["template"], "bb.ntpl");
+    }
+
+    #[tokio::test]
+    async fn test_lengths_of_normalized_outputs() {
+        let templates = vec!["a.ntpl".to_string(), "b.ntpl".to_string()];
+        let output = OutputOptions::from_value(&json!({ "bom": "emit" }));
+        let result = render(
+            &mock_config(),
+            b"{}".to_vec(),
+            CONTENT_JSON,
+            templates,
+            output,
+            &[],
+        )
+        .await
+        .unwrap();
+        let metadata: Value = serde_json::from_str(&result.json).unwrap();
+
+        assert_eq!(result.text, "\u{feff}a.ntpl\u{feff}b.ntpl");
+        assert_eq!(metadata["templates"][0]["length"], 9);
+        assert_eq!(metadata["templates"][1]["length"], 9);
+    }
+
+    #[tokio::test]
+    async fn test_failed_template_fails_all() {
+        let templates = vec!["a.ntpl".to_string(), "@missing".to_string()];
+
+        match render(
+            &mock_config(),
+            b"{}".to_vec(),
+            CONTENT_JSON,
+            templates,
+            OutputOptions::default(),
+            &[],
+        )
+        .await
+        {
+            Err(IpcError::Transaction { total, failures }) => {
+                assert_eq!(total, 2);
+                assert_eq!(failures.len(), 1);
+                assert_eq!(failures[0]["template"], "@missing");
+            }
+            _ => panic!("expected a transaction error"),
+        }
+    }
+}
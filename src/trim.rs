@@ -0,0 +1,190 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+use crate::Config;
+
+// ============================================
+// Schema value trimming
+// ============================================
+//
+// Strings in JSON schemas longer than `schema_value_max_bytes` (0 no limit)
+// are cut before rendering and end with MARKER, so a megabyte value a buggy
+// client put in a field doesn't reach the engine. `schema_value_limits` sets
+// the limit of single values by JSON pointer ("/data/description"), over the
+// general one. The trims are logged and reported in the response metadata
+// as "schema_trims". The trimmed schema keeps the order of its keys, which
+// templates iterating an object depend on. Only JSON schemas (CBOR ones are
+// converted to JSON before) are trimmed, MsgPack schemas are not.
+
+pub const MARKER: &str = " [trimmed]";
+
+/// A trimmed value.
+#[derive(Debug, PartialEq)]
+pub struct Trim {
+    pub path: String,
+    pub bytes: usize,
+    pub max: usize,
+}
+
+/// The limits of `schema_value_max_bytes` and `schema_value_limits`, owned
+/// so the trim runs off the runtime threads.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    max_bytes: usize,
+    values: HashMap<String, usize>,
+}
+
+impl Limits {
+    /// The configured limits, `None` if there are none.
+    pub fn of(config: &Config) -> Option<Limits> {
+        if config.schema_value_max_bytes == 0 && config.schema_value_limits.is_empty() {
+            return None;
+        }
+
+        Some(Limits {
+            max_bytes: config.schema_value_max_bytes,
+            values: config.schema_value_limits.clone(),
+        })
+    }
+}
+
+/// The schema with its long strings trimmed, `None` if nothing was trimmed.
+pub fn trim(schema: &[u8], limits: &Limits) -> Option<(Vec<u8>, Vec<Trim>)> {
+    // Not JSON: the engine reports it when rendering.
+    let mut value: Value = serde_json::from_slice(schema).ok()?;
+    let mut trims = Vec::new();
+    walk(&mut value, &mut String::new(), limits, &mut trims);
+
+    if trims.is_empty() {
+        return None;
+    }
+
+    Some((value.to_string().into_bytes(), trims))
+}
+
+fn walk(value: &mut Value, path: &mut String, limits: &Limits, trims: &mut Vec<Trim>) {
+    match value {
+        Value::String(text) => {
+            let max = limits
+                .values
+                .get(path.as_str())
+                .copied()
+                .unwrap_or(limits.max_bytes);
+            if max > 0 && text.len() > max {
+                trims.push(Trim {
+                    path: path.clone(),
+                    bytes: text.len(),
+                    max,
+                });
+                let mut end = max;
+                while !text.is_char_boundary(end) {
+                    end -= 1;
+                }
+                text.truncate(end);
+                text.push_str(MARKER);
+            }
+        }
+        Value::Array(items) => {
+            for (index, item) in items.iter_mut().enumerate() {
+                let length = path.len();
+                path.push('/');
+                path.push_str(&index.to_string());
+                walk(item, path, limits, trims);
+                path.truncate(length);
+            }
+        }
+        Value::Object(map) => {
+            for (key, item) in map.iter_mut() {
+                let length = path.len();
+                path.push('/');
+                path.push_str(&key.replace('~', "~0").replace('/', "~1"));
+                walk(item, path, limits, trims);
+                path.truncate(length);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Add the trims to the response metadata.
+pub fn annotate(json: &str, trims: &[Trim]) -> String {
+    match serde_json::from_str::<Value>(json) {
+        Ok(Value::Object(mut metadata)) => {
+            let trims: Vec<Value> = trims
+                .iter()
+                .map(|trim| json!({ "path": trim.path, "bytes": trim.bytes, "max": trim.max }))
+                .collect();
+            metadata.insert("schema_trims".to_string(), Value::from(trims));
+            Value::Object(metadata).to_string()
+        }
+        _ => json.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trim_long_values() {
+        let mut config = Config::default();
+        config.schema_value_max_bytes = 4;
+        config
+            .schema_value_limits
+            .insert("/data/title".to_string(), 8);
+
+        let schema = br#"{"data": {"title": "Long title", "list": ["ok", "toolong"], "n": 12345}}"#;
+        let (schema, trims) = trim(schema, &Limits::of(&config).unwrap()).unwrap();
+        let value: Value = serde_json::from_slice(&schema).unwrap();
+
+        assert_eq!(value["data"]["title"], format!("Long tit{}", MARKER));
+        assert_eq!(value["data"]["list"][0], "ok");
+        assert_eq!(value["data"]["list"][1], format!("tool{}", MARKER));
+        assert_eq!(value["data"]["n"], 12345);
+        assert_eq!(trims.len(), 2);
+        assert!(trims.contains(&Trim {
+            path: "/data/list/1".to_string(),
+            bytes: 7,
+            max: 4
+        }));
+    }
+
+    #[test]
+    fn test_trim_char_boundary() {
+        let mut config = Config::default();
+        config.schema_value_max_bytes = 2;
+
+        let (schema, _) =
+            trim(r#"{"a": "añb"}"#.as_bytes(), &Limits::of(&config).unwrap()).unwrap();
+        let value: Value = serde_json::from_slice(&schema).unwrap();
+
+        assert_eq!(value["a"], format!("a{}", MARKER));
+    }
+
+    #[test]
+    fn test_trim_keeps_key_order() {
+        let mut config = Config::default();
+        config.schema_value_max_bytes = 2;
+
+        let (schema, _) = trim(
+            br#"{"z": "long", "a": 1, "m": {"y": 2, "b": 3}}"#,
+            &Limits::of(&config).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            schema,
+            format!(r#"{{"z":"lo{}","a":1,"m":{{"y":2,"b":3}}}}"#, MARKER).as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_nothing_to_trim() {
+        let mut config = Config::default();
+        assert!(Limits::of(&config).is_none());
+
+        config.schema_value_max_bytes = 100;
+        let limits = Limits::of(&config).unwrap();
+        assert!(trim(br#"{"a": "long value"}"#, &limits).is_none());
+        assert!(trim(b"not json", &limits).is_none());
+    }
+}
@@ -0,0 +1,186 @@
+use jsonschema::Validator;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::error::IpcError;
+use crate::{scopes, Config};
+
+// ============================================
+// JSON Schema validation of render schemas
+// ============================================
+//
+// `json_schemas` maps a template (path or @alias) to a JSON Schema document.
+// JSON schemas (content-1) sent for that template are validated against it
+// before rendering. In "warn" mode the violations are logged and added to
+// the response metadata as "schema_violations", in "enforce" mode the
+// request fails.
+//
+// Templates are matched by the file rendered: the entries are resolved and
+// canonicalized at load, a relative path from the working directory as the
+// engine opens it, and so is the template of a request after alias
+// resolution. `./x.ntpl`, the absolute path or the alias of the same file
+// are validated alike.
+
+static VALIDATORS: OnceLock<HashMap<PathBuf, Validator>> = OnceLock::new();
+
+/// Load and compile the JSON Schema documents, called once at startup.
+pub fn init(config: &Config) -> Result<(), IpcError> {
+    if config.json_schema_mode != "warn" && config.json_schema_mode != "enforce" {
+        return Err(IpcError::Config(format!(
+            "unknown json_schema_mode '{}', expected warn or enforce",
+            config.json_schema_mode
+        )));
+    }
+
+    let _ = VALIDATORS.set(load(config)?);
+
+    Ok(())
+}
+
+/// The validators of `json_schemas` by the canonical path of their template.
+fn load(config: &Config) -> Result<HashMap<PathBuf, Validator>, IpcError> {
+    let mut validators = HashMap::new();
+    for (template, path) in &config.json_schemas {
+        let file = crate::resolve_template_path(template.clone(), config)
+            .map_err(|e| IpcError::Config(format!("json_schemas {}: {}", template, e)))?;
+        let document = fs::read_to_string(path)
+            .map_err(|e| IpcError::Config(format!("JSON Schema {}: {}", path, e)))?;
+        let document: Value = serde_json::from_str(&document)
+            .map_err(|e| IpcError::Config(format!("JSON Schema {}: {}", path, e)))?;
+        validators.insert(
+            scopes::canonical(Path::new(&file)),
+            compile(&document, path)?,
+        );
+    }
+
+    Ok(validators)
+}
+
+fn compile(document: &Value, path: &str) -> Result<Validator, IpcError> {
+    jsonschema::validator_for(document)
+        .map_err(|e| IpcError::Config(format!("JSON Schema {}: {}", path, e)))
+}
+
+/// Whether any template has a JSON Schema.
+pub fn enabled() -> bool {
+    VALIDATORS
+        .get()
+        .is_some_and(|validators| !validators.is_empty())
+}
+
+/// Violations of the schema sent for `template`, the template path after
+/// alias resolution, empty if valid or if no JSON Schema is registered for it.
+pub fn validate(template: &str, schema: &[u8]) -> Vec<String> {
+    VALIDATORS
+        .get()
+        .map(|validators| validate_with(validators, template, schema))
+        .unwrap_or_default()
+}
+
+fn validate_with(
+    validators: &HashMap<PathBuf, Validator>,
+    template: &str,
+    schema: &[u8],
+) -> Vec<String> {
+    if validators.is_empty() {
+        return Vec::new();
+    }
+    let Some(validator) = validators.get(&scopes::canonical(Path::new(template))) else {
+        return Vec::new();
+    };
+
+    violations(validator, schema)
+}
+
+fn violations(validator: &Validator, schema: &[u8]) -> Vec<String> {
+    // Not JSON: the engine reports it when rendering.
+    let Ok(instance) = serde_json::from_slice::<Value>(schema) else {
+        return Vec::new();
+    };
+
+    validator
+        .iter_errors(&instance)
+        .map(|error| format!("{}: {}", error.instance_path, error))
+        .collect()
+}
+
+/// Add the violations to the response metadata.
+pub fn annotate(json: &str, violations: &[String]) -> String {
+    match serde_json::from_str::<Value>(json) {
+        Ok(Value::Object(mut metadata)) => {
+            metadata.insert("schema_violations".to_string(), Value::from(violations));
+            Value::Object(metadata).to_string()
+        }
+        _ => json.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+    use serde_json::json;
+
+    #[test]
+    fn test_violations() {
+        let document = json!({
+            "type": "object",
+            "required": ["data"],
+            "properties": { "data": { "type": "object" } }
+        });
+        let validator = compile(&document, "test.json").unwrap();
+
+        assert!(violations(&validator, br#"{"data": {}}"#).is_empty());
+        assert!(violations(&validator, b"not json").is_empty());
+
+        let found = violations(&validator, br#"{"data": 1}"#);
+        assert_eq!(found.len(), 1);
+        assert!(found[0].starts_with("/data: "));
+    }
+
+    #[test]
+    fn test_validate_canonical_template() {
+        let root = temp_dir("validation");
+        fs::create_dir_all(root.join("pages")).unwrap();
+        fs::write(root.join("pages/home.ntpl"), "").unwrap();
+        let document = root.join("home.schema.json");
+        fs::write(&document, r#"{"required": ["data"]}"#).unwrap();
+
+        let home = root.join("pages/home.ntpl").to_string_lossy().into_owned();
+        let mut config = Config::default();
+        config
+            .template_aliases
+            .insert("@home".to_string(), home.clone());
+        config
+            .json_schemas
+            .insert("@home".to_string(), document.to_string_lossy().into_owned());
+        let validators = load(&config).unwrap();
+
+        // The same file spelled two ways, and the alias resolved, are validated.
+        let dotted = format!("{}/./pages/../pages/home.ntpl", root.display());
+        for template in [home.as_str(), dotted.as_str()] {
+            assert_eq!(validate_with(&validators, template, b"{}").len(), 1);
+            assert!(validate_with(&validators, template, br#"{"data": {}}"#).is_empty());
+        }
+
+        config
+            .json_schemas
+            .insert("@missing".to_string(), "x.json".to_string());
+        assert!(matches!(load(&config), Err(IpcError::Config(_))));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_annotate() {
+        let json = r#"{"has_error":false,"status_code":"200"}"#;
+        let value: Value =
+            serde_json::from_str(&annotate(json, &["/data: not an object".to_string()])).unwrap();
+
+        assert_eq!(value["status_code"], "200");
+        assert_eq!(value["schema_violations"][0], "/data: not an object");
+    }
+}
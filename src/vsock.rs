@@ -0,0 +1,33 @@
+use std::sync::Arc;
+use tokio_vsock::{VsockAddr, VsockListener};
+
+use crate::logger;
+use crate::{Config, ListenerOptions};
+
+// ============================================
+// vsock transport
+// ============================================
+//
+// AF_VSOCK listener for VM guests, they connect to the host CID over
+// virtio-vsock without guest networking. Set with a `vsock://PORT` or
+// `vsock://CID:PORT` entry in `listeners`, the protocol is the same as
+// over TCP.
+
+/// Accept connections from any CID.
+pub const CID_ANY: u32 = u32::MAX;
+
+pub fn bind(cid: u32, port: u32) -> std::io::Result<VsockListener> {
+    VsockListener::bind(VsockAddr::new(cid, port))
+}
+
+pub async fn serve(mut listener: VsockListener, config: Arc<Config>, options: ListenerOptions) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let peer = format!("vsock:{}:{}", peer.cid(), peer.port());
+                crate::spawn_client(stream, peer, &config, options)
+            }
+            Err(e) => logger::error(&format!("Failed to accept connection: {}", e), &[]),
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use futures_util::{SinkExt, StreamExt};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::exchange::Exchange;
+use crate::logger;
+use crate::Config;
+
+// ============================================
+// WebSocket transport
+// ============================================
+//
+// Each binary message carries one record (header and contents), the
+// response record comes back as one binary message. A connection can send
+// any number of requests, one at a time. Text messages are ignored.
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub async fn serve(listener: TcpListener, config: Arc<Config>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                crate::tasks::spawn(
+                    peer.to_string(),
+                    connection(stream, peer.to_string(), Arc::clone(&config)),
+                );
+            }
+            Err(e) => logger::error(&format!("Failed to accept connection: {}", e), &[]),
+        }
+    }
+}
+
+async fn connection(stream: TcpStream, peer: String, config: Arc<Config>) {
+    let mut websocket = match tokio::time::timeout(
+        HANDSHAKE_TIMEOUT,
+        tokio_tungstenite::accept_async(stream),
+    )
+    .await
+    {
+        Ok(Ok(websocket)) => websocket,
+        Ok(Err(e)) => {
+            logger::warning(
+                &format!("WebSocket handshake failed: {}", e),
+                &[("peer", &peer)],
+            );
+            return;
+        }
+        Err(_) => {
+            logger::warning("WebSocket handshake timeout", &[("peer", &peer)]);
+            return;
+        }
+    };
+
+    while let Some(message) = websocket.next().await {
+        let record = match message {
+            Ok(Message::Binary(record)) => record,
+            Ok(Message::Close(_)) => return,
+            Ok(_) => continue,
+            Err(e) => {
+                logger::debug(
+                    &format!("WebSocket connection closed: {}", e),
+                    &[("peer", &peer)],
+                );
+                return;
+            }
+        };
+
+        let mut exchange = Exchange::new(record);
+        crate::run_client(&mut exchange, &peer, &config, config.listener_options()).await;
+
+        // A truncated record gets no response, as over TCP.
+        if exchange.response.is_empty() {
+            let _ = websocket.close(None).await;
+            return;
+        }
+
+        if websocket
+            .send(Message::Binary(exchange.response))
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Header, CTRL_STATUS_KO, HEADER_SIZE};
+
+    #[tokio::test]
+    async fn test_exchange_record() {
+        let request = Header {
+            version: 0,
+            control: 99,
+            content_format_1: 0,
+            content_length_1: 0,
+            content_format_2: 0,
+            content_length_2: 0,
+        };
+        let config = Config::default();
+        let mut exchange = Exchange::new(request.to_bytes().to_vec());
+        crate::run_client(&mut exchange, "test", &config, config.listener_options()).await;
+
+        assert!(exchange.response.len() > HEADER_SIZE);
+        assert_eq!(exchange.response[1], CTRL_STATUS_KO);
+    }
+
+    #[tokio::test]
+    async fn test_exchange_truncated_record() {
+        let config = Config::default();
+        let mut exchange = Exchange::new(vec![0, 10, 10]);
+        crate::run_client(&mut exchange, "test", &config, config.listener_options()).await;
+
+        assert!(exchange.response.is_empty());
+    }
+}
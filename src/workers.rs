@@ -0,0 +1,256 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+
+use crate::error::IpcError;
+use crate::logger;
+use crate::{Config, ParseTemplateResult};
+
+// ============================================
+// Render workers
+// ============================================
+//
+// Renders are CPU bound, they run on the blocking thread pool and at most
+// `render_workers` at a time (0 = number of CPUs), the rest wait for a turn.
+//
+// With `render_autotune` the limit is adjusted every AUTOTUNE_INTERVAL within
+// [render_workers_min, render_workers_max]: it grows by one while requests
+// have to wait and render latency holds, and shrinks by one when latency
+// rises well above the best observed, which is what CPU saturation looks
+// like from here.
+
+const AUTOTUNE_INTERVAL: Duration = Duration::from_secs(5);
+/// Renders needed in an interval to take a decision.
+const AUTOTUNE_MIN_RENDERS: u64 = 20;
+/// Latency over the best observed that counts as saturation.
+const AUTOTUNE_SATURATION: f64 = 1.5;
+/// The best latency drifts up each interval so an old baseline expires.
+const AUTOTUNE_BASELINE_DECAY: f64 = 1.02;
+
+static POOL: OnceLock<RenderPool> = OnceLock::new();
+
+struct RenderPool {
+    semaphore: Semaphore,
+    limit: AtomicUsize,
+    window: Mutex<Window>,
+}
+
+/// Observations since the last tuning.
+#[derive(Debug, Default)]
+struct Window {
+    renders: u64,
+    render_us: u64,
+    waited: u64,
+}
+
+/// What the tuner decided for an interval.
+#[derive(Debug, PartialEq)]
+enum Adjust {
+    Grow,
+    Shrink,
+    Keep,
+}
+
+fn pool() -> &'static RenderPool {
+    POOL.get_or_init(|| RenderPool::new(cpus()))
+}
+
+fn cpus() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+impl RenderPool {
+    fn new(limit: usize) -> Self {
+        RenderPool {
+            semaphore: Semaphore::new(limit),
+            limit: AtomicUsize::new(limit),
+            window: Mutex::new(Window::default()),
+        }
+    }
+}
+
+/// Set up the pool from the config and start the tuner, called once at startup.
+pub fn init(config: &Config) {
+    let workers = if config.render_workers == 0 {
+        cpus()
+    } else {
+        config.render_workers
+    };
+    let _ = POOL.set(RenderPool::new(workers));
+
+    if config.render_autotune {
+        let min = config.render_workers_min.clamp(1, workers);
+        let max = if config.render_workers_max == 0 {
+            workers * 2
+        } else {
+            config.render_workers_max.max(workers)
+        };
+        tokio::spawn(autotune(min, max));
+    }
+}
+
+/// Run a render on the blocking pool when a worker is free.
+pub async fn render<F>(job: F) -> Result<ParseTemplateResult, IpcError>
+where
+    F: FnOnce() -> Result<ParseTemplateResult, IpcError> + Send + 'static,
+{
+    let pool = pool();
+    let waited = pool.semaphore.available_permits() == 0;
+    #[cfg(feature = "metrics")]
+    let queued = Instant::now();
+    let permit = pool
+        .semaphore
+        .acquire()
+        .await
+        .map_err(|e| IpcError::Render(e.to_string()))?;
+    #[cfg(feature = "metrics")]
+    crate::metrics::record_phase(crate::metrics::QUEUE_WAIT, queued.elapsed());
+
+    // The permit goes with the render: a cancelled request stops waiting for
+    // it, but the worker stays busy until the render ends.
+    let started = Instant::now();
+    let result = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        job()
+    })
+    .await
+    .map_err(|e| IpcError::Render(format!("render task failed: {}", e)))?;
+    #[cfg(feature = "metrics")]
+    crate::metrics::record_phase(crate::metrics::RENDER, started.elapsed());
+
+    let mut window = pool.window.lock().unwrap_or_else(|e| e.into_inner());
+    window.renders += 1;
+    window.render_us = window
+        .render_us
+        .saturating_add(u64::try_from(started.elapsed().as_micros()).unwrap_or(u64::MAX));
+    if waited {
+        window.waited += 1;
+    }
+
+    result
+}
+
+async fn autotune(min: usize, max: usize) {
+    let pool = pool();
+    let mut best_us = f64::INFINITY;
+    let mut interval = tokio::time::interval(AUTOTUNE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+        let window = std::mem::take(&mut *pool.window.lock().unwrap_or_else(|e| e.into_inner()));
+        let limit = pool.limit.load(Ordering::Relaxed);
+
+        let new_limit = match decide(&window, &mut best_us) {
+            Adjust::Grow if limit < max => {
+                pool.semaphore.add_permits(1);
+                limit + 1
+            }
+            Adjust::Shrink if limit > min => {
+                // Takes the permit as soon as a render finishes.
+                match pool.semaphore.acquire().await {
+                    Ok(permit) => permit.forget(),
+                    Err(_) => return,
+                }
+                limit - 1
+            }
+            _ => continue,
+        };
+
+        pool.limit.store(new_limit, Ordering::Relaxed);
+        logger::info(
+            &format!("Render workers {} -> {}", limit, new_limit),
+            &[
+                ("renders", &window.renders.to_string()),
+                ("waited", &window.waited.to_string()),
+            ],
+        );
+    }
+}
+
+fn decide(window: &Window, best_us: &mut f64) -> Adjust {
+    if window.renders < AUTOTUNE_MIN_RENDERS {
+        return Adjust::Keep;
+    }
+
+    let average_us = window.render_us as f64 / window.renders as f64;
+    *best_us = (*best_us * AUTOTUNE_BASELINE_DECAY).min(average_us);
+
+    if average_us > *best_us * AUTOTUNE_SATURATION {
+        Adjust::Shrink
+    } else if window.waited > 0 {
+        Adjust::Grow
+    } else {
+        Adjust::Keep
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(renders: u64, average_us: u64, waited: u64) -> Window {
+        Window {
+            renders,
+            render_us: renders * average_us,
+            waited,
+        }
+    }
+
+    #[test]
+    fn test_decide() {
+        let mut best_us = f64::INFINITY;
+
+        assert_eq!(decide(&window(5, 1000, 5), &mut best_us), Adjust::Keep);
+        assert_eq!(best_us, f64::INFINITY);
+
+        assert_eq!(decide(&window(100, 1000, 10), &mut best_us), Adjust::Grow);
+        assert_eq!(best_us, 1000.0);
+        assert_eq!(decide(&window(100, 1100, 0), &mut best_us), Adjust::Keep);
+        assert_eq!(decide(&window(100, 2000, 10), &mut best_us), Adjust::Shrink);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_running_render() {
+        let (started, render_started) = tokio::sync::oneshot::channel();
+        let (finish, render_finish) = std::sync::mpsc::channel::<()>();
+        let (ended, render_ended) = tokio::sync::oneshot::channel();
+        let rendering = render(move || {
+            let _ = started.send(());
+            let _ = render_finish.recv();
+            let _ = ended.send(());
+            Ok(ParseTemplateResult {
+                json: "{}".to_string(),
+                text: String::new(),
+                status: 0,
+                binary: None,
+            })
+        });
+
+        // Cancelled once running: the request stops waiting for it...
+        tokio::select! {
+            _ = rendering => panic!("the render can't end before it is released"),
+            _ = render_started => {}
+        }
+
+        // ...but the render goes on until it ends.
+        finish.send(()).unwrap();
+        assert!(render_ended.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_render_on_pool() {
+        let result = render(|| {
+            Ok(ParseTemplateResult {
+                json: "{}".to_string(),
+                text: "rendered".to_string(),
+                status: 0,
+                binary: None,
+            })
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(result.text, "rendered");
+    }
+}